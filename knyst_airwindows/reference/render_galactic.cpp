@@ -0,0 +1,62 @@
+// Renders the reference vectors for the Galactic tests in src/galactic.rs
+// with the original airwindows C++, see README.md.
+//
+// Usage: render_galactic <sample rate> <output file>
+//
+// Writes the left output, one sample per line.
+
+#include <cmath>
+#include <cstdio>
+#include <cstdlib>
+#include <vector>
+
+// The dither state is private in the plugin but has to match the Rust render
+#define private public
+#include "Galactic.h"
+#undef private
+
+// Keep in sync with `reference_input` in src/galactic.rs
+static float reference_input(long frame, double sampleRate) {
+    if (frame == 0) return 0.5f;
+    double t = frame / sampleRate;
+    if (t >= 1.0 && t < 1.1) return (float)(0.25 * sin(2.0 * M_PI * 440.0 * t));
+    return 0.0f;
+}
+
+int main(int argc, char **argv) {
+    if (argc != 3) {
+        fprintf(stderr, "usage: %s <sample rate> <output file>\n", argv[0]);
+        return 1;
+    }
+    double sampleRate = atof(argv[1]);
+    long frames = (long)(2.0 * sampleRate);
+
+    Galactic galactic(nullptr);
+    galactic.setSampleRate(sampleRate);
+    galactic.setParameter(kParamA, 0.5); // Replace
+    galactic.setParameter(kParamB, 0.5); // Brightness
+    galactic.setParameter(kParamC, 0.2); // Detune
+    galactic.setParameter(kParamD, 1.0); // Bigness
+    galactic.setParameter(kParamE, 1.0); // Dry/Wet
+    galactic.fpdL = 17;
+    galactic.fpdR = 17;
+
+    std::vector<float> left(frames), right(frames), leftOut(frames), rightOut(frames);
+    for (long i = 0; i < frames; i++) {
+        left[i] = right[i] = reference_input(i, sampleRate);
+    }
+    float *inputs[2] = {left.data(), right.data()};
+    float *outputs[2] = {leftOut.data(), rightOut.data()};
+    galactic.processReplacing(inputs, outputs, frames);
+
+    FILE *file = fopen(argv[2], "w");
+    if (!file) {
+        perror(argv[2]);
+        return 1;
+    }
+    for (long i = 0; i < frames; i++) {
+        fprintf(file, "%.9g\n", leftOut[i]);
+    }
+    fclose(file);
+    return 0;
+}
@@ -0,0 +1,299 @@
+//! Shared plumbing for airwindows style stereo effects
+//!
+//! Every airwindows plugin has the same frame around its DSP: denormal level
+//! input is replaced by dither level noise, the result is mixed with the dry
+//! signal if the plugin has a dry/wet control and floating point dither is
+//! added to the output. An
+//! [`AirwindowsCore`] only implements the DSP, and `airwindows_gen!` turns it
+//! into a gen with one input per parameter, and a "dry" input if the original
+//! plugin has a dry/wet control.
+
+use knyst::{Sample, SampleRate};
+
+/// Description of a normalised parameter of an airwindows plugin
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Parameter {
+    /// Also the name of the input of the gen
+    pub name: &'static str,
+    pub min: Sample,
+    pub max: Sample,
+    /// The default of the original plugin. Unconnected inputs are 0.0, so
+    /// this is the value to set the input to for the original sound.
+    pub default: Sample,
+}
+
+impl Parameter {
+    /// A parameter in the usual 0.0 to 1.0 range
+    pub const fn normalised(name: &'static str, default: Sample) -> Self {
+        Self {
+            name,
+            min: 0.0,
+            max: 1.0,
+            default,
+        }
+    }
+}
+
+/// Parameter introspection for airwindows gens
+pub trait AirwindowsPlugin {
+    /// All parameters in the order of the gen inputs, including "dry" last if the gen has it
+    const PARAMETERS: &'static [Parameter];
+    /// The parameter called `name`
+    fn parameter(name: &str) -> Option<&'static Parameter> {
+        Self::PARAMETERS.iter().find(|p| p.name == name)
+    }
+}
+
+/// The DSP of an airwindows plugin with `PARAMETERS` parameters, without dither and dry/wet
+pub trait AirwindowsCore<const PARAMETERS: usize> {
+    /// Called when the gen is initialised, e.g. to allocate delays for the sample rate
+    fn init(&mut self, _sample_rate: SampleRate) {}
+    /// Process one stereo frame. `parameters` has one value per parameter
+    /// of the gen, not including "dry", in the same order.
+    fn process_frame(
+        &mut self,
+        input: [Sample; 2],
+        parameters: [Sample; PARAMETERS],
+        sample_rate: SampleRate,
+    ) -> [Sample; 2];
+}
+
+/// The floating point dither state of an airwindows plugin, one `fpd` per channel
+pub struct FloatingPointDither {
+    fpd: [u32; 2],
+}
+
+impl FloatingPointDither {
+    /// Seeded from knyst's randomness seed like other gens
+    pub fn new() -> Self {
+        Self::with_seed(knyst::gen::random::next_randomness_seed())
+    }
+    pub fn with_seed(seed: u64) -> Self {
+        let mut rng = fastrand::Rng::with_seed(seed);
+        Self::from_fpd([rng.u32(16386..u32::MAX), rng.u32(16386..u32::MAX)])
+    }
+    /// Start from exactly these `fpd` values, e.g. to match a render of the original plugin
+    pub fn from_fpd(fpd: [u32; 2]) -> Self {
+        Self { fpd }
+    }
+    /// The current `fpd` values, some plugins also use them as a source of randomness
+    pub fn fpd(&self) -> [u32; 2] {
+        self.fpd
+    }
+    /// Replace denormal level input with noise at a level far below hearing
+    #[inline]
+    pub fn denormal_substitution(&self, input: [Sample; 2]) -> [Sample; 2] {
+        std::array::from_fn(|c| {
+            if input[c].abs() < 1.18e-23 {
+                (self.fpd[c] as f64 * 1.18e-17) as Sample
+            } else {
+                input[c]
+            }
+        })
+    }
+    /// Add dither below the last bit of a 32 bit float mantissa
+    #[inline]
+    pub fn dither(&mut self, output: [Sample; 2]) -> [Sample; 2] {
+        std::array::from_fn(|c| {
+            let (_mantissa, exponent) = frexp(output[c]);
+            let fpd = &mut self.fpd[c];
+            *fpd ^= *fpd << 13;
+            *fpd ^= *fpd >> 17;
+            *fpd ^= *fpd << 5;
+            output[c]
+                + ((*fpd as f64 - 0x7fffffff_u32 as f64) * 5.5e-36 * 2.0_f64.powi(exponent + 62))
+                    as Sample
+        })
+    }
+}
+
+impl Default for FloatingPointDither {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Split into a mantissa in 0.5..1.0 and an exponent like C's `frexpf`
+pub(crate) fn frexp(s: f32) -> (f32, i32) {
+    if s == 0.0 || !s.is_finite() {
+        return (s, 0);
+    }
+    let bits = s.to_bits();
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    if exponent == 0 {
+        // Subnormal, scale it up into the normal range first
+        let (mantissa, exponent) = frexp(s * 2.0_f32.powi(64));
+        return (mantissa, exponent - 64);
+    }
+    let mantissa = f32::from_bits((bits & 0x807f_ffff) | (126 << 23));
+    (mantissa, exponent - 126)
+}
+
+/// An [`AirwindowsCore`] with the dither and dry/wet around it
+pub struct AirwindowsStereo<C, const PARAMETERS: usize> {
+    pub core: C,
+    pub dither: FloatingPointDither,
+}
+
+impl<C: AirwindowsCore<PARAMETERS>, const PARAMETERS: usize> AirwindowsStereo<C, PARAMETERS> {
+    pub fn new(core: C) -> Self {
+        Self {
+            core,
+            dither: FloatingPointDither::new(),
+        }
+    }
+    pub fn init(&mut self, sample_rate: SampleRate) {
+        self.core.init(sample_rate);
+    }
+    /// Process a block with one slice per parameter, not including `dry`.
+    ///
+    /// `dry` crossfades from only the effect (0.0) to only the input (1.0),
+    /// so that it is fully wet when unconnected. Plugins without a dry/wet
+    /// control in the original pass `None`.
+    pub fn process(
+        &mut self,
+        left: &[Sample],
+        right: &[Sample],
+        parameters: [&[Sample]; PARAMETERS],
+        dry: Option<&[Sample]>,
+        left_out: &mut [Sample],
+        right_out: &mut [Sample],
+        sample_rate: SampleRate,
+    ) {
+        for i in 0..left_out.len() {
+            let frame_parameters = parameters.map(|parameter| parameter[i]);
+            let input = self.dither.denormal_substitution([left[i], right[i]]);
+            let mut wet = self
+                .core
+                .process_frame(input, frame_parameters, sample_rate);
+            if let Some(dry) = dry {
+                let mix = 1.0 - dry[i];
+                if mix < 1.0 {
+                    for (wet, input) in wet.iter_mut().zip(input) {
+                        *wet = (*wet * mix) + (input * (1.0 - mix));
+                    }
+                }
+            }
+            [left_out[i], right_out[i]] = self.dither.dither(wet);
+        }
+    }
+}
+
+/// Define a stereo gen for an [`AirwindowsCore`] with one input per
+/// parameter, and its [`AirwindowsPlugin`] parameter list. Ending with
+/// `, dry` adds a "dry" input after the parameters, for plugins with a
+/// dry/wet control in the original, see [`AirwindowsStereo::process`].
+///
+/// The core needs to implement `Default`, and `impl_gen`, `GenState`,
+/// `Sample` and `SampleRate` need to be in scope where it is used.
+macro_rules! airwindows_gen {
+    ($(#[$meta:meta])* $name:ident, $core:ty, [$($param:ident: $default:expr),* $(,)?] $(, $dry:ident)?) => {
+        $(#[$meta])*
+        pub struct $name {
+            plugin: $crate::airwindows::AirwindowsStereo<$core, { <[&str]>::len(&[$(stringify!($param)),*]) }>,
+        }
+        impl $name {
+            /// The plugin with its core and dither state
+            pub fn plugin(
+                &mut self,
+            ) -> &mut $crate::airwindows::AirwindowsStereo<$core, { <[&str]>::len(&[$(stringify!($param)),*]) }> {
+                &mut self.plugin
+            }
+        }
+        impl $crate::airwindows::AirwindowsPlugin for $name {
+            const PARAMETERS: &'static [$crate::airwindows::Parameter] = &[
+                $($crate::airwindows::Parameter::normalised(stringify!($param), $default),)*
+                $($crate::airwindows::Parameter::normalised(stringify!($dry), 0.0),)?
+            ];
+        }
+        impl Default for $name {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+        #[impl_gen]
+        impl $name {
+            pub fn new() -> Self {
+                Self {
+                    plugin: $crate::airwindows::AirwindowsStereo::new(<$core>::default()),
+                }
+            }
+            pub fn init(&mut self, sample_rate: SampleRate) {
+                self.plugin.init(sample_rate);
+            }
+            pub fn process(
+                &mut self,
+                left: &[Sample],
+                right: &[Sample],
+                $($param: &[Sample],)*
+                $($dry: &[Sample],)?
+                left_out: &mut [Sample],
+                right_out: &mut [Sample],
+                sample_rate: SampleRate,
+            ) -> GenState {
+                self.plugin.process(
+                    left,
+                    right,
+                    [$($param),*],
+                    None$(.or(Some($dry)))?,
+                    left_out,
+                    right_out,
+                    sample_rate,
+                );
+                GenState::Continue
+            }
+        }
+    };
+}
+pub(crate) use airwindows_gen;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parameters_list_the_inputs() {
+        let names: Vec<_> = crate::Density::PARAMETERS.iter().map(|p| p.name).collect();
+        assert_eq!(names, ["density", "highpass", "output", "dry"]);
+        let density = crate::Density::parameter("density").unwrap();
+        assert_eq!((density.min, density.max, density.default), (0.0, 1.0, 0.2));
+        // Fully wet like the original, and like an unconnected input
+        assert_eq!(crate::Density::parameter("dry").unwrap().default, 0.0);
+        assert!(crate::PurestDrive::parameter("density").is_none());
+        // The original PurestDrive has no dry/wet control
+        assert!(crate::PurestDrive::parameter("dry").is_none());
+    }
+
+    #[test]
+    fn dither_stays_below_the_last_bit() {
+        let mut dither = FloatingPointDither::with_seed(1);
+        for x in [1.0, -0.3, 1e-3, 1e-20] {
+            for _ in 0..100 {
+                let [l, r] = dither.dither([x, x]);
+                assert!((l - x).abs() <= x.abs() * 2e-7, "{x} {l}");
+                assert!((r - x).abs() <= x.abs() * 2e-7, "{x} {r}");
+            }
+        }
+    }
+
+    #[test]
+    fn frexp_matches_c() {
+        assert_eq!(frexp(1.0), (0.5, 1));
+        assert_eq!(frexp(0.75), (0.75, 0));
+        assert_eq!(frexp(-3.0), (-0.75, 2));
+        assert_eq!(frexp(0.0), (0.0, 0));
+        for x in [0.001, -0.3, 1e-20, 1e-40, 12345.0, f32::MIN_POSITIVE] {
+            let (mantissa, exponent) = frexp(x);
+            assert!((0.5..1.0).contains(&mantissa.abs()), "{x}: {mantissa}");
+            assert_eq!(mantissa as f64 * 2.0_f64.powi(exponent), x as f64, "{x}");
+        }
+    }
+
+    #[test]
+    fn denormals_are_replaced() {
+        let dither = FloatingPointDither::with_seed(1);
+        let [l, r] = dither.denormal_substitution([0.0, 0.5]);
+        assert!(l != 0.0 && l.abs() < 1e-7);
+        assert_eq!(r, 0.5);
+    }
+}
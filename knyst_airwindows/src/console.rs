@@ -0,0 +1,129 @@
+//! PurestConsole channel and buss
+//!
+//! ported from airwindows PurestConsoleChannel and PurestConsoleBuss plugins
+//! License: MIT
+// Original code: Copyright (c) 2016 airwindows, Airwindows uses the MIT license
+
+use knyst::gen::GenState;
+use knyst::prelude::impl_gen;
+use knyst::{Sample, SampleRate};
+
+use crate::airwindows::{airwindows_gen, AirwindowsCore};
+
+/// The DSP of [`ConsoleChannel`]
+#[derive(Default)]
+pub struct ConsoleChannelCore;
+
+impl AirwindowsCore<0> for ConsoleChannelCore {
+    fn process_frame(
+        &mut self,
+        input: [Sample; 2],
+        _parameters: [Sample; 0],
+        _sample_rate: SampleRate,
+    ) -> [Sample; 2] {
+        input.map(Sample::sin)
+    }
+}
+
+/// The DSP of [`ConsoleBuss`]
+#[derive(Default)]
+pub struct ConsoleBussCore;
+
+impl AirwindowsCore<0> for ConsoleBussCore {
+    fn process_frame(
+        &mut self,
+        input: [Sample; 2],
+        _parameters: [Sample; 0],
+        _sample_rate: SampleRate,
+    ) -> [Sample; 2] {
+        input.map(|x| x.clamp(-1.0, 1.0).asin())
+    }
+}
+
+airwindows_gen!(
+    /// Console channel, put on every channel before they are summed into a [`ConsoleBuss`].
+    ///
+    /// The sine encoding on the channels and the arcsine decoding on the buss
+    /// cancel out for a single channel, but sums of channels interact the way
+    /// they would on an analog mixing console.
+    ///
+    /// *Inputs*
+    /// - "left", "right"
+    ConsoleChannel,
+    ConsoleChannelCore,
+    []
+);
+
+airwindows_gen!(
+    /// Console buss, put on the sum of channels that each have a [`ConsoleChannel`].
+    ///
+    /// *Inputs*
+    /// - "left", "right"
+    ConsoleBuss,
+    ConsoleBussCore,
+    []
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::airwindows::FloatingPointDither;
+
+    fn new_channel() -> ConsoleChannel {
+        let mut channel = ConsoleChannel::new();
+        channel.plugin().dither = FloatingPointDither::with_seed(1);
+        channel
+    }
+
+    fn new_buss() -> ConsoleBuss {
+        let mut buss = ConsoleBuss::new();
+        buss.plugin().dither = FloatingPointDither::with_seed(1);
+        buss
+    }
+
+    #[test]
+    fn one_channel_passes_through() {
+        let input = [0.0, 0.5, -0.9, 1.2];
+        let mut encoded = ([0.0; 4], [0.0; 4]);
+        let mut decoded = ([0.0; 4], [0.0; 4]);
+        let sample_rate = SampleRate(44100.);
+        new_channel().process(&input, &input, &mut encoded.0, &mut encoded.1, sample_rate);
+        new_buss().process(
+            &encoded.0,
+            &encoded.1,
+            &mut decoded.0,
+            &mut decoded.1,
+            sample_rate,
+        );
+        for ((l, r), x) in decoded.0.iter().zip(&decoded.1).zip(input) {
+            assert!((l - x).abs() < 1e-5, "{l} {x}");
+            assert!((r - x).abs() < 1e-5, "{r} {x}");
+        }
+    }
+
+    #[test]
+    fn buss_clamps_before_decoding() {
+        let mut left_out = [0.0; 2];
+        let mut right_out = [0.0; 2];
+        new_buss().process(
+            &[2.0, -3.0],
+            &[1.0, -1.0],
+            &mut left_out,
+            &mut right_out,
+            SampleRate(44100.),
+        );
+        let half_pi = std::f64::consts::FRAC_PI_2 as Sample;
+        for (l, r) in left_out.iter().zip(&right_out) {
+            assert!((l.abs() - half_pi).abs() < 1e-6, "{l}");
+            assert!((l - r).abs() < 1e-6, "{l} {r}");
+        }
+        assert!(left_out[0] > 0.0 && left_out[1] < 0.0);
+    }
+
+    #[test]
+    fn parameters_are_only_the_inputs() {
+        use crate::airwindows::AirwindowsPlugin;
+        assert!(ConsoleChannel::PARAMETERS.is_empty());
+        assert!(ConsoleBuss::PARAMETERS.is_empty());
+    }
+}
@@ -0,0 +1,149 @@
+//! Density saturation
+//!
+//! ported from airwindows Density plugin
+//! License: MIT
+// Original code: Copyright (c) 2016 airwindows, Airwindows uses the MIT license
+
+use knyst::gen::GenState;
+use knyst::prelude::impl_gen;
+use knyst::{Sample, SampleRate};
+
+use crate::airwindows::{airwindows_gen, AirwindowsCore};
+
+const HALF_PI: Sample = std::f64::consts::FRAC_PI_2 as Sample;
+
+/// The DSP of [`Density`]
+#[derive(Default)]
+pub struct DensityCore {
+    /// Two alternating highpass states per channel, as in the original
+    iir_sample_a: [Sample; 2],
+    iir_sample_b: [Sample; 2],
+    flip: bool,
+}
+
+impl AirwindowsCore<3> for DensityCore {
+    fn process_frame(
+        &mut self,
+        input: [Sample; 2],
+        [density, highpass, output]: [Sample; 3],
+        sample_rate: SampleRate,
+    ) -> [Sample; 2] {
+        let overallscale = *sample_rate / 44100.;
+        let density_param = (density * 5.0) - 1.0;
+        let iir_amount = highpass.powi(3) / overallscale;
+        let mut out = density_param.abs();
+        let density = density_param * density_param.abs();
+        while out > 1.0 {
+            out -= 1.0;
+        }
+        let mut outputs = [0.0; 2];
+        for c in 0..2 {
+            let mut sig = input[c];
+            if self.flip {
+                self.iir_sample_a[c] =
+                    (self.iir_sample_a[c] * (1.0 - iir_amount)) + (sig * iir_amount);
+                sig -= self.iir_sample_a[c];
+            } else {
+                self.iir_sample_b[c] =
+                    (self.iir_sample_b[c] * (1.0 - iir_amount)) + (sig * iir_amount);
+                sig -= self.iir_sample_b[c];
+            }
+            // Every whole step of density above 1.0 is another full sine stage
+            let mut count = density;
+            while count > 1.0 {
+                let bridge_rectifier = (sig.abs() * HALF_PI).min(HALF_PI).sin();
+                sig = if sig > 0.0 {
+                    bridge_rectifier
+                } else {
+                    -bridge_rectifier
+                };
+                count -= 1.0;
+            }
+            let bridge_rectifier = (sig.abs() * HALF_PI).min(HALF_PI);
+            // Either a boosted or a starved version
+            let bridge_rectifier = if density > 0.0 {
+                bridge_rectifier.sin()
+            } else {
+                1.0 - bridge_rectifier.cos()
+            };
+            sig = if sig > 0.0 {
+                (sig * (1.0 - out)) + (bridge_rectifier * out)
+            } else {
+                (sig * (1.0 - out)) - (bridge_rectifier * out)
+            };
+            if output < 1.0 {
+                sig *= output;
+            }
+            outputs[c] = sig;
+        }
+        self.flip = !self.flip;
+        outputs
+    }
+}
+
+airwindows_gen!(
+    /// Density saturation, from starved and expanded below the middle of the
+    /// density range to increasingly dense above it.
+    ///
+    /// *Inputs*
+    /// - "left", "right"
+    /// - "density": 0.0 to 1.0, 0.2 is clean
+    /// - "highpass": 0.0 to 1.0, removes lows before the saturation
+    /// - "output": Output level, 0.0 to 1.0
+    /// - "dry": Wet (0.0) to dry (1.0)
+    Density,
+    DensityCore,
+    [density: 0.2, highpass: 0.0, output: 1.0],
+    dry
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::airwindows::FloatingPointDither;
+
+    fn process(density: Sample, input: &[Sample]) -> Vec<Sample> {
+        let len = input.len();
+        let mut gen = Density::new();
+        gen.plugin().dither = FloatingPointDither::with_seed(1);
+        let mut left_out = vec![0.0; len];
+        let mut right_out = vec![0.0; len];
+        gen.process(
+            input,
+            input,
+            &vec![density; len],
+            &vec![0.0; len],
+            &vec![1.0; len],
+            &vec![0.0; len],
+            &mut left_out,
+            &mut right_out,
+            SampleRate(44100.),
+        );
+        left_out
+    }
+
+    #[test]
+    fn matches_the_reference() {
+        let input = [0.5, -0.25, 0.9, 2.0];
+        // At 0.4 the density is exactly 1.0: one full sine stage
+        for (out, x) in process(0.4, &input).into_iter().zip(input) {
+            let expected = (x.abs() * HALF_PI).min(HALF_PI).sin() * x.signum();
+            assert!((out - expected).abs() < 2e-6, "{out} {expected}");
+        }
+        // At 0.1 the density is negative and half way to the starved version
+        for (out, x) in process(0.1, &input).into_iter().zip(input) {
+            let starved = 1.0 - (x.abs() * HALF_PI).min(HALF_PI).cos();
+            let expected = x * 0.5 + starved * 0.5 * x.signum();
+            assert!((out - expected).abs() < 2e-6, "{out} {expected}");
+        }
+    }
+
+    #[test]
+    fn default_density_is_clean() {
+        let input = [0.5, -0.25, 0.9];
+        // Only the dither is added
+        for (out, x) in process(0.2, &input).into_iter().zip(input) {
+            assert!((out - x).abs() <= x.abs() * 2e-7, "{out} {x}");
+        }
+    }
+}
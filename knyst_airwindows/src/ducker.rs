@@ -0,0 +1,65 @@
+//! Ducking the wet signal of a reverb by the level of a sidechain
+//!
+//! The same ducker as in knyst_reverb, kept here so that this crate doesn't
+//! depend on all of knyst_reverb for it.
+
+use knyst::{Sample, SampleRate};
+
+/// Lowest envelope level taken into account, about -120 dB
+const MIN_LEVEL: Sample = 1e-6;
+
+/// Compressor style ducker for the wet signal of a reverb.
+///
+/// The envelope of the detector signal is followed with separate attack and
+/// release times, and everything above the threshold is reduced by the ratio.
+/// A ratio of 1.0 or less leaves the signal untouched.
+pub(crate) struct Ducker {
+    envelope: Sample,
+}
+
+impl Ducker {
+    pub fn new() -> Self {
+        Self { envelope: 0.0 }
+    }
+    /// Returns the gain to apply to the wet signal for one sample.
+    ///
+    /// `threshold` is in dB, `attack` and `release` in seconds.
+    #[inline]
+    pub fn process(
+        &mut self,
+        detector: Sample,
+        threshold: Sample,
+        ratio: Sample,
+        attack: Sample,
+        release: Sample,
+        sample_rate: SampleRate,
+    ) -> Sample {
+        let level = detector.abs();
+        let time = if level > self.envelope {
+            attack
+        } else {
+            release
+        };
+        let coefficient = if time > 0.0 {
+            (-(time * *sample_rate).recip()).exp()
+        } else {
+            0.0
+        };
+        self.envelope = level + (self.envelope - level) * coefficient;
+        if ratio <= 1.0 {
+            return 1.0;
+        }
+        let over = 20.0 * self.envelope.max(MIN_LEVEL).log10() - threshold;
+        if over > 0.0 {
+            (10.0 as Sample).powf(-over * (1.0 - ratio.recip()) / 20.0)
+        } else {
+            1.0
+        }
+    }
+}
+
+impl Default for Ducker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
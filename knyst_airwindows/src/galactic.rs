@@ -10,41 +10,263 @@ use knyst::gen::GenState;
 use knyst::prelude::impl_gen;
 use knyst::{Sample, SampleRate};
 
+use crate::airwindows::FloatingPointDither;
+use crate::ducker::Ducker;
+
+/// Airwindows Galactic, a huge and lush stereo reverb.
+///
+/// The input goes through a slowly drifting vibrato and a lowpass into a
+/// tank of three stages of four delays per channel, mixed into each other and
+/// fed back crosswise between the channels.
+///
+/// *Inputs*
+/// - "left", "right"
+/// - "size": Length of the delays, 0.0 to 1.0
+/// - "replace": How fast new input replaces the tail, 0.0 to 1.0. Low values give a longer decay.
+/// - "brightness": Lowpass before and after the tank, 0.0 to 1.0
+/// - "detune": Speed of the vibrato drift, 0.0 to 1.0
+/// - "mix": Dry (0.0) to wet (1.0)
+/// - "freeze": While above 0.0 the tail is held and new input is muted
+/// - "sidechain", "external_sidechain", "threshold", "ratio", "attack", "release": Ducking
+///
+/// The wet signal is ducked by the level of the input, or of "sidechain"
+/// while "external_sidechain" is above 0.0. Everything above "threshold" dB
+/// is reduced by "ratio", with "attack" and "release" in seconds. A ratio of
+/// 1.0 or less turns the ducking off.
 pub struct Galactic {
-    delays_left: [StaticSampleDelay; 12],
-    delays_right: [StaticSampleDelay; 12],
+    delays_left: [SizedDelay; 12],
+    delays_right: [SizedDelay; 12],
+    /// Delay lengths for the previous and the current size, the same for left and right
+    tap_lengths: [[usize; 12]; 2],
+    /// Crossfade from the previous to the current size, 1.0 when done
+    size_fade: Sample,
+    /// Crossfade increment per sample of the reverb core
+    size_fade_step: Sample,
+    /// Smoothed size, replace, brightness, detune and mix
+    smoothed: [Smoothed; 5],
+    smoothing_coefficient: Sample,
     feedback: [[Sample; 4]; 2],
     detune_delay_left: StaticSampleDelay,
     detune_delay_right: StaticSampleDelay,
     lowpass_pre: [Sample; 2],
     lowpass_post: [Sample; 2],
-    fpd_l: u32,
-    fpd_r: u32,
+    /// Denormal substitution and dither, its `fpd` also drives the vibrato
+    dither: FloatingPointDither,
     oldfpd: f64,
     vib_m: f64,
     iir_al: Sample,
     iir_ar: Sample,
     iir_bl: Sample,
     iir_br: Sample,
+    /// Current freeze amount (0..=1), faded towards the freeze gate
+    freeze_amount: Sample,
+    ducker: Ducker,
+    /// The reverb core runs once every `cycle_end` samples so that it always runs at 44.1-48kHz
+    cycle_end: usize,
+    cycle: usize,
+    /// Reverb samples to interpolate between for the samples of one cycle, per channel
+    last_ref: [[Sample; 5]; 2],
+    /// Sample rate relative to 44.1kHz, scales the vibrato delay
+    overallscale: Sample,
 }
 
-const GALACTIC_DELAY_TIMES: [usize; 12] = [
-    6480, 3660, 1720, 680, 9700, 6000, 2320, 940, 15220, 8460, 4540, 3200,
+/// Time in seconds to fade in and out of freeze
+const FREEZE_FADE_TIME: Sample = 0.05;
+/// The regen where the feedback loop has unity gain: 3 mixing stages with a gain of 2 each
+const UNITY_REGEN: Sample = 0.125;
+
+/// Time in seconds to crossfade between delay lengths when the size changes
+const SIZE_FADE_TIME: Sample = 0.03;
+/// Time constant in seconds of the parameter smoothing
+const PARAMETER_SMOOTHING_TIME: Sample = 0.02;
+
+/// Delay lengths at 44.1kHz for a size multiplier of 1.0, the I-L, A-D and E-H delays of the original
+const GALACTIC_DELAY_TIMES: [Sample; 12] = [
+    3407., 1823., 859., 331., 4801., 2909., 1153., 461., 7607., 4217., 2269., 1597.,
 ];
+/// The size multiplier at a size of 1.0, (1.0 * 1.77) + 0.1
+const MAX_SIZE: Sample = 1.87;
+
+/// Delay line with room for the largest size that can be read at any length up to it
+struct SizedDelay {
+    buffer: Vec<Sample>,
+    position: usize,
+}
+
+impl SizedDelay {
+    fn new(max_length: usize) -> Self {
+        Self {
+            buffer: vec![0.0; max_length.max(1)],
+            position: 0,
+        }
+    }
+    fn write_and_advance(&mut self, input: Sample) {
+        self.buffer[self.position] = input;
+        self.position = (self.position + 1) % self.buffer.len();
+    }
+    /// Read `length` samples back, the same as a `StaticSampleDelay` of that length
+    fn read_at_length(&self, length: usize) -> Sample {
+        let len = self.buffer.len();
+        self.buffer[(self.position + len - length) % len]
+    }
+    /// Crossfade between the output at the previous and the current length
+    fn read(&self, lengths: &[[usize; 12]; 2], index: usize, fade: Sample) -> Sample {
+        if fade >= 1.0 {
+            self.read_at_length(lengths[1][index])
+        } else {
+            self.read_at_length(lengths[0][index]) * (1.0 - fade)
+                + self.read_at_length(lengths[1][index]) * fade
+        }
+    }
+}
+
+/// One pole smoothing of a parameter that starts at the first value it gets
+#[derive(Clone, Copy)]
+struct Smoothed {
+    value: Sample,
+}
+
+impl Smoothed {
+    fn new() -> Self {
+        Self { value: Sample::NAN }
+    }
+    fn next(&mut self, target: Sample, coefficient: Sample) -> Sample {
+        if self.value.is_nan() {
+            self.value = target;
+        } else {
+            self.value = target + (self.value - target) * coefficient;
+        }
+        self.value
+    }
+}
+
+impl Galactic {
+    /// Start a crossfade to the delay lengths for `size` unless one is already running
+    fn update_size(&mut self, size: Sample) {
+        if self.size_fade < 1.0 {
+            return;
+        }
+        let core_scale = self.overallscale / self.cycle_end as Sample;
+        let lengths: [usize; 12] = std::array::from_fn(|i| {
+            ((GALACTIC_DELAY_TIMES[i] * core_scale * size) as usize)
+                .clamp(1, self.delays_left[i].buffer.len())
+        });
+        if lengths != self.tap_lengths[1] {
+            self.tap_lengths = [self.tap_lengths[1], lengths];
+            self.size_fade = 0.0;
+        }
+    }
+    /// Calculate one sample of the reverb core from the filtered input
+    fn reverb_sample(
+        &mut self,
+        input_sample_l: Sample,
+        input_sample_r: Sample,
+        regen: Sample,
+        size: Sample,
+    ) -> [Sample; 2] {
+        self.update_size(size);
+        // Reverb sample:
+        // Set I-L delays for the input + respective feedback from last cycle for the opposite channel (left for right, right for left)
+        // BLOCK 0
+
+        for i in 0..4 {
+            self.delays_left[i].write_and_advance((self.feedback[1][i] * regen) + input_sample_l);
+        }
+        for i in 0..4 {
+            self.delays_right[i].write_and_advance((self.feedback[0][i] * regen) + input_sample_r);
+        }
+
+        let mut block_0_l = [0.0; 4];
+        for i in 0..4 {
+            block_0_l[i] = self.delays_left[i].read(&self.tap_lengths, i, self.size_fade);
+        }
+        let mut block_0_r = [0.0; 4];
+        for i in 0..4 {
+            block_0_r[i] = self.delays_right[i].read(&self.tap_lengths, i, self.size_fade);
+        }
+        // BLOCK 1
+
+        for i in 0..4 {
+            self.delays_left[i + 4].write_and_advance(
+                block_0_l[0 + i]
+                    - (block_0_l[(1 + i) % 4] + block_0_l[(2 + i) % 4] + block_0_l[(3 + i) % 4]),
+            );
+        }
+        for i in 0..4 {
+            self.delays_right[i + 4].write_and_advance(
+                block_0_r[0 + i]
+                    - (block_0_r[(1 + i) % 4] + block_0_r[(2 + i) % 4] + block_0_r[(3 + i) % 4]),
+            );
+        }
+
+        let mut block_1_l = [0.0; 4];
+        for i in 0..4 {
+            block_1_l[i] = self.delays_left[i + 4].read(&self.tap_lengths, i + 4, self.size_fade);
+        }
+        let mut block_1_r = [0.0; 4];
+        for i in 0..4 {
+            block_1_r[i] = self.delays_right[i + 4].read(&self.tap_lengths, i + 4, self.size_fade);
+        }
+
+        // BLOCK 2
+
+        for i in 0..4 {
+            self.delays_left[i + 8].write_and_advance(
+                block_1_l[0 + i]
+                    - (block_1_l[(1 + i) % 4] + block_1_l[(2 + i) % 4] + block_1_l[(3 + i) % 4]),
+            );
+        }
+        for i in 0..4 {
+            self.delays_right[i + 8].write_and_advance(
+                block_1_r[0 + i]
+                    - (block_1_r[(1 + i) % 4] + block_1_r[(2 + i) % 4] + block_1_r[(3 + i) % 4]),
+            );
+        }
+
+        let mut block_2_l = [0.0; 4];
+        for i in 0..4 {
+            block_2_l[i] = self.delays_left[i + 8].read(&self.tap_lengths, i + 8, self.size_fade);
+        }
+        let mut block_2_r = [0.0; 4];
+        for i in 0..4 {
+            block_2_r[i] = self.delays_right[i + 8].read(&self.tap_lengths, i + 8, self.size_fade);
+        }
+
+        // Set feedback
+        for i in 0..4 {
+            self.feedback[0][i] = block_2_l[i]
+                - (block_2_l[(1 + i) % 4] + block_2_l[(2 + i) % 4] + block_2_l[(3 + i) % 4]);
+        }
+        for i in 0..4 {
+            self.feedback[1][i] = block_2_r[i]
+                - (block_2_r[(1 + i) % 4] + block_2_r[(2 + i) % 4] + block_2_r[(3 + i) % 4]);
+        }
+
+        self.size_fade = (self.size_fade + self.size_fade_step).min(1.0);
+
+        [
+            block_2_l.iter().sum::<Sample>() * 0.125,
+            block_2_r.iter().sum::<Sample>() * 0.125,
+        ]
+    }
+}
 
 #[impl_gen]
 impl Galactic {
     pub fn new() -> Self {
-        let mut rng = fastrand::Rng::with_seed(knyst::gen::random::next_randomness_seed());
         Self {
-            delays_left: std::array::from_fn(|_| StaticSampleDelay::new(1)),
-            delays_right: std::array::from_fn(|_| StaticSampleDelay::new(1)),
+            delays_left: std::array::from_fn(|_| SizedDelay::new(1)),
+            delays_right: std::array::from_fn(|_| SizedDelay::new(1)),
+            tap_lengths: [[1; 12]; 2],
+            size_fade: 1.0,
+            size_fade_step: 1.0,
+            smoothed: [Smoothed::new(); 5],
+            smoothing_coefficient: 0.0,
             detune_delay_left: StaticSampleDelay::new(1),
             detune_delay_right: StaticSampleDelay::new(1),
             lowpass_pre: [0., 0.],
             lowpass_post: [0., 0.],
-            fpd_l: rng.u32(16386..std::u32::MAX),
-            fpd_r: rng.u32(16386..std::u32::MAX),
+            dither: FloatingPointDither::new(),
             vib_m: 3.,
             feedback: [[0.0; 4]; 2],
             oldfpd: 429496.7295,
@@ -52,23 +274,38 @@ impl Galactic {
             iir_ar: 0.,
             iir_bl: 0.,
             iir_br: 0.,
+            freeze_amount: 0.,
+            ducker: Ducker::new(),
+            cycle_end: 1,
+            cycle: 0,
+            last_ref: [[0.0; 5]; 2],
+            overallscale: 1.0,
         }
     }
     pub fn init(&mut self, sample_rate: SampleRate) {
+        self.overallscale = *sample_rate / 44100.;
+        // 2 at 88.2 or 96kHz, 4 at 176.4 or 192kHz
+        self.cycle_end = (self.overallscale.floor() as usize).clamp(1, 4);
+        self.cycle = 0;
+        self.last_ref = [[0.0; 5]; 2];
+        // The delays are in samples of the reverb core, which runs at sample_rate / cycle_end
+        let core_scale = self.overallscale / self.cycle_end as Sample;
         for (delay, time) in self.delays_left.iter_mut().zip(GALACTIC_DELAY_TIMES) {
-            let time = (time as Sample / 44100.) * *sample_rate;
-            *delay = StaticSampleDelay::new(time as usize);
+            *delay = SizedDelay::new((time * core_scale * MAX_SIZE) as usize);
         }
         for (delay, time) in self.delays_right.iter_mut().zip(GALACTIC_DELAY_TIMES) {
-            let time = (time as Sample / 44100.) * *sample_rate;
-            *delay = StaticSampleDelay::new(time as usize);
+            *delay = SizedDelay::new((time * core_scale * MAX_SIZE) as usize);
         }
-        // self.detune_delay_left =
-        //     StaticSampleDelay::new((0.07054421768707483 * *sample_rate) as usize);
-        // self.detune_delay_right =
-        //     StaticSampleDelay::new((0.07054421768707483 * *sample_rate) as usize);
-        self.detune_delay_left = StaticSampleDelay::new(256);
-        self.detune_delay_right = StaticSampleDelay::new(256);
+        // Start at the full size, the first size is faded to from there
+        self.tap_lengths = [std::array::from_fn(|i| self.delays_left[i].buffer.len()); 2];
+        self.size_fade = 1.0;
+        self.size_fade_step = (SIZE_FADE_TIME * *sample_rate / self.cycle_end as Sample).recip();
+        self.smoothed = [Smoothed::new(); 5];
+        self.smoothing_coefficient = (-(PARAMETER_SMOOTHING_TIME * *sample_rate).recip()).exp();
+        // The vibrato delay is 256 samples at 44.1kHz
+        let detune_delay_length = (256. * self.overallscale).ceil() as usize;
+        self.detune_delay_left = StaticSampleDelay::new(detune_delay_length);
+        self.detune_delay_right = StaticSampleDelay::new(detune_delay_length);
         self.lowpass_pre = [0., 0.];
         self.lowpass_post = [0., 0.];
     }
@@ -81,180 +318,123 @@ impl Galactic {
         brightness: &[Sample],
         detune: &[Sample],
         mix: &[Sample],
+        freeze: &[Sample],
+        sidechain: &[Sample],
+        external_sidechain: &[Sample],
+        threshold: &[Sample],
+        ratio: &[Sample],
+        attack: &[Sample],
+        release: &[Sample],
         left_out: &mut [Sample],
         right_out: &mut [Sample],
         sample_rate: SampleRate,
     ) -> GenState {
-        let mut overallscale = 1.0;
-        overallscale /= 44100.0;
-        overallscale *= *sample_rate;
+        let overallscale = self.overallscale;
 
         // double regen = 0.0625+((1.0-A)*0.0625); // High (0.125) if Replace is low
         // double attenuate = (1.0 - (regen / 0.125))*1.333; // 1.33 if regen is low / replace is high
 
-        let regen = 0.0625 + ((1.0 - replace[0]) * 0.0625);
-        let attenuate = (1.0 - (regen / 0.125)) * 1.333; // 1.33 if regen is high / replace is low
-        let lowpass = (1.00001 - (1.0 - brightness[0])).powi(2) / (overallscale).sqrt(); // (0.00001 + Brightness).powi(2)/overallscale.sqrt()
-        let drift = detune[0].powi(3) * 0.001; // Detune.powi(3) * 0.001
-        let size = (size[0] * 0.9) + 0.1;
-        let wet = 1.0 - (1.0 - mix[0]).powi(3);
-
-        for (delay_left, delay_right) in self
-            .delays_left
-            .iter_mut()
-            .zip(self.delays_right.iter_mut())
-        {
-            delay_left.set_delay_length_fraction(size);
-            delay_right.set_delay_length_fraction(size);
-        }
-
-        // let lengths = [3407., 1823., 859., 331., 4801., 2909., 1153., 461., 7607., 4217., 2269., 1597.];
-        // for ((left, right), len) in self.delays_left.iter_mut().zip(self.delays_right.iter_mut()).zip(lengths) {
-        //     let len = (len * size) as usize;
-        //     left.set_delay_length(len);
-        //     right.set_delay_length(len);
-        // }
+        let freeze_step = (FREEZE_FADE_TIME * *sample_rate).recip();
 
-        for (((&input_sample_l, &input_sample_r), output_l), output_r) in left
+        for (i, ((((&input_sample_l, &input_sample_r), output_l), output_r), &freeze_gate)) in left
             .iter()
             .zip(right.iter())
             .zip(left_out.iter_mut())
             .zip(right_out.iter_mut())
+            .zip(freeze)
+            .enumerate()
         {
-            // # Per sample:
-            // - If the input is very faint, use the fpd values instead (floating point dither, similar to the last output sample)
-
-            // Apply dither
-            let input_sample_l = if input_sample_l.abs() < 1.18e-23 {
-                (self.fpd_l as f64 * 1.18e-17) as Sample
-            } else {
-                input_sample_l
-            };
-            let input_sample_r = if input_sample_r.abs() < 1.18e-23 {
-                (self.fpd_r as f64 * 1.18e-17) as Sample
+            // Duck the wet signal by the input, or by the sidechain while external_sidechain is on
+            let detector = if external_sidechain[i] > 0.0 {
+                sidechain[i]
             } else {
-                input_sample_r
+                input_sample_l.abs().max(input_sample_r.abs())
             };
+            // Parameters are smoothed per sample to avoid zipper noise
+            let coefficient = self.smoothing_coefficient;
+            let size = self.smoothed[0].next(size[i], coefficient);
+            let replace = self.smoothed[1].next(replace[i], coefficient);
+            let brightness = self.smoothed[2].next(brightness[i], coefficient);
+            let detune = self.smoothed[3].next(detune[i], coefficient);
+            let mix = self.smoothed[4].next(mix[i], coefficient);
+            let regen = 0.0625 + ((1.0 - replace) * 0.0625);
+            let attenuate = (1.0 - (regen / 0.125)) * 1.333; // 1.33 if regen is high / replace is low
+            let lowpass = (1.00001 - (1.0 - brightness)).powi(2) / (overallscale).sqrt(); // (0.00001 + Brightness).powi(2)/overallscale.sqrt()
+            let drift = detune.powi(3) * 0.001; // Detune.powi(3) * 0.001
+            let size = (size.clamp(0.0, 1.0) * 1.77) + 0.1;
+            let wet = 1.0 - (1.0 - mix).powi(3);
+
+            let duck = self.ducker.process(
+                detector,
+                threshold[i],
+                ratio[i],
+                attack[i],
+                release[i],
+                sample_rate,
+            );
+            // While frozen the feedback loop has unity gain and new input is muted
+            let freeze_target = if freeze_gate > 0.0 { 1.0 } else { 0.0 };
+            self.freeze_amount +=
+                (freeze_target - self.freeze_amount).clamp(-freeze_step, freeze_step);
+            let regen = regen + (UNITY_REGEN - regen) * self.freeze_amount;
+            let input_gain = 1.0 - self.freeze_amount;
+
+            // # Per sample:
+            // - If the input is very faint, use the fpd values instead (floating point dither, similar to the last output sample)
+            let [input_sample_l, input_sample_r] = self
+                .dither
+                .denormal_substitution([input_sample_l, input_sample_r]);
             let dry_sample_l = input_sample_l;
             let dry_sample_r = input_sample_r;
 
             // - vibM cycles 0. - TAU, speed depending on drift (Detune) and the fpdL value last time it reset
             // vibM is phase 0-TAU, speed dpends on drift and fpd
-            self.vib_m += self.oldfpd * drift as f64;
-            if self.vib_m > (3.141592653589793238 * 2.0) {
+            // Scaled so that the vibrato rate doesn't depend on the sample rate
+            self.vib_m += self.oldfpd * (drift / self.overallscale) as f64;
+            if self.vib_m > std::f64::consts::TAU {
                 self.vib_m = 0.0;
-                self.oldfpd = 0.4294967295 + (self.fpd_l as f64 * 0.0000000000618);
+                self.oldfpd = 0.4294967295 + (self.dither.fpd()[0] as f64 * 0.0000000000618);
             }
 
-            // - set the fixed size delay (256 frames) to the inputSample at the current position
+            // - set the vibrato delay (256 frames at 44.1kHz) to the inputSample at the current position
             self.detune_delay_left
                 .write_and_advance(input_sample_l * attenuate);
             self.detune_delay_right
                 .write_and_advance(input_sample_r * attenuate);
             // - Get a sample from the aM buffer (lin interp)
             let vib_m_sin = self.vib_m.sin(); // TODO: replace by something faster
-            let offset_ml = ((vib_m_sin) + 1.0) * 127.; // 0-256
-            let offset_mr = ((self.vib_m + (3.141592653589793238 / 2.0)).sin() + 1.0) * 127.; // 0-256 90 degrees phase shifted
+            let depth = 127. * self.overallscale as f64;
+            let offset_ml = ((vib_m_sin) + 1.0) * depth; // 0-256 at 44.1kHz
+            let offset_mr = ((self.vib_m + std::f64::consts::FRAC_PI_2).sin() + 1.0) * depth; // 90 degrees phase shifted
             let working_ml = self.detune_delay_left.position as f64 + offset_ml;
             let working_mr = self.detune_delay_right.position as f64 + offset_mr;
             let input_sample_l = self.detune_delay_left.read_at_lin(working_ml as Sample);
             let input_sample_r = self.detune_delay_right.read_at_lin(working_mr as Sample);
             // - Apply a lowpass filter to the output from the M delay (iirA variable)
             self.iir_al = (self.iir_al * (1.0 - lowpass)) + (input_sample_l * lowpass);
-            let input_sample_l = self.iir_al;
+            let input_sample_l = self.iir_al * input_gain;
             self.iir_ar = (self.iir_ar * (1.0 - lowpass)) + (input_sample_r * lowpass);
-            let input_sample_r = self.iir_ar;
-            // - Only calculate a new reverb sample once every 4 samples if SR is 44100*4
-
-            // Reverb sample:
-            // Set I-L delays for the input + respective feedback from last cycle for the opposite channel (left for right, right for left)
-            // BLOCK 0
-
-            for i in 0..4 {
-                self.delays_left[i]
-                    .write_and_advance((self.feedback[1][i] * regen) + input_sample_l);
-            }
-            for i in 0..4 {
-                self.delays_right[i]
-                    .write_and_advance((self.feedback[0][i] * regen) + input_sample_r);
-            }
-
-            let mut block_0_l = [0.0; 4];
-            for i in 0..4 {
-                block_0_l[i] = self.delays_left[i].read();
-            }
-            let mut block_0_r = [0.0; 4];
-            for i in 0..4 {
-                block_0_r[i] = self.delays_right[i].read();
-            }
-            // BLOCK 1
-
-            for i in 0..4 {
-                self.delays_left[i + 4].write_and_advance(
-                    block_0_l[0 + i]
-                        - (block_0_l[(1 + i) % 4]
-                            + block_0_l[(2 + i) % 4]
-                            + block_0_l[(3 + i) % 4]),
-                );
-            }
-            for i in 0..4 {
-                self.delays_right[i + 4].write_and_advance(
-                    block_0_r[0 + i]
-                        - (block_0_r[(1 + i) % 4]
-                            + block_0_r[(2 + i) % 4]
-                            + block_0_r[(3 + i) % 4]),
-                );
+            let input_sample_r = self.iir_ar * input_gain;
+            // - Only calculate a new reverb sample once every cycle_end samples at high sample rates,
+            //   interpolating from the previous reverb sample in between
+            self.cycle += 1;
+            if self.cycle >= self.cycle_end {
+                let reverb = self.reverb_sample(input_sample_l, input_sample_r, regen, size);
+                for (refs, new) in self.last_ref.iter_mut().zip(reverb) {
+                    if self.cycle_end == 1 {
+                        refs[0] = new;
+                    } else {
+                        refs[0] = refs[self.cycle_end];
+                        for k in 1..=self.cycle_end {
+                            refs[k] =
+                                refs[0] + (new - refs[0]) * k as Sample / self.cycle_end as Sample;
+                        }
+                    }
+                }
+                self.cycle = 0;
             }
-
-            let mut block_1_l = [0.0; 4];
-            for i in 0..4 {
-                block_1_l[i] = self.delays_left[i + 4].read();
-            }
-            let mut block_1_r = [0.0; 4];
-            for i in 0..4 {
-                block_1_r[i] = self.delays_right[i + 4].read();
-            }
-
-            // BLOCK 2
-
-            for i in 0..4 {
-                self.delays_left[i + 8].write_and_advance(
-                    block_1_l[0 + i]
-                        - (block_1_l[(1 + i) % 4]
-                            + block_1_l[(2 + i) % 4]
-                            + block_1_l[(3 + i) % 4]),
-                );
-            }
-            for i in 0..4 {
-                self.delays_right[i + 8].write_and_advance(
-                    block_1_r[0 + i]
-                        - (block_1_r[(1 + i) % 4]
-                            + block_1_r[(2 + i) % 4]
-                            + block_1_r[(3 + i) % 4]),
-                );
-            }
-
-            let mut block_2_l = [0.0; 4];
-            for i in 0..4 {
-                block_2_l[i] = self.delays_left[i + 8].read();
-            }
-            let mut block_2_r = [0.0; 4];
-            for i in 0..4 {
-                block_2_r[i] = self.delays_right[i + 8].read();
-            }
-
-            // Set feedback
-            for i in 0..4 {
-                self.feedback[0][i] = block_2_l[i]
-                    - (block_2_l[(1 + i) % 4] + block_2_l[(2 + i) % 4] + block_2_l[(3 + i) % 4]);
-            }
-            for i in 0..4 {
-                self.feedback[1][i] = block_2_r[i]
-                    - (block_2_r[(1 + i) % 4] + block_2_r[(2 + i) % 4] + block_2_r[(3 + i) % 4]);
-            }
-
-            let input_sample_l: Sample = block_2_l.iter().sum::<Sample>() * 0.125;
-            let input_sample_r: Sample = block_2_r.iter().sum::<Sample>() * 0.125;
+            let input_sample_l = self.last_ref[0][self.cycle];
+            let input_sample_r = self.last_ref[1][self.cycle];
 
             // Get the output from I-L delays
             // Set A-D delays to a mixing configuration of the I-L outputs e.g. I - (J+K+L);
@@ -265,49 +445,296 @@ impl Galactic {
             // Apply another lowpass to the reverbed value
 
             self.iir_bl = (self.iir_bl * (1.0 - lowpass)) + input_sample_l * lowpass;
-            let mut input_sample_l = self.iir_bl;
+            let mut input_sample_l = self.iir_bl * duck;
             self.iir_br = (self.iir_br * (1.0 - lowpass)) + (input_sample_r * lowpass);
-            let mut input_sample_r = self.iir_br;
+            let mut input_sample_r = self.iir_br * duck;
 
             if wet < 1.0 {
                 input_sample_l = (input_sample_l * wet) + (dry_sample_l * (1.0 - wet));
                 input_sample_r = (input_sample_r * wet) + (dry_sample_r * (1.0 - wet));
             }
 
-            let (_mantissa_l, exp_l) = frexp(input_sample_l as f32);
-            let mut fpd_l = self.fpd_l;
-            fpd_l ^= fpd_l << 13;
-            fpd_l ^= fpd_l >> 17;
-            fpd_l ^= fpd_l << 5;
-            input_sample_l += (((fpd_l as f64) - (0x7fffffff_u32) as f64)
-                * 5.5e-36
-                * (2_u64.pow(exp_l + 62) as f64)) as Sample;
-            self.fpd_l = fpd_l;
-
-            let (_mantissa_r, exp_r) = frexp(input_sample_r as f32);
-            let mut fpd_r = self.fpd_r;
-            fpd_r ^= fpd_r << 13;
-            fpd_r ^= fpd_r >> 17;
-            fpd_r ^= fpd_r << 5;
-            input_sample_r += (((fpd_r as f64) - (0x7fffffff_u32) as f64)
-                * 5.5e-36
-                * (2_u64.pow(exp_r + 62) as f64)) as Sample;
-            self.fpd_r = fpd_r;
-
-            *output_l = input_sample_l;
-            *output_r = input_sample_r;
+            [*output_l, *output_r] = self.dither.dither([input_sample_l, input_sample_r]);
         }
         GenState::Continue
     }
 }
 
-fn frexp(s: f32) -> (f32, u32) {
-    if 0.0 == s {
-        return (s, 0);
-    } else {
-        let lg = s.abs().log2();
-        let x = (lg - lg.floor() - 1.0).exp2();
-        let exp = lg.floor() + 1.0;
-        (s.signum() * x, exp as u32)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Render the left output of a Galactic with a fixed seed for the dither.
+    /// The input, size and mix are given per frame.
+    fn render(
+        sample_rate: Sample,
+        frames: usize,
+        input: impl Fn(usize) -> Sample,
+        size: impl Fn(usize) -> Sample,
+        mix: impl Fn(usize) -> Sample,
+    ) -> Vec<Sample> {
+        let block_size = 64;
+        let sample_rate = SampleRate(sample_rate);
+        let mut galactic = Galactic::new();
+        galactic.dither = FloatingPointDither::from_fpd([17, 17]);
+        galactic.init(sample_rate);
+        let block = |value| vec![value; block_size];
+        let (replace, brightness, detune) = (block(0.5), block(0.5), block(0.2));
+        let freeze = block(0.0);
+        let no_ducking = block(0.0);
+        let mut left_out = block(0.0);
+        let mut right_out = block(0.0);
+        let mut rendered = Vec::new();
+        for start in (0..frames).step_by(block_size) {
+            let frames = start..start + block_size;
+            let input_block: Vec<Sample> = frames.clone().map(&input).collect();
+            let size_block: Vec<Sample> = frames.clone().map(&size).collect();
+            let mix_block: Vec<Sample> = frames.map(&mix).collect();
+            galactic.process(
+                &input_block,
+                &input_block,
+                &size_block,
+                &replace,
+                &brightness,
+                &detune,
+                &mix_block,
+                &freeze,
+                &no_ducking,
+                &no_ducking,
+                &no_ducking,
+                &no_ducking,
+                &no_ducking,
+                &no_ducking,
+                &mut left_out,
+                &mut right_out,
+                sample_rate,
+            );
+            rendered.extend_from_slice(&left_out);
+        }
+        rendered
+    }
+
+    /// An impulse and a 440Hz burst after one second, the same as in `reference/render_galactic.cpp`
+    fn reference_input(frame: usize, sample_rate: Sample) -> Sample {
+        if frame == 0 {
+            return 0.5;
+        }
+        let t = frame as f64 / sample_rate as f64;
+        if (1.0..1.1).contains(&t) {
+            (0.25 * (std::f64::consts::TAU * 440.0 * t).sin()) as Sample
+        } else {
+            0.0
+        }
+    }
+
+    /// Level in dB of every 10 ms window
+    fn window_levels(signal: &[Sample], sample_rate: Sample) -> Vec<Sample> {
+        signal
+            .chunks((sample_rate * 0.01) as usize)
+            .map(|window| {
+                let energy = window.iter().map(|s| s * s).sum::<Sample>() / window.len() as Sample;
+                10.0 * energy.max(1e-20).log10()
+            })
+            .collect()
+    }
+
+    #[test]
+    #[ignore = "needs the reference renders of the original, see reference/README.md"]
+    fn matches_the_cpp_reference() {
+        for sample_rate in [44100, 96000] {
+            let path = format!(
+                "{}/reference/galactic_{sample_rate}.txt",
+                env!("CARGO_MANIFEST_DIR")
+            );
+            let reference: Vec<Sample> = std::fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("{path}: {e}"))
+                .lines()
+                .map(|line| line.parse().unwrap())
+                .collect();
+            let sample_rate = sample_rate as Sample;
+            let output = render(
+                sample_rate,
+                reference.len(),
+                |i| reference_input(i, sample_rate),
+                |_| 1.0,
+                |_| 1.0,
+            );
+            if sample_rate == 44100. {
+                for (i, (out, reference)) in output.iter().zip(&reference).enumerate() {
+                    assert!((out - reference).abs() < 1e-4, "{i}: {out} {reference}");
+                }
+            }
+            let levels = window_levels(&output, sample_rate);
+            let reference_levels = window_levels(&reference, sample_rate);
+            let peak = reference_levels.iter().fold(Sample::MIN, |a, &b| a.max(b));
+            for (i, (level, reference)) in levels.iter().zip(&reference_levels).enumerate() {
+                if *reference > peak - 60.0 {
+                    assert!(
+                        (level - reference).abs() < 1.0,
+                        "{sample_rate} {i}: {level} {reference}"
+                    );
+                }
+            }
+        }
+    }
+
+    fn impulse_response(sample_rate: Sample, seconds: Sample) -> Vec<Sample> {
+        render(
+            sample_rate,
+            (seconds * sample_rate) as usize,
+            |i| if i == 0 { 1.0 } else { 0.0 },
+            |_| 0.5,
+            |_| 1.0,
+        )
+    }
+
+    /// Energy in consecutive windows of `window` seconds
+    fn energy_envelope(ir: &[Sample], sample_rate: Sample, window: Sample) -> Vec<Sample> {
+        ir.chunks((window * sample_rate) as usize)
+            .map(|chunk| chunk.iter().map(|s| s * s).sum())
+            .collect()
+    }
+
+    #[test]
+    fn decay_does_not_depend_on_the_sample_rate() {
+        let reference = impulse_response(48000., 2.0);
+        let reference = energy_envelope(&reference, 48000., 0.25);
+        assert!(reference.iter().all(|&energy| energy > 0.0));
+        for sample_rate in [96000., 192000.] {
+            let ir = impulse_response(sample_rate, 2.0);
+            let envelope = energy_envelope(&ir, sample_rate, 0.25);
+            // The decay relative to the first window stays within about 2 dB of the 48kHz render
+            for i in 1..reference.len() {
+                let ratio = (envelope[i] / envelope[0]) / (reference[i] / reference[0]);
+                assert!(
+                    ratio > 0.6 && ratio < 1.6,
+                    "{sample_rate}, window {i}: {ratio}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn parameters_are_read_per_sample() {
+        let input = |i| (i as Sample * 0.05).sin() * 0.5;
+        let dry = render(48000., 4800, input, |_| 0.5, |_| 0.0);
+        // Only the first sample of every block is dry
+        let modulated = render(
+            48000.,
+            4800,
+            input,
+            |_| 0.5,
+            |i| (i % 64 != 0) as u8 as Sample,
+        );
+        assert!(dry
+            .iter()
+            .zip(&modulated)
+            .any(|(dry, modulated)| (dry - modulated).abs() > 1e-3));
+    }
+
+    #[test]
+    fn size_changes_do_not_click() {
+        let sample_rate = 48000.;
+        let switch = 48000;
+        let input = |i| (i as Sample * 0.02).sin() * 0.5;
+        let size = |i| if i < switch { 0.3 } else { 0.9 };
+        let output = render(sample_rate, switch + 9600, input, size, |_| 1.0);
+        let max_step = |range: std::ops::Range<usize>| {
+            output[range]
+                .windows(2)
+                .map(|w| (w[1] - w[0]).abs())
+                .fold(0.0, Sample::max)
+        };
+        let before = max_step(switch - 9600..switch);
+        let after = max_step(switch..switch + 9600);
+        assert!(after < before * 2.0, "{before} {after}");
+    }
+
+    #[test]
+    fn dry_mix_only_adds_dither() {
+        let input = |i| (i as Sample * 0.05).sin() * 0.5 + 0.6;
+        let output = render(48000., 4800, input, |_| 0.5, |_| 0.0);
+        for (i, out) in output.iter().enumerate() {
+            let x = input(i);
+            // The dither is below the last bit of a 32 bit float mantissa
+            assert!((out - x).abs() <= x.abs() * 2e-7, "{i}: {out} {x}");
+        }
+    }
+
+    #[test]
+    fn impulse_response_is_stereo() {
+        let sample_rate = SampleRate(48000.);
+        let mut galactic = Galactic::new();
+        galactic.init(sample_rate);
+        let block = |value| vec![value; 64];
+        let (size, replace, brightness) = (block(0.5), block(0.5), block(0.5));
+        let (detune, mix, freeze) = (block(0.2), block(1.0), block(0.0));
+        let no_ducking = block(0.0);
+        let mut input = block(0.0);
+        input[0] = 1.0;
+        let (mut left_out, mut right_out) = (block(0.0), block(0.0));
+        let (mut left, mut right) = (Vec::new(), Vec::new());
+        for _ in 0..750 {
+            galactic.process(
+                &input,
+                &input,
+                &size,
+                &replace,
+                &brightness,
+                &detune,
+                &mix,
+                &freeze,
+                &no_ducking,
+                &no_ducking,
+                &no_ducking,
+                &no_ducking,
+                &no_ducking,
+                &no_ducking,
+                &mut left_out,
+                &mut right_out,
+                sample_rate,
+            );
+            input.fill(0.0);
+            left.extend_from_slice(&left_out);
+            right.extend_from_slice(&right_out);
+        }
+        for channel in [&left, &right] {
+            assert!(channel.iter().all(|s| s.is_finite()));
+            assert!(channel.iter().any(|&s| s.abs() > 1e-4));
+        }
+        assert_ne!(left, right);
+    }
+
+    #[test]
+    fn silence_stays_silent() {
+        let output = render(48000., 48000, |_| 0.0, |_| 0.5, |_| 1.0);
+        let peak = output.iter().fold(0.0, |peak: Sample, s| peak.max(s.abs()));
+        assert!(peak < 1e-6, "{peak}");
+    }
+
+    #[test]
+    fn renders_are_deterministic_and_bounded() {
+        let input = |i| if i % 4800 < 100 { 0.8 } else { 0.0 };
+        let a = render(48000., 48000, input, |_| 1.0, |_| 1.0);
+        let b = render(48000., 48000, input, |_| 1.0, |_| 1.0);
+        assert_eq!(a, b);
+        assert!(a.iter().all(|s| s.is_finite() && s.abs() < 2.0));
+    }
+
+    #[test]
+    fn size_gives_the_original_delay_lengths() {
+        let mut galactic = Galactic::new();
+        galactic.init(SampleRate(44100.));
+        // A size of 1.0 in the original is a multiplier of 1.87
+        assert_eq!(galactic.tap_lengths[1][0], 6371);
+        galactic.update_size(0.1);
+        assert_eq!(galactic.tap_lengths[1][..4], [340, 182, 85, 33]);
+        // At 96kHz the reverb core runs at 48kHz
+        galactic.init(SampleRate(96000.));
+        galactic.update_size(1.0);
+        assert_eq!(
+            galactic.tap_lengths[1][0],
+            (3407. * 48000. / 44100.) as usize
+        );
     }
 }
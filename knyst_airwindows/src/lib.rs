@@ -1,2 +1,11 @@
+pub mod airwindows;
+mod console;
+mod density;
+mod ducker;
 mod galactic;
+mod purest_drive;
+pub use airwindows::{AirwindowsCore, AirwindowsPlugin, Parameter};
+pub use console::*;
+pub use density::*;
 pub use galactic::*;
+pub use purest_drive::*;
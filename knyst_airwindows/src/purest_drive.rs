@@ -0,0 +1,105 @@
+//! PurestDrive saturation
+//!
+//! ported from airwindows PurestDrive plugin
+//! License: MIT
+// Original code: Copyright (c) 2016 airwindows, Airwindows uses the MIT license
+
+use knyst::gen::GenState;
+use knyst::prelude::impl_gen;
+use knyst::{Sample, SampleRate};
+
+use crate::airwindows::{airwindows_gen, AirwindowsCore};
+
+/// The DSP of [`PurestDrive`]
+#[derive(Default)]
+pub struct PurestDriveCore {
+    previous_sample: [Sample; 2],
+}
+
+impl AirwindowsCore<1> for PurestDriveCore {
+    fn process_frame(
+        &mut self,
+        input: [Sample; 2],
+        [intensity]: [Sample; 1],
+        _sample_rate: SampleRate,
+    ) -> [Sample; 2] {
+        std::array::from_fn(|c| {
+            let dry = input[c];
+            let saturated = dry.sin();
+            // Saturate less if the previous sample was low level or of inverse polarity
+            let apply = ((self.previous_sample[c] + saturated).abs() / 2.0) * intensity;
+            self.previous_sample[c] = saturated;
+            (dry * (1.0 - apply)) + (saturated * apply)
+        })
+    }
+}
+
+airwindows_gen!(
+    /// Sine saturation that lets through more highs and low level detail the
+    /// less saturated the previous sample was.
+    ///
+    /// *Inputs*
+    /// - "left", "right"
+    /// - "drive": 0.0 to 1.0
+    PurestDrive,
+    PurestDriveCore,
+    [drive: 0.0]
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::airwindows::FloatingPointDither;
+
+    fn new_drive() -> PurestDrive {
+        let mut drive = PurestDrive::new();
+        drive.plugin().dither = FloatingPointDither::with_seed(1);
+        drive
+    }
+
+    #[test]
+    fn matches_the_reference() {
+        let mut drive = new_drive();
+        let input = [0.5, 0.5, -0.25];
+        let mut left_out = [0.0; 3];
+        let mut right_out = [0.0; 3];
+        drive.process(
+            &input,
+            &[0.0; 3],
+            &[1.0; 3],
+            &mut left_out,
+            &mut right_out,
+            SampleRate(44100.),
+        );
+        // Computed step by step from the C++ processReplacing
+        let mut previous: Sample = 0.0;
+        for (&x, &out) in input.iter().zip(&left_out) {
+            let apply = (previous + x.sin()).abs() / 2.0;
+            let expected = x * (1.0 - apply) + x.sin() * apply;
+            assert!((out - expected).abs() < 2e-7, "{out} {expected}");
+            previous = x.sin();
+        }
+        // Silence is replaced by noise far below hearing
+        assert!(right_out.iter().all(|s| s.abs() < 1e-7));
+    }
+
+    #[test]
+    fn no_drive_is_clean() {
+        let mut drive = new_drive();
+        let input = [0.9, -0.3, 0.1];
+        let mut left_out = [0.0; 3];
+        let mut right_out = [0.0; 3];
+        drive.process(
+            &input,
+            &input,
+            &[0.0; 3],
+            &mut left_out,
+            &mut right_out,
+            SampleRate(44100.),
+        );
+        for ((l, r), x) in left_out.iter().zip(&right_out).zip(input) {
+            assert!((l - x).abs() <= x.abs() * 2e-7);
+            assert!((r - x).abs() <= x.abs() * 2e-7);
+        }
+    }
+}
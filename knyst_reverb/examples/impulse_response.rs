@@ -0,0 +1,33 @@
+//! Render the impulse response of a LuffVerb to a WAV file and print its measurements
+use anyhow::Result;
+use knyst_reverb::{
+    analysis::{ImpulseResponse, LuffVerbParameters},
+    LuffVerb,
+};
+
+fn main() -> Result<()> {
+    let sample_rate = 48000.;
+    let mut verb: LuffVerb = LuffVerb::with_seed(2350 * 48, 1);
+    let parameters = LuffVerbParameters {
+        lowpass: 7000.,
+        damping: 4000.,
+        decay_time: 3.5,
+        ..Default::default()
+    };
+    let ir = ImpulseResponse::of_luff_verb(&mut verb, &parameters, 2, 6.0, sample_rate);
+    ir.write_wav("luffverb_ir.wav")?;
+
+    println!("RT60 broadband: {:?}", ir.rt60_broadband(0));
+    for (freq, rt60) in ir.rt60_per_octave(0) {
+        match rt60 {
+            Some(rt60) => println!("RT60 {freq} Hz: {rt60:.2} s"),
+            None => println!("RT60 {freq} Hz: doesn't decay 25 dB"),
+        }
+    }
+    let density = ir.echo_density(0, 0.02);
+    for (i, density) in density.iter().enumerate().step_by(10) {
+        println!("Echo density at {:.2} s: {density:.2}", i as f32 * 0.01);
+    }
+    println!("Stereo correlation: {:?}", ir.stereo_correlation());
+    Ok(())
+}
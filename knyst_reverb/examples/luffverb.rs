@@ -46,9 +46,18 @@ fn main() -> Result<()> {
     });
     // Set the root freq to an initial value of 200. Hz
     sine_graph.set(0, 200.);
-    let verb = luff_verb(2350 * 48, 0.65, 0.3)
+    let verb = luff_verb(2350 * 48)
         .lowpass(7000.)
-        .damping(4000.);
+        .damping(4000.)
+        .decay_time(3.5)
+        .mod_depth(0.002)
+        .mod_rate(0.4)
+        .early_reflections(0.3)
+        // Duck the tail under the dry signal
+        .threshold(-30.)
+        .ratio(4.)
+        .attack(0.01)
+        .release(0.3);
     // Connect the sine wave graph output and the first top level graph input to the reverb input
     verb.input(sine_graph * 0.125 + graph_input(0, 1));
     let sig = verb * 0.5;
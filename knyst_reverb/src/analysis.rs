@@ -0,0 +1,496 @@
+//! Offline rendering and measurement of impulse responses
+//!
+//! Renders the impulse response of a reverb without an audio backend and
+//! measures it, e.g. to catch regressions or compare presets.
+//!
+//! ```ignore
+//! let mut verb: LuffVerb = LuffVerb::with_seed(48000, 1);
+//! let ir = ImpulseResponse::of_luff_verb(&mut verb, &LuffVerbParameters::default(), 2, 3.0, 48000.);
+//! println!("RT60: {:?}", ir.rt60_per_octave(0));
+//! ir.write_wav("luffverb_ir.wav")?;
+//! ```
+
+use std::path::Path;
+
+use knyst::{prelude::Buffer, BlockSize, Sample, SampleRate};
+
+use crate::LuffVerb;
+
+/// Block size used when rendering
+const RENDER_BLOCK_SIZE: usize = 64;
+/// Zero crossings on each side of the sinc kernel when resampling
+const RESAMPLE_ZERO_CROSSINGS: usize = 32;
+/// Center frequencies of the octave bands used for RT60 measurements
+pub const OCTAVE_BANDS: [Sample; 7] = [125., 250., 500., 1000., 2000., 4000., 8000.];
+
+/// Parameters for rendering a [`LuffVerb`] offline
+#[derive(Clone, Debug, PartialEq)]
+pub struct LuffVerbParameters {
+    pub lowpass: Sample,
+    pub damping: Sample,
+    pub decay_time: Sample,
+    pub mod_depth: Sample,
+    pub mod_rate: Sample,
+    pub early_reflections: Sample,
+    pub dry: Sample,
+}
+
+impl Default for LuffVerbParameters {
+    fn default() -> Self {
+        Self {
+            lowpass: 20000.,
+            damping: 8000.,
+            decay_time: 2.0,
+            mod_depth: 0.0015,
+            mod_rate: 0.5,
+            early_reflections: 0.5,
+            dry: 0.0,
+        }
+    }
+}
+
+/// An impulse response with one `Vec` per channel
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImpulseResponse {
+    pub channels: Vec<Vec<Sample>>,
+    pub sample_rate: Sample,
+}
+
+impl ImpulseResponse {
+    /// Render an impulse response of `length` seconds by calling `process`
+    /// with blocks of mono input, a unit impulse in the very first sample,
+    /// and `num_outputs` output channels to fill.
+    ///
+    /// This works with any gen: call its `process` method in the closure with
+    /// the parameters you want to measure.
+    pub fn render(
+        num_outputs: usize,
+        length: Sample,
+        sample_rate: Sample,
+        mut process: impl FnMut(&[Sample], &mut [&mut [Sample]]),
+    ) -> Self {
+        let num_frames = (length * sample_rate) as usize;
+        let mut input = vec![0.0; RENDER_BLOCK_SIZE];
+        input[0] = 1.0;
+        let mut blocks = vec![vec![0.0; RENDER_BLOCK_SIZE]; num_outputs];
+        let mut channels = vec![Vec::with_capacity(num_frames); num_outputs];
+        while channels[0].len() < num_frames {
+            let mut outputs: Vec<&mut [Sample]> =
+                blocks.iter_mut().map(|b| b.as_mut_slice()).collect();
+            process(&input, &mut outputs);
+            input.fill(0.0);
+            for (channel, block) in channels.iter_mut().zip(&blocks) {
+                let remaining = num_frames - channel.len();
+                channel.extend_from_slice(&block[..remaining.min(RENDER_BLOCK_SIZE)]);
+            }
+        }
+        Self {
+            channels,
+            sample_rate,
+        }
+    }
+    /// Render the impulse response of a [`LuffVerb`] of any size
+    pub fn of_luff_verb<const CHANNELS: usize, const DIFFUSERS: usize>(
+        verb: &mut LuffVerb<CHANNELS, DIFFUSERS>,
+        parameters: &LuffVerbParameters,
+        num_outputs: usize,
+        length: Sample,
+        sample_rate: Sample,
+    ) -> Self {
+        let block = |value| vec![value; RENDER_BLOCK_SIZE];
+        let lowpass = block(parameters.lowpass);
+        let damping = block(parameters.damping);
+        let decay_time = block(parameters.decay_time);
+        let mod_depth = block(parameters.mod_depth);
+        let mod_rate = block(parameters.mod_rate);
+        let early_reflections = block(parameters.early_reflections);
+        let dry = block(parameters.dry);
+        let freeze = block(0.0);
+        // Ducking is off, the impulse would otherwise duck its own tail
+        let no_ducking = block(0.0);
+        verb.init_sized(BlockSize(RENDER_BLOCK_SIZE), SampleRate(sample_rate));
+        Self::render(num_outputs, length, sample_rate, |input, outputs| {
+            verb.process_multichannel(
+                &[input],
+                outputs,
+                &lowpass,
+                &damping,
+                &decay_time,
+                &mod_depth,
+                &mod_rate,
+                &early_reflections,
+                &dry,
+                &freeze,
+                &no_ducking,
+                &no_ducking,
+                &no_ducking,
+                &no_ducking,
+                &no_ducking,
+                &no_ducking,
+                SampleRate(sample_rate),
+            )
+        })
+    }
+    /// Read an impulse response from a WAV file
+    pub fn read_wav(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let mut reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+        let interleaved: Vec<Sample> = match spec.sample_format {
+            hound::SampleFormat::Float => reader
+                .samples::<f32>()
+                .map(|s| s.map(|s| s as Sample))
+                .collect::<Result<_, _>>()?,
+            hound::SampleFormat::Int => {
+                let scale = (1_i64 << (spec.bits_per_sample - 1)) as Sample;
+                reader
+                    .samples::<i32>()
+                    .map(|s| s.map(|s| s as Sample / scale))
+                    .collect::<Result<_, _>>()?
+            }
+        };
+        let num_channels = spec.channels as usize;
+        let channels = (0..num_channels)
+            .map(|c| {
+                interleaved
+                    .iter()
+                    .skip(c)
+                    .step_by(num_channels)
+                    .copied()
+                    .collect()
+            })
+            .collect();
+        Ok(Self {
+            channels,
+            sample_rate: spec.sample_rate as Sample,
+        })
+    }
+    /// Copy an impulse response from a knyst [`Buffer`], e.g. a sound file loaded into [`knyst::resources::Resources`]
+    pub fn from_buffer(buffer: &Buffer) -> Self {
+        let mut channels = vec![Vec::with_capacity(buffer.size()); buffer.num_channels()];
+        for frame in 0..buffer.size() {
+            for (channel, &sample) in channels.iter_mut().zip(buffer.get_interleaved(frame)) {
+                channel.push(sample);
+            }
+        }
+        Self {
+            channels,
+            sample_rate: buffer.sample_rate() as Sample,
+        }
+    }
+    /// Length in frames
+    pub fn len(&self) -> usize {
+        self.channels.first().map_or(0, Vec::len)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Convert to an interleaved knyst [`Buffer`], e.g. to use for convolution
+    pub fn to_buffer(&self) -> Buffer {
+        let num_channels = self.channels.len();
+        let mut interleaved = Vec::with_capacity(self.len() * num_channels);
+        for frame in 0..self.len() {
+            interleaved.extend(self.channels.iter().map(|channel| channel[frame]));
+        }
+        Buffer::from_vec_interleaved(interleaved, num_channels, self.sample_rate as f64)
+    }
+    /// Resample to `sample_rate` with windowed sinc interpolation. The
+    /// samples are scaled so that the frequency response stays the same,
+    /// e.g. for convolution at a different sample rate. Not real time safe.
+    pub fn resample(&self, sample_rate: Sample) -> Self {
+        if sample_rate == self.sample_rate {
+            return self.clone();
+        }
+        let ratio = self.sample_rate as f64 / sample_rate as f64;
+        // Lower the cutoff to the new Nyquist frequency when downsampling
+        let cutoff = ratio.recip().min(1.0);
+        let half_width = RESAMPLE_ZERO_CROSSINGS as f64 / cutoff;
+        let len = (self.len() as f64 / ratio).ceil() as usize;
+        let channels = self
+            .channels
+            .iter()
+            .map(|channel| {
+                (0..len)
+                    .map(|i| {
+                        let position = i as f64 * ratio;
+                        let first = (position - half_width).ceil().max(0.0) as usize;
+                        let last = (position + half_width).floor() as usize;
+                        let mut sum = 0.0;
+                        for (k, &sample) in channel.iter().enumerate().take(last + 1).skip(first) {
+                            let x = position - k as f64;
+                            let window = 0.5 + 0.5 * (std::f64::consts::PI * x / half_width).cos();
+                            sum += sample as f64 * cutoff * sinc(cutoff * x) * window;
+                        }
+                        (sum * ratio) as Sample
+                    })
+                    .collect()
+            })
+            .collect();
+        Self {
+            channels,
+            sample_rate,
+        }
+    }
+    /// Write the impulse response to a 32 bit float WAV file
+    pub fn write_wav(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let spec = hound::WavSpec {
+            channels: self.channels.len() as u16,
+            sample_rate: self.sample_rate as u32,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(path, spec)?;
+        for frame in 0..self.len() {
+            for channel in &self.channels {
+                writer.write_sample(channel[frame])?;
+            }
+        }
+        writer.finalize()?;
+        Ok(())
+    }
+    /// RT60 in seconds of a channel for every band in [`OCTAVE_BANDS`].
+    ///
+    /// Measured as T20, the slope of the Schroeder energy decay curve between
+    /// -5 and -25 dB. `None` if the band doesn't decay by 25 dB within the
+    /// impulse response.
+    pub fn rt60_per_octave(&self, channel: usize) -> Vec<(Sample, Option<Sample>)> {
+        OCTAVE_BANDS
+            .iter()
+            .map(|&freq| {
+                let band = octave_band(&self.channels[channel], freq, self.sample_rate);
+                (freq, rt60(&band, self.sample_rate))
+            })
+            .collect()
+    }
+    /// RT60 in seconds of the whole frequency range of a channel, see [`ImpulseResponse::rt60_per_octave`]
+    pub fn rt60_broadband(&self, channel: usize) -> Option<Sample> {
+        rt60(&self.channels[channel], self.sample_rate)
+    }
+    /// Normalised echo density profile of a channel (Abel & Huang), one value
+    /// per half window of `window` seconds.
+    ///
+    /// The value is the fraction of samples in the window more than one
+    /// standard deviation from zero, normalised so that Gaussian noise, i.e. a
+    /// fully diffuse tail, gives 1.0. Sparse early echoes give values close to 0.0.
+    pub fn echo_density(&self, channel: usize, window: Sample) -> Vec<Sample> {
+        let channel = &self.channels[channel];
+        let window_len = ((window * self.sample_rate) as usize).max(2);
+        let hann: Vec<f64> = (0..window_len)
+            .map(|i| 0.5 - 0.5 * (i as f64 * std::f64::consts::TAU / window_len as f64).cos())
+            .collect();
+        let hann_sum: f64 = hann.iter().sum();
+        // The fraction of Gaussian samples further than one standard deviation from the mean, erfc(1/sqrt(2))
+        const GAUSSIAN_OUTSIDE_STD: f64 = 0.317_310_507_862_914;
+        let mut profile = Vec::new();
+        let mut start = 0;
+        while start + window_len <= channel.len() {
+            let frame = &channel[start..start + window_len];
+            let variance = frame
+                .iter()
+                .zip(&hann)
+                .map(|(&s, w)| w * (s as f64).powi(2))
+                .sum::<f64>()
+                / hann_sum;
+            let std = variance.sqrt();
+            let outside = frame
+                .iter()
+                .zip(&hann)
+                .filter(|(&s, _)| (s as f64).abs() > std)
+                .map(|(_, w)| w)
+                .sum::<f64>()
+                / hann_sum;
+            profile.push((outside / GAUSSIAN_OUTSIDE_STD) as Sample);
+            start += window_len / 2;
+        }
+        profile
+    }
+    /// Correlation (-1..=1) between the first two channels, `None` for mono.
+    ///
+    /// Close to 0.0 means a wide, decorrelated stereo image and 1.0 is mono.
+    pub fn stereo_correlation(&self) -> Option<Sample> {
+        let [left, right, ..] = self.channels.as_slice() else {
+            return None;
+        };
+        let mut lr = 0.0;
+        let mut ll = 0.0;
+        let mut rr = 0.0;
+        for (&l, &r) in left.iter().zip(right) {
+            let (l, r) = (l as f64, r as f64);
+            lr += l * r;
+            ll += l * l;
+            rr += r * r;
+        }
+        if ll == 0.0 || rr == 0.0 {
+            return None;
+        }
+        Some((lr / (ll * rr).sqrt()) as Sample)
+    }
+}
+
+/// Normalised sinc function
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        let x = std::f64::consts::PI * x;
+        x.sin() / x
+    }
+}
+
+/// Filter out one octave band around `freq` with two cascaded bandpass biquads
+fn octave_band(signal: &[Sample], freq: Sample, sample_rate: Sample) -> Vec<Sample> {
+    // RBJ bandpass with constant 0 dB peak gain and a bandwidth of one octave
+    let w0 = std::f64::consts::TAU * freq as f64 / sample_rate as f64;
+    let q = std::f64::consts::SQRT_2;
+    let alpha = w0.sin() / (2.0 * q);
+    let a0 = 1.0 + alpha;
+    let b0 = alpha / a0;
+    let b2 = -alpha / a0;
+    let a1 = -2.0 * w0.cos() / a0;
+    let a2 = (1.0 - alpha) / a0;
+    let mut output: Vec<f64> = signal.iter().map(|&s| s as f64).collect();
+    for _ in 0..2 {
+        let (mut x1, mut x2, mut y1, mut y2) = (0.0, 0.0, 0.0, 0.0);
+        for sample in output.iter_mut() {
+            let x = *sample;
+            let y = b0 * x + b2 * x2 - a1 * y1 - a2 * y2;
+            x2 = x1;
+            x1 = x;
+            y2 = y1;
+            y1 = y;
+            *sample = y;
+        }
+    }
+    output.into_iter().map(|s| s as Sample).collect()
+}
+
+/// T20 estimate of the RT60 from the Schroeder backwards integrated energy decay curve
+fn rt60(signal: &[Sample], sample_rate: Sample) -> Option<Sample> {
+    let mut energy = vec![0.0; signal.len()];
+    let mut sum = 0.0;
+    for (e, &s) in energy.iter_mut().zip(signal).rev() {
+        sum += (s as f64).powi(2);
+        *e = sum;
+    }
+    let total = *energy.first()?;
+    if total <= 0.0 {
+        return None;
+    }
+    // Linear regression of the decay curve in dB between -5 and -25 dB
+    let (mut n, mut sum_x, mut sum_y, mut sum_xx, mut sum_xy) = (0.0, 0.0, 0.0, 0.0, 0.0);
+    let mut reached_end = false;
+    for (i, &e) in energy.iter().enumerate() {
+        let db = 10.0 * (e / total).log10();
+        if db < -25.0 {
+            reached_end = true;
+            break;
+        }
+        if db <= -5.0 {
+            let x = i as f64 / sample_rate as f64;
+            n += 1.0;
+            sum_x += x;
+            sum_y += db;
+            sum_xx += x * x;
+            sum_xy += x * db;
+        }
+    }
+    if !reached_end || n < 2.0 {
+        return None;
+    }
+    let slope = (n * sum_xy - sum_x * sum_y) / (n * sum_xx - sum_x * sum_x);
+    if slope >= 0.0 {
+        return None;
+    }
+    Some((-60.0 / slope) as Sample)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Gaussian noise decaying by 60 dB in `decay_time` seconds
+    fn decaying_noise(decay_time: Sample, sample_rate: Sample) -> ImpulseResponse {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let len = (decay_time * 1.5 * sample_rate) as usize;
+        let channel = (0..len)
+            .map(|i| {
+                let t = i as Sample / sample_rate;
+                // Approximately Gaussian noise
+                let noise = (0..12).map(|_| rng.f32()).sum::<Sample>() - 6.0;
+                noise * (0.001 as Sample).powf(t / decay_time)
+            })
+            .collect();
+        ImpulseResponse {
+            channels: vec![channel],
+            sample_rate,
+        }
+    }
+
+    #[test]
+    fn rt60_of_decaying_noise() {
+        let ir = decaying_noise(1.2, 48000.);
+        let rt60 = ir.rt60_broadband(0).unwrap();
+        assert!((rt60 - 1.2).abs() < 0.05, "{rt60}");
+        for (freq, rt60) in ir.rt60_per_octave(0) {
+            let rt60 = rt60.unwrap();
+            assert!((rt60 - 1.2).abs() < 0.15, "{freq}: {rt60}");
+        }
+        let density = ir.echo_density(0, 0.02);
+        assert!(density.iter().all(|&d| d > 0.8 && d < 1.2), "{density:?}");
+    }
+
+    #[test]
+    fn resampling_keeps_the_frequency_response() {
+        let sine = |freq: Sample, sample_rate: Sample, len| -> Vec<Sample> {
+            (0..len)
+                .map(|i| (std::f64::consts::TAU as Sample * freq * i as Sample / sample_rate).sin())
+                .collect()
+        };
+        let ir = ImpulseResponse {
+            channels: vec![sine(1000., 44100., 4410)],
+            sample_rate: 44100.,
+        };
+        let resampled = ir.resample(48000.);
+        assert_eq!(resampled.len(), 4800);
+        assert_eq!(resampled.sample_rate, 48000.);
+        // More samples per second, each scaled down to keep the same gain
+        let expected = sine(1000., 48000., 4800);
+        for (i, (s, e)) in resampled.channels[0]
+            .iter()
+            .zip(&expected)
+            .enumerate()
+            .take(4700)
+            .skip(100)
+        {
+            assert!((s - e * 44100. / 48000.).abs() < 1e-3, "{i}: {s}, {e}");
+        }
+        // Frequencies above the new Nyquist frequency are filtered out
+        let ir = ImpulseResponse {
+            channels: vec![sine(15000., 48000., 4800)],
+            sample_rate: 48000.,
+        };
+        let resampled = ir.resample(24000.);
+        assert!(resampled.channels[0][100..2300]
+            .iter()
+            .all(|s| s.abs() < 1e-2));
+    }
+
+    #[test]
+    fn luff_verb_decay_time_is_measured() {
+        let mut verb: LuffVerb = LuffVerb::with_seed(4800, 1);
+        let parameters = LuffVerbParameters {
+            damping: 20000.,
+            decay_time: 1.0,
+            mod_depth: 0.0,
+            ..Default::default()
+        };
+        let ir = ImpulseResponse::of_luff_verb(&mut verb, &parameters, 2, 2.0, 48000.);
+        assert_eq!(ir.len(), 96000);
+        assert_eq!(ImpulseResponse::from_buffer(&ir.to_buffer()), ir);
+        let rt60 = ir.rt60_broadband(0).unwrap();
+        assert!(rt60 > 0.7 && rt60 < 1.3, "{rt60}");
+        // The reverb becomes more diffuse over time
+        let density = ir.echo_density(0, 0.02);
+        assert!(density[1] < density[density.len() / 2]);
+        assert!(ir.stereo_correlation().unwrap().abs() < 0.9);
+    }
+}
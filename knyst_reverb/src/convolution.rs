@@ -0,0 +1,350 @@
+use std::sync::Arc;
+
+use knyst::{
+    prelude::{impl_gen, GenState},
+    Sample, SampleRate,
+};
+use realfft::{num_complex::Complex, ComplexToReal, RealFftPlanner, RealToComplex};
+
+use crate::analysis::ImpulseResponse;
+
+/// Uniformly partitioned overlap-save FFT convolution of one input with one impulse response channel.
+///
+/// The input is gathered into partitions of `partition_size` samples, so the
+/// output has a latency of exactly `partition_size` samples regardless of the
+/// block size. Everything is allocated in `new`, processing is real time safe.
+pub struct PartitionedConvolver {
+    partition_size: usize,
+    fft: Arc<dyn RealToComplex<Sample>>,
+    ifft: Arc<dyn ComplexToReal<Sample>>,
+    /// Spectrum of every partition of the impulse response
+    ir_partitions: Vec<Vec<Complex<Sample>>>,
+    /// Spectra of the most recent input partitions, a ring buffer with one slot per impulse response partition
+    input_spectra: Vec<Vec<Complex<Sample>>>,
+    /// Index of the newest spectrum in `input_spectra`
+    newest_spectrum: usize,
+    /// The previous and the current input partition
+    input_window: Vec<Sample>,
+    /// The output of the last processed partition
+    output_partition: Vec<Sample>,
+    /// Position in the current partition
+    position: usize,
+    fft_input: Vec<Sample>,
+    accumulated: Vec<Complex<Sample>>,
+    fft_output: Vec<Sample>,
+    scratch: Vec<Complex<Sample>>,
+}
+
+impl PartitionedConvolver {
+    pub fn new(impulse_response: &[Sample], partition_size: usize) -> Self {
+        let partition_size = partition_size.max(1);
+        let fft_size = partition_size * 2;
+        let mut planner = RealFftPlanner::<Sample>::new();
+        let fft = planner.plan_fft_forward(fft_size);
+        let ifft = planner.plan_fft_inverse(fft_size);
+        let scratch_len = fft.get_scratch_len().max(ifft.get_scratch_len());
+        let mut scratch = vec![Complex::default(); scratch_len];
+        // Scale the impulse response here instead of every inverse FFT
+        let scale = (fft_size as Sample).recip();
+        let mut fft_input = fft.make_input_vec();
+        let ir_partitions: Vec<_> = impulse_response
+            .chunks(partition_size)
+            .map(|chunk| {
+                fft_input.fill(0.0);
+                for (input, &ir) in fft_input.iter_mut().zip(chunk) {
+                    *input = ir * scale;
+                }
+                let mut spectrum = fft.make_output_vec();
+                fft.process_with_scratch(&mut fft_input, &mut spectrum, &mut scratch)
+                    .expect("buffer sizes are from the plan");
+                spectrum
+            })
+            .collect();
+        let num_partitions = ir_partitions.len().max(1);
+        Self {
+            partition_size,
+            input_spectra: vec![fft.make_output_vec(); num_partitions],
+            newest_spectrum: 0,
+            input_window: vec![0.0; fft_size],
+            output_partition: vec![0.0; partition_size],
+            position: 0,
+            accumulated: fft.make_output_vec(),
+            fft_output: ifft.make_output_vec(),
+            fft_input,
+            scratch,
+            ir_partitions,
+            fft,
+            ifft,
+        }
+    }
+    /// Latency in samples between input and output
+    pub fn latency(&self) -> usize {
+        self.partition_size
+    }
+    pub fn process(&mut self, input: &[Sample], output: &mut [Sample]) {
+        let mut frame = 0;
+        while frame < input.len() {
+            // Process as much as possible up to the end of the current partition
+            let len = (self.partition_size - self.position).min(input.len() - frame);
+            let window_start = self.partition_size + self.position;
+            self.input_window[window_start..window_start + len]
+                .copy_from_slice(&input[frame..frame + len]);
+            output[frame..frame + len]
+                .copy_from_slice(&self.output_partition[self.position..self.position + len]);
+            self.position += len;
+            frame += len;
+            if self.position == self.partition_size {
+                self.process_partition();
+                self.position = 0;
+            }
+        }
+    }
+    fn process_partition(&mut self) {
+        let num_partitions = self.input_spectra.len();
+        self.newest_spectrum = (self.newest_spectrum + 1) % num_partitions;
+        self.fft_input.copy_from_slice(&self.input_window);
+        self.fft
+            .process_with_scratch(
+                &mut self.fft_input,
+                &mut self.input_spectra[self.newest_spectrum],
+                &mut self.scratch,
+            )
+            .expect("buffer sizes are from the plan");
+        // Keep the current partition as the previous for next time
+        self.input_window.copy_within(self.partition_size.., 0);
+        // Multiply every impulse response partition with the input partition from that many partitions ago
+        self.accumulated.fill(Complex::default());
+        for (k, ir) in self.ir_partitions.iter().enumerate() {
+            let spectrum =
+                &self.input_spectra[(self.newest_spectrum + num_partitions - k) % num_partitions];
+            for ((acc, &x), &h) in self.accumulated.iter_mut().zip(spectrum).zip(ir) {
+                *acc += x * h;
+            }
+        }
+        // The imaginary parts of the DC and Nyquist bins have to be zero for the inverse
+        self.accumulated[0].im = 0.0;
+        if let Some(last) = self.accumulated.last_mut() {
+            last.im = 0.0;
+        }
+        self.ifft
+            .process_with_scratch(
+                &mut self.accumulated,
+                &mut self.fft_output,
+                &mut self.scratch,
+            )
+            .expect("buffer sizes are from the plan");
+        // Overlap-save: the second half is the valid part of the circular convolution
+        self.output_partition
+            .copy_from_slice(&self.fft_output[self.partition_size..]);
+    }
+}
+
+/// Check that an impulse response has at least one frame to convolve with
+fn check_impulse_response(impulse_response: &ImpulseResponse) -> anyhow::Result<()> {
+    if impulse_response.is_empty() {
+        anyhow::bail!("The impulse response is empty");
+    }
+    Ok(())
+}
+
+/// Mono convolution reverb using the first channel of an impulse response.
+///
+/// The output is delayed by [`ConvolutionReverb::latency`] samples, which is
+/// always `partition_size`. A smaller `partition_size` gives a lower latency
+/// at a higher CPU cost. An impulse response at another sample rate than the
+/// graph is resampled in `init`.
+pub struct ConvolutionReverb {
+    impulse_response: ImpulseResponse,
+    partition_size: usize,
+    convolver: PartitionedConvolver,
+}
+
+impl ConvolutionReverb {
+    /// Create a reverb, returning an error if the impulse response is empty
+    pub fn try_new(
+        impulse_response: ImpulseResponse,
+        partition_size: usize,
+    ) -> anyhow::Result<Self> {
+        check_impulse_response(&impulse_response)?;
+        Ok(Self {
+            convolver: PartitionedConvolver::new(&impulse_response.channels[0], partition_size),
+            impulse_response,
+            partition_size,
+        })
+    }
+    /// Latency in samples between input and output
+    pub fn latency(&self) -> usize {
+        self.convolver.latency()
+    }
+}
+
+#[impl_gen]
+impl ConvolutionReverb {
+    /// Panics if the impulse response is empty, use [`ConvolutionReverb::try_new`] to get an error instead
+    pub fn new(impulse_response: ImpulseResponse, partition_size: usize) -> Self {
+        Self::try_new(impulse_response, partition_size).unwrap()
+    }
+    /// Resample the impulse response to the sample rate of the graph. Not real time safe.
+    pub fn init(&mut self, sample_rate: SampleRate) {
+        let impulse_response = self.impulse_response.resample(*sample_rate);
+        self.convolver =
+            PartitionedConvolver::new(&impulse_response.channels[0], self.partition_size);
+    }
+    pub fn process(&mut self, input: &[Sample], output: &mut [Sample]) -> GenState {
+        self.convolver.process(input, output);
+        GenState::Continue
+    }
+}
+
+/// Stereo convolution reverb. The left input is convolved with the first
+/// channel of the impulse response and the right input with the second, or
+/// also the first for a mono impulse response.
+///
+/// The output is delayed by [`ConvolutionReverbStereo::latency`] samples, which is always `partition_size`.
+/// An impulse response at another sample rate than the graph is resampled in `init`.
+pub struct ConvolutionReverbStereo {
+    impulse_response: ImpulseResponse,
+    partition_size: usize,
+    left: PartitionedConvolver,
+    right: PartitionedConvolver,
+}
+
+impl ConvolutionReverbStereo {
+    /// Create a reverb, returning an error if the impulse response is empty
+    pub fn try_new(
+        impulse_response: ImpulseResponse,
+        partition_size: usize,
+    ) -> anyhow::Result<Self> {
+        check_impulse_response(&impulse_response)?;
+        let (left, right) = Self::convolvers(&impulse_response, partition_size);
+        Ok(Self {
+            impulse_response,
+            partition_size,
+            left,
+            right,
+        })
+    }
+    /// Latency in samples between input and output
+    pub fn latency(&self) -> usize {
+        self.left.latency()
+    }
+    fn convolvers(
+        impulse_response: &ImpulseResponse,
+        partition_size: usize,
+    ) -> (PartitionedConvolver, PartitionedConvolver) {
+        let channels = &impulse_response.channels;
+        (
+            PartitionedConvolver::new(&channels[0], partition_size),
+            PartitionedConvolver::new(channels.get(1).unwrap_or(&channels[0]), partition_size),
+        )
+    }
+}
+
+#[impl_gen]
+impl ConvolutionReverbStereo {
+    /// Panics if the impulse response is empty, use [`ConvolutionReverbStereo::try_new`] to get an error instead
+    pub fn new(impulse_response: ImpulseResponse, partition_size: usize) -> Self {
+        Self::try_new(impulse_response, partition_size).unwrap()
+    }
+    /// Resample the impulse response to the sample rate of the graph. Not real time safe.
+    pub fn init(&mut self, sample_rate: SampleRate) {
+        let impulse_response = self.impulse_response.resample(*sample_rate);
+        (self.left, self.right) = Self::convolvers(&impulse_response, self.partition_size);
+    }
+    pub fn process(
+        &mut self,
+        left: &[Sample],
+        right: &[Sample],
+        left_out: &mut [Sample],
+        right_out: &mut [Sample],
+    ) -> GenState {
+        self.left.process(left, left_out);
+        self.right.process(right, right_out);
+        GenState::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn direct_convolution(input: &[Sample], ir: &[Sample]) -> Vec<Sample> {
+        let mut output = vec![0.0; input.len()];
+        for (n, out) in output.iter_mut().enumerate() {
+            for (k, &h) in ir.iter().enumerate().take(n + 1) {
+                *out += h * input[n - k];
+            }
+        }
+        output
+    }
+
+    #[test]
+    fn matches_direct_convolution() {
+        let mut rng = fastrand::Rng::with_seed(3);
+        let ir: Vec<Sample> = (0..1000).map(|_| rng.f32() - 0.5).collect();
+        let input: Vec<Sample> = (0..5000).map(|_| rng.f32() - 0.5).collect();
+        let expected = direct_convolution(&input, &ir);
+        for (partition_size, block_size) in [(64, 64), (128, 64), (64, 100), (256, 1)] {
+            let mut convolver = PartitionedConvolver::new(&ir, partition_size);
+            let latency = convolver.latency();
+            assert_eq!(latency, partition_size);
+            let mut output = vec![0.0; input.len()];
+            for (input, output) in input.chunks(block_size).zip(output.chunks_mut(block_size)) {
+                convolver.process(input, output);
+            }
+            for (i, (out, expected)) in output[latency..].iter().zip(&expected).enumerate() {
+                assert!(
+                    (out - expected).abs() < 1e-4,
+                    "{partition_size}, {block_size}, {i}"
+                );
+            }
+            assert!(output[..latency].iter().all(|&s| s == 0.0));
+        }
+    }
+
+    #[test]
+    fn stereo_uses_a_mono_impulse_response_for_both_channels() {
+        let ir = ImpulseResponse {
+            channels: vec![vec![0.0, 0.5]],
+            sample_rate: 48000.,
+        };
+        let mut reverb = ConvolutionReverbStereo::new(ir, 16);
+        let mut impulse = [0.0; 32];
+        impulse[0] = 1.0;
+        let mut left_out = [0.0; 32];
+        let mut right_out = [0.0; 32];
+        reverb.process(&impulse, &impulse, &mut left_out, &mut right_out);
+        assert!((left_out[17] - 0.5).abs() < 1e-6);
+        assert_eq!(left_out, right_out);
+    }
+
+    #[test]
+    fn empty_impulse_responses_are_an_error() {
+        for channels in [vec![], vec![vec![]]] {
+            let ir = ImpulseResponse {
+                channels,
+                sample_rate: 48000.,
+            };
+            assert!(ConvolutionReverb::try_new(ir.clone(), 16).is_err());
+            assert!(ConvolutionReverbStereo::try_new(ir, 16).is_err());
+        }
+    }
+
+    #[test]
+    fn impulse_response_is_resampled_to_the_graph_rate() {
+        let mut channel = vec![0.0; 64];
+        channel[10] = 1.0;
+        let ir = ImpulseResponse {
+            channels: vec![channel],
+            sample_rate: 24000.,
+        };
+        let mut reverb = ConvolutionReverb::new(ir, 16);
+        reverb.init(SampleRate(48000.));
+        let mut impulse = [0.0; 64];
+        impulse[0] = 1.0;
+        let mut output = [0.0; 64];
+        reverb.process(&impulse, &mut output);
+        // Twice as many samples at half the amplitude
+        assert!((output[16 + 20] - 0.5).abs() < 1e-6, "{output:?}");
+    }
+}
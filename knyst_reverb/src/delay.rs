@@ -0,0 +1,84 @@
+use knyst::Sample;
+
+/// Delay line which can be read at a fractional delay time, e.g. for modulation.
+///
+/// A whole block is read before the block is written, so the delay time has
+/// to be at least the block size plus 2 samples for the interpolation.
+#[derive(Clone, Debug)]
+pub(crate) struct ModulatedDelay {
+    buffer: Vec<Sample>,
+    write_position: usize,
+}
+
+impl ModulatedDelay {
+    pub fn new(max_delay_length_in_samples: usize) -> Self {
+        Self {
+            // Extra room for the interpolation points
+            buffer: vec![0.0; max_delay_length_in_samples + 4],
+            write_position: 0,
+        }
+    }
+    /// Read the sample `delay` samples before frame `frame` of the current block using cubic interpolation.
+    #[inline]
+    pub fn read(&self, frame: usize, delay: Sample) -> Sample {
+        let len = self.buffer.len();
+        // Keep the integer part in integers to not lose precision on long delays
+        let delay_int = delay.floor();
+        let frac = delay - delay_int;
+        let newest = (self.write_position + frame + len - delay_int as usize) % len;
+        let y0 = self.buffer[(newest + len - 2) % len];
+        let y1 = self.buffer[(newest + len - 1) % len];
+        let y2 = self.buffer[newest];
+        let y3 = self.buffer[(newest + 1) % len];
+        cubic_interpolation(y0, y1, y2, y3, 1.0 - frac)
+    }
+    /// Write a block of samples after the block has been read
+    #[inline]
+    pub fn write_block(&mut self, input: &[Sample]) {
+        let len = self.buffer.len();
+        for &sample in input {
+            self.buffer[self.write_position] = sample;
+            self.write_position = (self.write_position + 1) % len;
+        }
+    }
+}
+
+/// Cubic Hermite interpolation between `y1` and `y2`, `t` in 0..=1
+#[inline]
+pub(crate) fn cubic_interpolation(
+    y0: Sample,
+    y1: Sample,
+    y2: Sample,
+    y3: Sample,
+    t: Sample,
+) -> Sample {
+    let c1 = 0.5 * (y2 - y0);
+    let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+    let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+    ((c3 * t + c2) * t + c1) * t + y1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integer_delay_is_exact() {
+        let block_size = 8;
+        let mut delay = ModulatedDelay::new(32);
+        let mut output = vec![0.0; block_size];
+        let mut input = vec![0.0; block_size];
+        input[0] = 1.0;
+        for block in 0..4 {
+            for (f, out) in output.iter_mut().enumerate() {
+                *out = delay.read(f, 19.0);
+            }
+            delay.write_block(&input);
+            input.fill(0.0);
+            if block == 2 {
+                // 19 samples after the impulse is frame 3 of the third block
+                assert_eq!(output, [0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0]);
+            }
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Ducking the wet signal of a reverb by the level of a sidechain
+
+use knyst::{Sample, SampleRate};
+
+/// Lowest envelope level taken into account, about -120 dB
+const MIN_LEVEL: Sample = 1e-6;
+
+/// Compressor style ducker for the wet signal of a reverb.
+///
+/// The envelope of the detector signal is followed with separate attack and
+/// release times, and everything above the threshold is reduced by the ratio.
+/// A ratio of 1.0 or less leaves the signal untouched.
+pub struct Ducker {
+    envelope: Sample,
+}
+
+impl Ducker {
+    pub fn new() -> Self {
+        Self { envelope: 0.0 }
+    }
+    /// Returns the gain to apply to the wet signal for one sample.
+    ///
+    /// `threshold` is in dB, `attack` and `release` in seconds.
+    #[inline]
+    pub fn process(
+        &mut self,
+        detector: Sample,
+        threshold: Sample,
+        ratio: Sample,
+        attack: Sample,
+        release: Sample,
+        sample_rate: SampleRate,
+    ) -> Sample {
+        let level = detector.abs();
+        let time = if level > self.envelope {
+            attack
+        } else {
+            release
+        };
+        let coefficient = if time > 0.0 {
+            (-(time * *sample_rate).recip()).exp()
+        } else {
+            0.0
+        };
+        self.envelope = level + (self.envelope - level) * coefficient;
+        if ratio <= 1.0 {
+            return 1.0;
+        }
+        let over = 20.0 * self.envelope.max(MIN_LEVEL).log10() - threshold;
+        if over > 0.0 {
+            (10.0 as Sample).powf(-over * (1.0 - ratio.recip()) / 20.0)
+        } else {
+            1.0
+        }
+    }
+}
+
+impl Default for Ducker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reduces_by_the_ratio_above_the_threshold() {
+        let sample_rate = SampleRate(48000.);
+        let mut ducker = Ducker::new();
+        // 0 dB in, 20 dB above the threshold, 4:1 leaves 5 dB above it
+        let gain = ducker.process(1.0, -20.0, 4.0, 0.0, 0.1, sample_rate);
+        assert!((20.0 * gain.log10() + 15.0).abs() < 1e-3, "{gain}");
+        assert_eq!(ducker.process(1.0, -20.0, 1.0, 0.0, 0.1, sample_rate), 1.0);
+        let mut ducker = Ducker::new();
+        assert_eq!(ducker.process(0.05, -20.0, 4.0, 0.0, 0.1, sample_rate), 1.0);
+    }
+
+    #[test]
+    fn releases_over_time() {
+        let sample_rate = SampleRate(48000.);
+        let mut ducker = Ducker::new();
+        let ducked = ducker.process(1.0, -40.0, 10.0, 0.0, 0.05, sample_rate);
+        let mut gain = ducked;
+        for _ in 0..4800 {
+            gain = ducker.process(0.0, -40.0, 10.0, 0.0, 0.05, sample_rate);
+        }
+        assert!(gain > ducked);
+        for _ in 0..48000 {
+            gain = ducker.process(0.0, -40.0, 10.0, 0.0, 0.05, sample_rate);
+        }
+        assert_eq!(gain, 1.0);
+    }
+}
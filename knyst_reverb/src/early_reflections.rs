@@ -0,0 +1,430 @@
+use knyst::{
+    prelude::{impl_gen, GenState},
+    BlockSize, Sample, SampleRate,
+};
+
+/// Speed of sound in m/s
+const SPEED_OF_SOUND: Sample = 343.0;
+
+/// One reflection: a delayed, attenuated and panned copy of the input
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tap {
+    /// Delay in seconds
+    pub delay: Sample,
+    /// Gain relative to the direct sound
+    pub gain: Sample,
+    /// Stereo position from -1.0 (left) to 1.0 (right)
+    pub pan: Sample,
+}
+
+/// A rectangular room for computing early reflections with the image source method.
+///
+/// Positions are in metres as `[x, y, z]` with the origin in a corner. The
+/// listener faces along the y axis, so x is left to right.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShoeboxRoom {
+    /// Width (x), depth (y) and height (z)
+    pub dimensions: [Sample; 3],
+    pub source: [Sample; 3],
+    pub listener: [Sample; 3],
+    /// How much energy the walls absorb per reflection, 0.0 to 1.0
+    pub absorption: Sample,
+    /// The highest number of wall reflections for a tap
+    pub max_order: usize,
+}
+
+impl Default for ShoeboxRoom {
+    fn default() -> Self {
+        Self {
+            dimensions: [8.0, 12.0, 3.5],
+            source: [3.0, 8.0, 1.5],
+            listener: [4.5, 3.0, 1.5],
+            absorption: 0.3,
+            max_order: 3,
+        }
+    }
+}
+
+impl ShoeboxRoom {
+    /// The maximum number of taps for the `max_order` of this room
+    pub fn max_taps(&self) -> usize {
+        let mut count = 0;
+        self.for_each_image(|_, _| count += 1);
+        count
+    }
+    /// Compute the reflections, not including the direct sound, sorted by delay
+    pub fn taps(&self) -> Vec<Tap> {
+        let mut taps = Vec::with_capacity(self.max_taps());
+        self.taps_into(&mut taps);
+        taps
+    }
+    /// Compute the reflections into `taps` without allocating if it has the
+    /// capacity of [`ShoeboxRoom::max_taps`].
+    pub fn taps_into(&self, taps: &mut Vec<Tap>) {
+        taps.clear();
+        let direct_distance = distance(&self.source, &self.listener).max(0.01);
+        let reflection = (1.0 - self.absorption.clamp(0.0, 1.0)).sqrt();
+        self.for_each_image(|image, order| {
+            if order == 0 {
+                return;
+            }
+            let relative = [
+                image[0] - self.listener[0],
+                image[1] - self.listener[1],
+                image[2] - self.listener[2],
+            ];
+            let distance = distance(&image, &self.listener).max(0.01);
+            taps.push(Tap {
+                delay: distance / SPEED_OF_SOUND,
+                gain: reflection.powi(order as i32) * direct_distance / distance,
+                pan: relative[0] / distance,
+            });
+        });
+        taps.sort_unstable_by(|a, b| a.delay.total_cmp(&b.delay));
+    }
+    /// Call `f` with every image source position and its number of reflections up to `max_order`
+    fn for_each_image(&self, mut f: impl FnMut([Sample; 3], usize)) {
+        let n = self.max_order as i64;
+        // Image coordinates along one axis: (1 - 2p) * s + 2 * m * L, hitting |m - p| + |m| walls
+        let image = |axis: usize, m: i64, p: i64| {
+            let position = (1 - 2 * p) as Sample * self.source[axis]
+                + 2.0 * m as Sample * self.dimensions[axis];
+            (position, ((m - p).abs() + m.abs()) as usize)
+        };
+        for mx in -n..=n {
+            for px in 0..=1 {
+                let (x, order_x) = image(0, mx, px);
+                for my in -n..=n {
+                    for py in 0..=1 {
+                        let (y, order_y) = image(1, my, py);
+                        for mz in -n..=n {
+                            for pz in 0..=1 {
+                                let (z, order_z) = image(2, mz, pz);
+                                let order = order_x + order_y + order_z;
+                                if order <= self.max_order {
+                                    f([x, y, z], order);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Set `value` to `input` unless it is 0.0 or less, e.g. an unconnected input, or NaN
+fn set_if_positive(value: &mut Sample, input: Sample) {
+    if input > 0.0 {
+        *value = input;
+    }
+}
+
+fn distance(a: &[Sample; 3], b: &[Sample; 3]) -> Sample {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (a - b).powi(2))
+        .sum::<Sample>()
+        .sqrt()
+}
+
+/// Multi-tap early reflections from a [`ShoeboxRoom`], mono in and stereo out.
+///
+/// The source and listener positions in the horizontal plane and the wall
+/// absorption are inputs. When they change the taps are recomputed at the
+/// start of the block and crossfaded over the block. Inputs at 0.0 or less,
+/// e.g. unconnected, keep the value from the room given to `new`, so a
+/// position on the wall at 0.0 or no absorption at all can only be set
+/// there. The output can be used on its own or as the early part in front of
+/// e.g. [`crate::LuffVerbStereo`] with its `early_reflections` at 0.0.
+///
+/// *Inputs*
+/// 0. "input"
+/// 1. "source_x", 2. "source_y": Source position in metres
+/// 3. "listener_x", 4. "listener_y": Listener position in metres
+/// 5. "absorption": Wall absorption, 0.0 to 1.0
+pub struct EarlyReflections {
+    /// The room given to `new`, for the inputs that are not set
+    base_room: ShoeboxRoom,
+    room: ShoeboxRoom,
+    /// The longest delay in seconds, later taps are ignored
+    max_delay: Sample,
+    buffer: Vec<Sample>,
+    write_position: usize,
+    taps: Vec<Tap>,
+    previous_taps: Vec<Tap>,
+    /// Set when the taps changed at the start of this block
+    crossfade: bool,
+}
+
+impl EarlyReflections {
+    /// The current taps
+    pub fn taps(&self) -> &[Tap] {
+        &self.taps
+    }
+    /// Update the room from the control inputs and recompute the taps if anything changed
+    fn update_room(&mut self, source: [Sample; 2], listener: [Sample; 2], absorption: Sample) {
+        let mut room = self.base_room.clone();
+        for (position, &input) in room.source[..2]
+            .iter_mut()
+            .chain(&mut room.listener[..2])
+            .zip(source.iter().chain(&listener))
+        {
+            set_if_positive(position, input);
+        }
+        set_if_positive(&mut room.absorption, absorption);
+        for (position, &dimension) in room
+            .source
+            .iter_mut()
+            .chain(room.listener.iter_mut())
+            .zip(room.dimensions.iter().cycle())
+        {
+            *position = position.clamp(0.0, dimension);
+        }
+        self.crossfade = room != self.room;
+        if self.crossfade {
+            self.room = room;
+            std::mem::swap(&mut self.taps, &mut self.previous_taps);
+            self.room.taps_into(&mut self.taps);
+        }
+    }
+    /// The left and right output of `taps` for the newest frame, scaled by `amp`
+    fn read_taps(
+        buffer: &[Sample],
+        newest: usize,
+        taps: &[Tap],
+        max_delay_in_samples: Sample,
+        sample_rate: Sample,
+        amp: Sample,
+    ) -> (Sample, Sample) {
+        let len = buffer.len();
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for tap in taps {
+            let delay = tap.delay * sample_rate;
+            if delay > max_delay_in_samples {
+                // The taps are sorted by delay
+                break;
+            }
+            let delay_int = delay.floor();
+            let frac = delay - delay_int;
+            let index = (newest + len - delay_int as usize) % len;
+            let sig = buffer[index] * (1.0 - frac) + buffer[(index + len - 1) % len] * frac;
+            // Constant power panning
+            let angle = (tap.pan + 1.0) * std::f64::consts::FRAC_PI_4 as Sample;
+            let sig = sig * tap.gain * amp;
+            left += sig * angle.cos();
+            right += sig * angle.sin();
+        }
+        (left, right)
+    }
+}
+
+#[impl_gen]
+impl EarlyReflections {
+    /// `max_delay` is the longest delay in seconds, later reflections are left out
+    pub fn new(room: ShoeboxRoom, max_delay: Sample) -> Self {
+        let max_taps = room.max_taps();
+        // Enough capacity for recomputing the taps without allocating
+        let mut taps = Vec::with_capacity(max_taps);
+        room.taps_into(&mut taps);
+        let mut previous_taps = Vec::with_capacity(max_taps);
+        previous_taps.extend_from_slice(&taps);
+        Self {
+            base_room: room.clone(),
+            room,
+            max_delay,
+            buffer: Vec::new(),
+            write_position: 0,
+            previous_taps,
+            taps,
+            crossfade: false,
+        }
+    }
+    /// Allocate the delay buffer. Not real time safe.
+    pub fn init(&mut self, sample_rate: SampleRate, block_size: BlockSize) {
+        let len = (self.max_delay * *sample_rate).ceil() as usize + 2;
+        self.buffer = vec![0.0; len.max(*block_size)];
+        self.write_position = 0;
+    }
+    pub fn process(
+        &mut self,
+        input: &[Sample],
+        source_x: &[Sample],
+        source_y: &[Sample],
+        listener_x: &[Sample],
+        listener_y: &[Sample],
+        absorption: &[Sample],
+        left: &mut [Sample],
+        right: &mut [Sample],
+        sample_rate: SampleRate,
+    ) -> GenState {
+        self.update_room(
+            [source_x[0], source_y[0]],
+            [listener_x[0], listener_y[0]],
+            absorption[0],
+        );
+        let sample_rate = *sample_rate;
+        let len = self.buffer.len();
+        let max_delay_in_samples = (len - 2) as Sample;
+        let block_size = input.len();
+        for (f, ((&input, left), right)) in input
+            .iter()
+            .zip(left.iter_mut())
+            .zip(right.iter_mut())
+            .enumerate()
+        {
+            // Write first so that a delay of 0 reads the current input
+            self.buffer[self.write_position] = input;
+            let newest = self.write_position;
+            self.write_position = (self.write_position + 1) % len;
+            let fade = if self.crossfade {
+                (f + 1) as Sample / block_size as Sample
+            } else {
+                1.0
+            };
+            let (mut l, mut r) = Self::read_taps(
+                &self.buffer,
+                newest,
+                &self.taps,
+                max_delay_in_samples,
+                sample_rate,
+                fade,
+            );
+            if fade < 1.0 {
+                let (pl, pr) = Self::read_taps(
+                    &self.buffer,
+                    newest,
+                    &self.previous_taps,
+                    max_delay_in_samples,
+                    sample_rate,
+                    1.0 - fade,
+                );
+                l += pl;
+                r += pr;
+            }
+            *left = l;
+            *right = r;
+        }
+        GenState::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_order_reflections() {
+        let room = ShoeboxRoom {
+            dimensions: [10.0, 10.0, 10.0],
+            source: [5.0, 7.0, 5.0],
+            listener: [2.0, 3.0, 5.0],
+            absorption: 0.0,
+            max_order: 1,
+        };
+        let taps = room.taps();
+        assert_eq!(taps.len(), 6);
+        assert_eq!(room.max_taps(), 7);
+        // The image in the left wall (x = 0) is at x = -5, 7 metres to the left of the listener
+        let left_wall = distance(&[-5.0, 7.0, 5.0], &room.listener);
+        let tap = taps
+            .iter()
+            .find(|tap| (tap.delay - left_wall / SPEED_OF_SOUND).abs() < 1e-6)
+            .unwrap();
+        assert!(tap.pan < -0.8);
+        assert!((tap.gain - 5.0 / left_wall).abs() < 1e-5);
+        assert!(taps.windows(2).all(|taps| taps[0].delay <= taps[1].delay));
+    }
+
+    #[test]
+    fn absorption_attenuates_higher_orders_more() {
+        let room = ShoeboxRoom {
+            absorption: 0.5,
+            ..Default::default()
+        };
+        let mut no_absorption = room.clone();
+        no_absorption.absorption = 0.0;
+        let taps = room.taps();
+        assert_eq!(taps.len(), no_absorption.taps().len());
+        for (tap, full) in taps.iter().zip(no_absorption.taps()) {
+            assert!(tap.gain < full.gain);
+        }
+    }
+
+    const SAMPLE_RATE: SampleRate = SampleRate(48000.);
+
+    /// Render the impulse response of `room` with the source x and y,
+    /// listener x and y and absorption inputs set to `controls`
+    fn render(room: &ShoeboxRoom, controls: [Sample; 5]) -> (EarlyReflections, [Vec<Sample>; 2]) {
+        let mut early = EarlyReflections::new(room.clone(), 0.2);
+        early.init(SAMPLE_RATE, BlockSize(64));
+        let control = |value| vec![value; 64];
+        let [sx, sy, lx, ly, absorption] = controls.map(control);
+        let mut input = control(0.0);
+        input[0] = 1.0;
+        let mut left = Vec::new();
+        let mut right = Vec::new();
+        let (mut l, mut r) = (control(0.0), control(0.0));
+        for _ in 0..100 {
+            early.process(
+                &input,
+                &sx,
+                &sy,
+                &lx,
+                &ly,
+                &absorption,
+                &mut l,
+                &mut r,
+                SAMPLE_RATE,
+            );
+            input.fill(0.0);
+            left.extend_from_slice(&l);
+            right.extend_from_slice(&r);
+        }
+        (early, [left, right])
+    }
+
+    #[test]
+    fn impulse_gives_the_taps() {
+        let room = ShoeboxRoom::default();
+        let (early, [left, right]) = render(
+            &room,
+            [
+                room.source[0],
+                room.source[1],
+                room.listener[0],
+                room.listener[1],
+                room.absorption,
+            ],
+        );
+        let first = early.taps()[0];
+        let first_sample = (first.delay * *SAMPLE_RATE) as usize;
+        assert!(left[..first_sample].iter().all(|&s| s == 0.0));
+        assert!(left[first_sample] != 0.0 || left[first_sample + 1] != 0.0);
+        assert_ne!(left, right);
+    }
+
+    #[test]
+    fn unconnected_inputs_keep_the_room() {
+        let room = ShoeboxRoom::default();
+        let (early, output) = render(&room, [0.0; 5]);
+        assert_eq!(early.taps(), room.taps());
+        let connected = [
+            room.source[0],
+            room.source[1],
+            room.listener[0],
+            room.listener[1],
+            room.absorption,
+        ];
+        assert_eq!(output, render(&room, connected).1);
+        // A connected input still moves the source
+        let mut moved = connected;
+        moved[0] = 6.0;
+        assert_ne!(output, render(&room, moved).1);
+        assert_eq!(
+            render(&room, [6.0, 0.0, 0.0, 0.0, 0.0]).1,
+            render(&room, moved).1
+        );
+    }
+}
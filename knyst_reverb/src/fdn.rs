@@ -0,0 +1,240 @@
+use knyst::{
+    gen::filter::one_pole::OnePole,
+    prelude::{delay::StaticSampleDelay, impl_gen, GenState},
+    Sample, SampleRate,
+};
+
+use crate::{
+    luffverb::rt60_gain,
+    matrix::{FeedbackMatrix, MixingMatrix},
+};
+
+/// Gain and damping applied to a delay line every time around the loop
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AbsorptionFilter {
+    pub gain: Sample,
+    /// Cutoff frequency in Hz of a one pole lowpass filter
+    pub cutoff: Sample,
+}
+
+/// How the delay lines of an [`Fdn`] lose energy
+#[derive(Clone, Debug, PartialEq)]
+pub enum Absorption {
+    /// The gain of every line is set from its length so that the network
+    /// decays by 60 dB in `decay_time` seconds, and all lines are damped
+    /// above `damping` Hz.
+    DecayTime { decay_time: Sample, damping: Sample },
+    /// An explicit filter for every line
+    PerLine(Vec<AbsorptionFilter>),
+}
+
+/// Settings for an [`Fdn`]. All the per line `Vec`s need to be as long as `delay_lengths`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FdnSettings {
+    /// Length in samples of every delay line. This also sets the number of lines.
+    pub delay_lengths: Vec<usize>,
+    pub matrix: FeedbackMatrix,
+    pub absorption: Absorption,
+    /// How much of the input is fed into every line
+    pub input_gains: Vec<Sample>,
+    /// How much of every line is mixed into the left output
+    pub left_gains: Vec<Sample>,
+    /// How much of every line is mixed into the right output
+    pub right_gains: Vec<Sample>,
+}
+
+impl FdnSettings {
+    /// Settings for `delay_lengths.len()` lines with gain vectors that give
+    /// decorrelated left and right outputs.
+    pub fn new(delay_lengths: Vec<usize>, matrix: FeedbackMatrix, absorption: Absorption) -> Self {
+        let lines = delay_lengths.len();
+        let scale = (lines as Sample).sqrt().recip();
+        // Different sign patterns for left and right so that they are decorrelated
+        let left_gains = (0..lines)
+            .map(|i| if i % 2 == 0 { scale } else { -scale })
+            .collect();
+        let right_gains = (0..lines)
+            .map(|i| if (i / 2) % 2 == 0 { scale } else { -scale })
+            .collect();
+        Self {
+            delay_lengths,
+            matrix,
+            absorption,
+            input_gains: vec![scale; lines],
+            left_gains,
+            right_gains,
+        }
+    }
+}
+
+impl Default for FdnSettings {
+    fn default() -> Self {
+        Self::new(
+            vec![1123, 1289, 1433, 1601, 1783, 1951, 2113, 2293],
+            FeedbackMatrix::Householder,
+            Absorption::DecayTime {
+                decay_time: 2.0,
+                damping: 8000.,
+            },
+        )
+    }
+}
+
+/// Feedback delay network with a configurable number of lines, feedback
+/// matrix, absorption and input/output gains.
+///
+/// Mono in, stereo out. Where [`crate::LuffVerb`] is a complete reverb, this
+/// is a building block for designing the character of a room.
+pub struct Fdn {
+    settings: FdnSettings,
+    delays: Vec<StaticSampleDelay>,
+    matrix: MixingMatrix,
+    gains: Vec<Sample>,
+    lowpasses: Vec<OnePole<Sample>>,
+    /// The output of every line for one frame
+    line_outputs: Vec<Sample>,
+    /// The mixed signal fed back into every line for one frame
+    line_inputs: Vec<Sample>,
+}
+
+#[impl_gen]
+impl Fdn {
+    /// # Panics
+    /// If the per line settings don't all have the same length, see also [`FeedbackMatrix::build`].
+    pub fn new(settings: FdnSettings) -> Self {
+        let lines = settings.delay_lengths.len();
+        assert!(lines > 0, "An Fdn needs at least one delay line");
+        assert_eq!(settings.input_gains.len(), lines);
+        assert_eq!(settings.left_gains.len(), lines);
+        assert_eq!(settings.right_gains.len(), lines);
+        if let Absorption::PerLine(filters) = &settings.absorption {
+            assert_eq!(filters.len(), lines);
+        }
+        Self {
+            delays: settings
+                .delay_lengths
+                .iter()
+                .map(|&length| StaticSampleDelay::new(length.max(1)))
+                .collect(),
+            matrix: settings.matrix.build(lines),
+            gains: vec![0.0; lines],
+            lowpasses: (0..lines).map(|_| OnePole::new()).collect(),
+            line_outputs: vec![0.0; lines],
+            line_inputs: vec![0.0; lines],
+            settings,
+        }
+    }
+    /// Set up the absorption filters for the sample rate
+    pub fn init(&mut self, sample_rate: SampleRate) {
+        let filters: Vec<AbsorptionFilter> = match &self.settings.absorption {
+            Absorption::DecayTime {
+                decay_time,
+                damping,
+            } => self
+                .settings
+                .delay_lengths
+                .iter()
+                .map(|&length| AbsorptionFilter {
+                    gain: rt60_gain(length, decay_time.max(Sample::EPSILON), sample_rate),
+                    cutoff: *damping,
+                })
+                .collect(),
+            Absorption::PerLine(filters) => filters.clone(),
+        };
+        for ((gain, lowpass), filter) in self.gains.iter_mut().zip(&mut self.lowpasses).zip(filters)
+        {
+            *gain = filter.gain;
+            lowpass.set_freq_lowpass(filter.cutoff, *sample_rate);
+        }
+    }
+    pub fn process(
+        &mut self,
+        input: &[Sample],
+        left: &mut [Sample],
+        right: &mut [Sample],
+    ) -> GenState {
+        for ((&input, left), right) in input.iter().zip(left.iter_mut()).zip(right.iter_mut()) {
+            let mut left_sum = 0.0;
+            let mut right_sum = 0.0;
+            for (i, delay) in self.delays.iter().enumerate() {
+                let sig = delay.read();
+                left_sum += sig * self.settings.left_gains[i];
+                right_sum += sig * self.settings.right_gains[i];
+                self.line_outputs[i] = self.lowpasses[i].process_lp(sig * self.gains[i]);
+            }
+            *left = left_sum;
+            *right = right_sum;
+            self.matrix
+                .process(&self.line_outputs, &mut self.line_inputs);
+            for (i, delay) in self.delays.iter_mut().enumerate() {
+                delay.write_and_advance(self.line_inputs[i] + input * self.settings.input_gains[i]);
+            }
+        }
+        GenState::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::ImpulseResponse;
+
+    fn impulse_response(matrix: FeedbackMatrix) -> ImpulseResponse {
+        let settings = FdnSettings::new(
+            vec![1123, 1289, 1433, 1601, 1783, 1951, 2113, 2293],
+            matrix,
+            Absorption::DecayTime {
+                decay_time: 1.0,
+                damping: 20000.,
+            },
+        );
+        let mut fdn = Fdn::new(settings);
+        fdn.init(SampleRate(48000.));
+        ImpulseResponse::render(2, 2.0, 48000., |input, outputs| {
+            let [left, right] = outputs else {
+                unreachable!()
+            };
+            fdn.process(input, left, right);
+        })
+    }
+
+    #[test]
+    fn every_matrix_decays_in_the_decay_time() {
+        for matrix in [
+            FeedbackMatrix::Householder,
+            FeedbackMatrix::Hadamard,
+            FeedbackMatrix::RandomOrthogonal { seed: 1 },
+            FeedbackMatrix::lossless_circulant(8, 1),
+        ] {
+            let ir = impulse_response(matrix.clone());
+            let rt60 = ir.rt60_broadband(0).unwrap();
+            assert!(rt60 > 0.8 && rt60 < 1.2, "{matrix:?}: {rt60}");
+            assert!(ir.stereo_correlation().unwrap().abs() < 0.5);
+        }
+    }
+
+    #[test]
+    fn per_line_absorption_is_used() {
+        let settings = FdnSettings {
+            absorption: Absorption::PerLine(vec![
+                AbsorptionFilter {
+                    gain: 0.0,
+                    cutoff: 20000.,
+                };
+                8
+            ]),
+            ..Default::default()
+        };
+        let mut fdn = Fdn::new(settings);
+        fdn.init(SampleRate(48000.));
+        let ir = ImpulseResponse::render(2, 0.2, 48000., |input, outputs| {
+            let [left, right] = outputs else {
+                unreachable!()
+            };
+            fdn.process(input, left, right);
+        });
+        // With no feedback only the first pass through each line is heard
+        let last_echo = ir.channels[0].iter().rposition(|&s| s != 0.0).unwrap();
+        assert_eq!(last_echo, 2293);
+    }
+}
@@ -1,10 +1,25 @@
-/*
+use crate::delay::cubic_interpolation;
+use knyst::{
+    prelude::{impl_gen, GenState},
+    Sample, SampleRate,
+};
+
+/// Number of points in the grain window
+const WINDOW_SIZE: usize = 4096;
+/// Max number of grains playing at the same time
+const MAX_GRAINS: usize = 20;
+/// Closest a grain may read to the write position, leaving room for the interpolation
+const WRITE_MARGIN: f64 = 4.0;
+
 #[derive(Clone, Debug)]
 struct Grain {
-    /// phase and read_ptr are two representations of the same thing, but phase is needed to index into the window buffer
+    /// phase and position are two representations of the same thing, but phase is needed to index into the window buffer
     phase: Sample,
     phase_step: Sample,
-    read_ptr: usize,
+    /// Fractional read position in the buffer
+    position: f64,
+    /// How far the read position moves every sample, negative when playing in reverse
+    rate: f64,
     active: bool,
 }
 
@@ -13,145 +28,333 @@ impl Grain {
         Self {
             phase: 0.0,
             phase_step: 0.0,
-            read_ptr: 0,
+            position: 0.0,
+            rate: 1.0,
             active: false,
         }
     }
-    fn start(&mut self, start_pos: usize, phase_step: Sample) {
+    fn start(&mut self, start_pos: f64, phase_step: Sample, rate: f64) {
         self.active = true;
         self.phase = 0.0;
         self.phase_step = phase_step;
-        self.read_ptr = start_pos;
+        self.position = start_pos;
+        self.rate = rate;
+    }
+    /// Read the buffer at the current fractional position
+    fn read(&self, buffer: &[Sample]) -> Sample {
+        let len = buffer.len();
+        let index = self.position.floor();
+        let t = (self.position - index) as Sample;
+        let index = index as usize;
+        cubic_interpolation(
+            buffer[(index + len - 1) % len],
+            buffer[index % len],
+            buffer[(index + 1) % len],
+            buffer[(index + 2) % len],
+            t,
+        )
     }
-    fn update(&mut self) {
+    fn update(&mut self, buffer_len: usize) {
         self.phase += self.phase_step;
-        self.read_ptr += 1;
+        self.position = (self.position + self.rate).rem_euclid(buffer_len as f64);
         if self.phase >= 1.0 {
             self.active = false;
         }
     }
 }
+
 /// A ring buffer recording for a certain length of time and playing random snippets from the buffer
-#[derive(Clone, Debug)]
+///
+/// *Inputs*
+/// 0. "input": The signal to record
+/// 1. "density": The average number of new grains per second
+/// 2. "jump_min": The shortest time in seconds a grain jumps back in the recording
+/// 3. "jump_max": The longest time in seconds a grain jumps back in the recording
+/// 4. "duration_min": The shortest grain duration in seconds
+/// 5. "duration_max": The longest grain duration in seconds
+/// 6. "feedback": How much of the output is recorded again together with the input.
+///    Many overlapping grains can add up to more than 1.0 so keep it low with a high density.
+/// 7. "transpose": Transposition of the grains in semitones
+/// 8. "transpose_spread": Every grain is transposed by a random amount within +- this many semitones
+/// 9. "reverse": The probability (0..=1) that a grain plays in reverse
+///
+/// Grains that play faster or slower than the recording, or in reverse, are
+/// kept from crossing the write position by limiting how far back they can
+/// jump.
+///
+/// *Outputs*
+/// 0. "output": The sum of all playing grains
 pub struct FragmentedDelay {
     buffer: Vec<Sample>,
     write_ptr: usize,
-    /// Grain window
-    window: Wavetable,
+    /// Max time in seconds the grains can jump back in the recording
+    max_delay_time: Sample,
+    /// Hann grain window
+    window: Vec<Sample>,
     grains: Vec<Grain>,
-    grain_index: usize,
-    // In samples
-    jump_distance_max: usize,
-    // In samples
-    jump_distance_min: usize,
-    /// jump_distance_max - jump_distance_min
-    jump_distance_width: usize,
-    // In samples
-    duration_max: usize,
-    duration_min: usize,
-    duration_width: usize, // optimisation to avoid duration_max-duration_min every sample
-    sample_rate: Sample,
+    rng: fastrand::Rng,
+    /// The last output, recorded again for feedback
+    last_output: Sample,
 }
+
 impl FragmentedDelay {
-    pub fn new(
-        recording_time: Sample,
-        delay_duration_min: Sample,
-        delay_duration_max: Sample,
-        jump_distance_min: Sample,
-        jump_distance_max: Sample,
-        sample_rate: Sample,
-    ) -> Self {
-        let jump_distance_max = (jump_distance_max * sample_rate) as usize;
-        let jump_distance_min = (jump_distance_min * sample_rate) as usize;
-        let duration_max = (delay_duration_max * sample_rate) as usize;
-        let duration_min = (delay_duration_min * sample_rate) as usize;
-        let window = Wavetable::hann_window(4096);
+    /// Create a delay with a specific random seed. The same seed and inputs always give the same output.
+    pub fn with_seed(max_delay_time: Sample, seed: u64) -> Self {
+        let window = (0..WINDOW_SIZE)
+            .map(|i| {
+                let phase = i as Sample / WINDOW_SIZE as Sample;
+                0.5 - 0.5 * (phase * std::f64::consts::TAU as Sample).cos()
+            })
+            .collect();
         Self {
-            buffer: vec![0.0; (recording_time * sample_rate).ceil() as usize],
+            buffer: vec![],
             write_ptr: 0,
+            max_delay_time,
             window,
-            grains: vec![Grain::inactive(); 20],
-            grain_index: 0,
-            jump_distance_max,
-            jump_distance_min,
-            jump_distance_width: jump_distance_max - jump_distance_min,
-            duration_max,
-            duration_min,
-            duration_width: duration_max - duration_min,
-            sample_rate,
+            grains: vec![Grain::inactive(); MAX_GRAINS],
+            rng: fastrand::Rng::with_seed(seed),
+            last_output: 0.0,
+        }
+    }
+    /// Start a new grain if there is a free one
+    fn start_grain(
+        &mut self,
+        jump_min: Sample,
+        jump_max: Sample,
+        duration_min: Sample,
+        duration_max: Sample,
+        transpose: Sample,
+        transpose_spread: Sample,
+        reverse: Sample,
+        sample_rate: Sample,
+    ) {
+        let Some(grain) = self.grains.iter_mut().find(|g| !g.active) else {
+            return;
+        };
+        let semitones = transpose + transpose_spread * (self.rng.f32() * 2.0 - 1.0);
+        let mut rate = 2.0_f64.powf(semitones as f64 / 12.0);
+        if self.rng.f32() < reverse {
+            rate = -rate;
         }
+        let buffer_len = self.buffer.len();
+        let (duration_min, duration_max) = sorted_samples(duration_min, duration_max, sample_rate);
+        let duration = self.rng.usize(duration_min.max(1)..=duration_max.max(1));
+        let (jump_min, jump_max) = sorted_samples(jump_min, jump_max, sample_rate);
+        let (allowed_min, allowed_max) = jump_range(buffer_len, duration, rate);
+        let jump_min = (jump_min as f64).clamp(allowed_min, allowed_max);
+        let jump_max = (jump_max as f64).clamp(jump_min, allowed_max);
+        let jump_distance = jump_min + (jump_max - jump_min) * self.rng.f64();
+        let start_pos = (self.write_ptr as f64 - jump_distance).rem_euclid(buffer_len as f64);
+        // The phase goes from 0 to 1 over the duration of the grain
+        grain.start(start_pos, (duration as Sample).recip(), rate);
+    }
+}
+
+/// The range of jump distances in samples for which a grain of `duration`
+/// samples playing at `rate` stays between the write position and the oldest
+/// sample in the buffer for its whole duration.
+///
+/// If the grain moves further than the buffer allows, the range collapses to
+/// the middle of the buffer.
+fn jump_range(buffer_len: usize, duration: usize, rate: f64) -> (f64, f64) {
+    // How much the distance to the write position grows over the grain
+    let drift = duration as f64 * (1.0 - rate);
+    let min = WRITE_MARGIN - drift.min(0.0);
+    let max = buffer_len as f64 - WRITE_MARGIN - drift.max(0.0);
+    if min <= max {
+        (min, max)
+    } else {
+        let middle = (buffer_len as f64 - drift) * 0.5;
+        (middle, middle)
+    }
+}
+
+/// Convert two times in seconds to samples, shortest first
+fn sorted_samples(a: Sample, b: Sample, sample_rate: Sample) -> (usize, usize) {
+    let a = (a.max(0.0) * sample_rate) as usize;
+    let b = (b.max(0.0) * sample_rate) as usize;
+    (a.min(b), a.max(b))
+}
+
+#[impl_gen]
+impl FragmentedDelay {
+    /// Create a delay with a recording of `max_delay_time` seconds, seeded from knyst's randomness seed
+    pub fn new(max_delay_time: Sample) -> Self {
+        Self::with_seed(max_delay_time, knyst::gen::random::next_randomness_seed())
     }
-    pub fn set_delay_duration_interval(&mut self, min: Sample, max: Sample) {
-        self.duration_min = (min * self.sample_rate) as usize;
-        self.duration_max = (max * self.sample_rate) as usize;
-        self.duration_width = self.duration_max - self.duration_min;
+    /// Allocate the recording buffer. Not real time safe.
+    pub fn init(&mut self, sample_rate: SampleRate) {
+        let len = (self.max_delay_time * *sample_rate).ceil() as usize;
+        self.buffer = vec![0.0; len.max(WRITE_MARGIN as usize * 2)];
+        self.write_ptr = 0;
     }
-    /// Set the minimum and maximum time in seconds that the delay playback will jump back in time
-    pub fn set_jump_interval(&mut self, min: Sample, max: Sample) {
-        if max < min {
-            panic!("set_jump_interval max is smaller than min");
+    pub fn process(
+        &mut self,
+        input: &[Sample],
+        density: &[Sample],
+        jump_min: &[Sample],
+        jump_max: &[Sample],
+        duration_min: &[Sample],
+        duration_max: &[Sample],
+        feedback: &[Sample],
+        transpose: &[Sample],
+        transpose_spread: &[Sample],
+        reverse: &[Sample],
+        output: &mut [Sample],
+        sample_rate: SampleRate,
+    ) -> GenState {
+        let sample_rate = *sample_rate;
+        for i in 0..output.len() {
+            // Check if we need to add a new grain
+            if self.rng.f32() < density[i] / sample_rate {
+                self.start_grain(
+                    jump_min[i],
+                    jump_max[i],
+                    duration_min[i],
+                    duration_max[i],
+                    transpose[i],
+                    transpose_spread[i],
+                    reverse[i],
+                    sample_rate,
+                );
+            }
+            // Read output from buffer
+            let buffer_len = self.buffer.len();
+            let mut out = 0.0;
+            for g in self.grains.iter_mut().filter(|g| g.active) {
+                let window_index =
+                    ((g.phase * WINDOW_SIZE as Sample) as usize).min(WINDOW_SIZE - 1);
+                out += g.read(&self.buffer) * self.window[window_index];
+                g.update(buffer_len);
+            }
+            // Write the input into the buffer
+            self.buffer[self.write_ptr] = input[i] + self.last_output * feedback[i];
+            self.write_ptr += 1;
+            if self.write_ptr >= buffer_len {
+                self.write_ptr = 0;
+            }
+            self.last_output = out;
+            output[i] = out;
         }
-        self.jump_distance_min = (min * self.sample_rate) as usize;
-        self.jump_distance_max = ((max * self.sample_rate) as usize).max(1);
-        self.jump_distance_width = self.jump_distance_max - self.jump_distance_min;
-    }
-    #[inline(always)]
-    pub fn process(&mut self, resources: &mut Resources, input: Sample) -> Sample {
-        // First write and then read, since if the delay is 0 we should read the
-        // current input and not one buffer size away
-        //
-        // Write the input into the buffer
-        self.buffer[self.write_ptr] = input;
-        // Advance write ptr
-        self.write_ptr += 1;
-        if self.write_ptr >= self.buffer.len() {
-            self.write_ptr = 0;
+        GenState::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(
+        delay: &mut FragmentedDelay,
+        density: Sample,
+        feedback: Sample,
+        transpose: Sample,
+        reverse: Sample,
+    ) -> Vec<Sample> {
+        let block_size = 64;
+        let sample_rate = SampleRate(48000.);
+        delay.init(sample_rate);
+        let input: Vec<Sample> = (0..block_size)
+            .map(|i| (i as Sample * 0.05).sin())
+            .collect();
+        let density = vec![density; block_size];
+        let jump_min = vec![0.01; block_size];
+        let jump_max = vec![0.2; block_size];
+        let duration_min = vec![0.005; block_size];
+        let duration_max = vec![0.05; block_size];
+        let feedback = vec![feedback; block_size];
+        let transpose = vec![transpose; block_size];
+        let transpose_spread = vec![0.5; block_size];
+        let reverse = vec![reverse; block_size];
+        let mut output = vec![0.0; block_size];
+        let mut rendered = Vec::new();
+        for _ in 0..400 {
+            delay.process(
+                &input,
+                &density,
+                &jump_min,
+                &jump_max,
+                &duration_min,
+                &duration_max,
+                &feedback,
+                &transpose,
+                &transpose_spread,
+                &reverse,
+                &mut output,
+                sample_rate,
+            );
+            rendered.extend_from_slice(&output);
         }
-        // Read output from buffer
-        let mut out = 0.0;
-        for g in &mut self.grains {
-            if g.active {
-                out += self.buffer[g.read_ptr % self.buffer.len()] * self.window.get(g.phase);
-                g.update();
-            }
+        rendered
+    }
+
+    #[test]
+    fn seeded_output_is_deterministic() {
+        let a = render(&mut FragmentedDelay::with_seed(0.5, 4), 20.0, 0.3, 0.0, 0.0);
+        let b = render(&mut FragmentedDelay::with_seed(0.5, 4), 20.0, 0.3, 0.0, 0.0);
+        let c = render(&mut FragmentedDelay::with_seed(0.5, 5), 20.0, 0.3, 0.0, 0.0);
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert!(a.iter().any(|&s| s != 0.0));
+    }
+
+    #[test]
+    fn zero_density_is_silent() {
+        let output = render(&mut FragmentedDelay::with_seed(0.5, 4), 0.0, 0.5, 0.0, 0.0);
+        assert!(output.iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn jumps_longer_than_the_recording_do_not_underflow() {
+        let mut delay = FragmentedDelay::with_seed(0.01, 1);
+        let sample_rate = SampleRate(48000.);
+        delay.init(sample_rate);
+        let long = [10.0; 16];
+        let mut output = [0.0; 16];
+        for _ in 0..100 {
+            delay.process(
+                &[1.0; 16],
+                &[48000.0; 16],
+                &long,
+                &[-1.0; 16],
+                &[0.0; 16],
+                &long,
+                &[0.0; 16],
+                &[12.0; 16],
+                &[12.0; 16],
+                &[0.5; 16],
+                &mut output,
+                sample_rate,
+            );
         }
+        assert!(output.iter().all(|s| s.is_finite()));
+    }
 
-        // Check if we need to add a new grain
-        if resources.rng.f32() > 0.99966 {
-            let duration = self.duration_min + resources.rng.usize(0..self.duration_width);
-            let jump_distance =
-                self.jump_distance_min + resources.rng.usize(0..self.jump_distance_width);
-            // Add the buffer size to avoid usize underrun
-            let mut start_pos = self.buffer.len() + self.write_ptr - jump_distance;
-            while start_pos > self.buffer.len() {
-                start_pos -= self.buffer.len();
+    #[test]
+    fn transposed_grains_stay_behind_the_write_position() {
+        let buffer_len = 48000;
+        let duration = 4800;
+        for rate in [0.25, 1.0, 2.0, 4.0, -0.5, -2.0] {
+            let (min, max) = jump_range(buffer_len, duration, rate);
+            assert!(min <= max);
+            for jump in [min, max] {
+                // Distance behind the write position at the start and end of the grain
+                let end = jump + duration as f64 * (1.0 - rate);
+                for distance in [jump, end] {
+                    assert!(distance >= WRITE_MARGIN, "rate {rate}: {distance}");
+                    assert!(distance <= buffer_len as f64 - WRITE_MARGIN);
+                }
             }
-            let phase_step = duration as Sample / self.sample_rate.powi(2) as Sample;
-            self.grains[self.grain_index].start(start_pos, phase_step);
-            self.grain_index = (self.grain_index + 1) % self.grains.len();
         }
+    }
 
-        // self.read_ptr += 1;
-        // if self.read_ptr >= self.buffer.len() {
-        //     self.read_ptr = 0;
-        // }
-        // // Check if we need to set a new read ptr position
-        // if self.read_ptr == self.read_ptr_end {
-        //     let duration = self.duration_min + resources.rng.usize(0..self.duration_width);
-        //     let jump_distance =
-        //         self.jump_distance_min + resources.rng.usize(0..self.jump_distance_width);
-        //     // Add the buffer size to avoid usize underrun
-        //     self.read_ptr = self.buffer.len() + self.write_ptr - jump_distance;
-        //     while self.read_ptr > self.buffer.len() {
-        //         self.read_ptr -= self.buffer.len();
-        //     }
-        //     self.read_ptr_end = self.read_ptr + duration;
-        //     while self.read_ptr_end > self.buffer.len() {
-        //         self.read_ptr_end -= self.buffer.len();
-        //     }
-        // }
-
-        out
+    #[test]
+    fn transposed_and_reversed_grains_differ() {
+        let plain = render(&mut FragmentedDelay::with_seed(0.5, 4), 20.0, 0.0, 0.0, 0.0);
+        let shifted = render(&mut FragmentedDelay::with_seed(0.5, 4), 20.0, 0.0, 7.0, 0.0);
+        let reversed = render(&mut FragmentedDelay::with_seed(0.5, 4), 20.0, 0.0, 0.0, 1.0);
+        assert_ne!(plain, shifted);
+        assert_ne!(plain, reversed);
+        assert!(shifted.iter().chain(&reversed).all(|s| s.is_finite()));
     }
 }
-*/
@@ -1,2 +1,18 @@
+pub mod analysis;
+mod convolution;
+mod delay;
+mod ducker;
+mod early_reflections;
+mod fdn;
+mod granular_delay;
 mod luffverb;
+pub mod matrix;
+mod plate;
+mod spring;
+pub use convolution::*;
+pub use early_reflections::*;
+pub use fdn::*;
+pub use granular_delay::*;
 pub use luffverb::*;
+pub use plate::*;
+pub use spring::*;
@@ -1,26 +1,22 @@
+use crate::delay::ModulatedDelay;
+use crate::ducker::Ducker;
+use crate::matrix;
 use knyst::{
-    gen::filter::one_pole::*,
+    gen::filter::one_pole::OnePole,
     prelude::{delay::StaticSampleDelay, impl_gen, GenState},
     BlockSize, Sample, SampleRate,
 };
-use rand::{seq::SliceRandom, thread_rng, Rng};
 struct Diffuser<const CHANNELS: usize> {
     delays: [StaticSampleDelay; CHANNELS],
     flip_polarity: [Sample; CHANNELS],
 }
 
 impl<const CHANNELS: usize> Diffuser<CHANNELS> {
-    pub fn new(max_delay_length_in_samples: usize) -> Self {
-        let mut rng = thread_rng();
-        let mut flip_polarity = [-1.0; CHANNELS];
-        flip_polarity[CHANNELS / 2..].fill(1.);
-        flip_polarity.shuffle(&mut rng);
-        let delays = std::array::from_fn(|i| {
-            let time_min = (max_delay_length_in_samples / CHANNELS * i) as usize + 1;
-            let time_max = max_delay_length_in_samples / CHANNELS * (i + 1);
-            let delay_time = rng.gen_range(time_min..time_max);
-            StaticSampleDelay::new(delay_time)
-        });
+    pub fn new(delay_times: [usize; CHANNELS], flip_polarity: [bool; CHANNELS]) -> Self {
+        // The Hadamard matrix is not normalised so the scaling is baked into the polarity flips to preserve energy
+        let scale = (CHANNELS as Sample).sqrt().recip();
+        let flip_polarity = flip_polarity.map(|flip| if flip { -scale } else { scale });
+        let delays = delay_times.map(StaticSampleDelay::new);
 
         Self {
             flip_polarity,
@@ -48,50 +44,127 @@ impl<const CHANNELS: usize> Diffuser<CHANNELS> {
     }
 }
 
+/// One pole lowpass filter with a cutoff that can change every sample
+struct Lowpass {
+    one_pole: OnePole<Sample>,
+    cutoff: Sample,
+}
+
+impl Lowpass {
+    pub fn new() -> Self {
+        Self {
+            one_pole: OnePole::new(),
+            cutoff: Sample::NAN,
+        }
+    }
+    pub fn process(
+        &mut self,
+        sample_rate: SampleRate,
+        input: &[Sample],
+        cutoff: &[Sample],
+        output: &mut [Sample],
+    ) {
+        for ((out, &input), &cutoff) in output.iter_mut().zip(input).zip(cutoff) {
+            // Only recalculate the coefficients when the cutoff changes
+            if cutoff != self.cutoff {
+                self.one_pole.set_freq_lowpass(cutoff, *sample_rate);
+                self.cutoff = cutoff;
+            }
+            *out = self.one_pole.process_lp(input);
+        }
+    }
+}
+
+/// The longest modulation depth of the tail delays in seconds
+const MAX_MOD_DEPTH: Sample = 0.01;
+/// Time in seconds to fade in and out of freeze
+const FREEZE_FADE_TIME: Sample = 0.05;
+/// Decay time in seconds when the `decay_time` input is 0.0 or less, e.g. unconnected
+const DEFAULT_DECAY_TIME: Sample = 2.0;
+/// Gain of the wet signal on top of the channel compensation. The diffusers
+/// are normalised, which makes them 4 times louder than the original
+/// unnormalised 2x4 diffusers, and the original scaled the sum by 1/8. This
+/// keeps the output level of the original mono 2x4 reverb.
+const WET_GAIN: Sample = std::f64::consts::FRAC_1_SQRT_2 as Sample;
+
 /// Tail block of a reverb. Simply a relatively long feedback delay.
 struct Tail<const CHANNELS: usize> {
-    feedback_gain: Sample,
-    /// Size is the length of the delay
-    delays: [StaticSampleDelay; CHANNELS],
-    lowpasses: [OnePoleLpf; CHANNELS],
+    /// Current feedback gain per delay line, ramped towards the gain for the decay time every block
+    feedback_gains: [Sample; CHANNELS],
+    delay_lengths: [usize; CHANNELS],
+    /// Size is the length of the delay plus the max modulation depth
+    delays: [ModulatedDelay; CHANNELS],
+    /// Modulation depth in samples that fits in the delays
+    max_mod_depth: Sample,
+    /// Phase (0..1) of the modulation LFO of every delay line
+    lfo_phases: [Sample; CHANNELS],
+    /// Every delay line is modulated at a slightly different rate to decorrelate them
+    lfo_rate_factors: [Sample; CHANNELS],
+    lowpasses: [Lowpass; CHANNELS],
     /// One block of samples
     process_temp_buffers: [Vec<Sample>; CHANNELS],
     process_temp_buffers1: [Vec<Sample>; CHANNELS],
 }
 
 impl<const CHANNELS: usize> Tail<CHANNELS> {
-    pub fn new(delay_length_in_samples: usize, feedback: Sample) -> Self {
-        let time_min = delay_length_in_samples / 10;
-        let time_max = delay_length_in_samples;
-        let mut rng = thread_rng();
-        let delays = std::array::from_fn(|_i| {
-            let delay_time = rng.gen_range(time_min..time_max);
-            StaticSampleDelay::new(delay_time)
-        });
-        let lowpasses = std::array::from_fn(|_| OnePoleLpf::new());
+    pub fn new(delay_lengths: [usize; CHANNELS]) -> Self {
+        let delays = delay_lengths.map(ModulatedDelay::new);
+        let lowpasses = std::array::from_fn(|_| Lowpass::new());
+        // Spread the phases evenly and the rates irregularly (golden ratio) within +-15%
+        let lfo_phases = std::array::from_fn(|i| i as Sample / CHANNELS as Sample);
+        let lfo_rate_factors =
+            std::array::from_fn(|i| 1.0 + 0.3 * ((i as Sample * 0.618034).fract() - 0.5));
         Self {
-            feedback_gain: feedback,
+            feedback_gains: [0.0; CHANNELS],
+            delay_lengths,
             process_temp_buffers: std::array::from_fn(|_| vec![0.0; 0]),
             process_temp_buffers1: std::array::from_fn(|_| vec![0.0; 0]),
             delays,
+            max_mod_depth: 0.0,
+            lfo_phases,
+            lfo_rate_factors,
             lowpasses,
         }
     }
-    /// Init internal buffers to the block size. Not real time safe.
-    pub fn init(&mut self, block_size: usize) {
+    /// Init internal buffers to the block size and make room for modulation. Not real time safe.
+    pub fn init(&mut self, block_size: usize, sample_rate: SampleRate) {
         self.process_temp_buffers = std::array::from_fn(|_| vec![0.0; block_size]);
         self.process_temp_buffers1 = std::array::from_fn(|_| vec![0.0; block_size]);
+        let max_mod_depth = (MAX_MOD_DEPTH * *sample_rate).ceil() as usize;
+        self.delays = self
+            .delay_lengths
+            .map(|length| ModulatedDelay::new(length + max_mod_depth));
+        self.max_mod_depth = max_mod_depth as Sample;
     }
     pub fn process_block(
         &mut self,
         input: &[Vec<Sample>; CHANNELS],
         output: &mut [Vec<Sample>; CHANNELS],
         damping: &[Sample],
+        decay_time: &[Sample],
+        mod_depth: &[Sample],
+        mod_rate: &[Sample],
+        freeze: &[Sample],
         sample_rate: SampleRate,
     ) {
-        // Get the output of the delay
-        for (i, delay) in self.delays.iter_mut().enumerate() {
-            delay.read_block(&mut self.process_temp_buffers[i]);
+        // Get the output of the delay. The delay time is modulated between
+        // the delay length and the delay length + mod_depth so that it is
+        // never shorter than one block.
+        for (i, delay) in self.delays.iter().enumerate() {
+            let delay_length = self.delay_lengths[i] as Sample;
+            let rate_factor = self.lfo_rate_factors[i] / *sample_rate;
+            let phase = &mut self.lfo_phases[i];
+            for (f, (sample, (&depth, &rate))) in self.process_temp_buffers[i]
+                .iter_mut()
+                .zip(mod_depth.iter().zip(mod_rate))
+                .enumerate()
+            {
+                let depth = (depth * *sample_rate).clamp(0.0, self.max_mod_depth);
+                let lfo = 0.5 + 0.5 * (*phase * std::f64::consts::TAU as Sample).sin();
+                *sample = delay.read(f, delay_length + depth * lfo);
+                *phase += rate * rate_factor;
+                *phase -= phase.floor();
+            }
         }
         // Set output to the output of the delay
         for (output_channel, process_channel) in output.iter_mut().zip(&self.process_temp_buffers) {
@@ -110,142 +183,548 @@ impl<const CHANNELS: usize> Tail<CHANNELS> {
                 channel[f] = chan[c];
             }
         }
-        // apply feedback to output of delay
+        // apply feedback to output of delay, ramping to the new gain over the block to avoid clicks
+        // When frozen the gain is crossfaded to unity and the damping is bypassed
+        let decay_time = if decay_time[0] > 0.0 {
+            decay_time[0]
+        } else {
+            DEFAULT_DECAY_TIME
+        };
         for (i, channel) in self.process_temp_buffers.iter_mut().enumerate() {
-            for sample in channel.iter_mut() {
-                *sample *= self.feedback_gain;
+            let target_gain = rt60_gain(self.delay_lengths[i], decay_time, sample_rate);
+            let gain_step = (target_gain - self.feedback_gains[i]) / block_size as Sample;
+            for (sample, &freeze) in channel.iter_mut().zip(freeze) {
+                self.feedback_gains[i] += gain_step;
+                let gain = self.feedback_gains[i];
+                *sample *= gain + (1.0 - gain) * freeze;
+            }
+            self.feedback_gains[i] = target_gain;
+            let damped = &mut self.process_temp_buffers1[i];
+            self.lowpasses[i].process(sample_rate, channel, damping, damped);
+            for ((damped, undamped), &freeze) in damped.iter_mut().zip(channel.iter()).zip(freeze) {
+                *damped += (undamped - *damped) * freeze;
             }
-            self.lowpasses[i].process(
-                sample_rate,
-                channel,
-                damping,
-                &mut self.process_temp_buffers1[i],
-            );
         }
-        // add together with input
+        // add together with input, muted while frozen
         for (process_channel, input_channel) in self.process_temp_buffers1.iter_mut().zip(input) {
-            for (process_s, input_s) in process_channel.iter_mut().zip(input_channel) {
-                *process_s += *input_s;
+            for ((process_s, input_s), freeze) in
+                process_channel.iter_mut().zip(input_channel).zip(freeze)
+            {
+                *process_s += *input_s * (1.0 - freeze);
             }
         }
         // Pipe back into the delay
         for (channel, delay) in self.delays.iter_mut().enumerate() {
-            delay.write_block_and_advance(&self.process_temp_buffers1[channel]);
+            delay.write_block(&self.process_temp_buffers1[channel]);
+        }
+    }
+}
+
+/// The gain for a feedback delay of `delay_length` samples to decay by 60dB in `decay_time` seconds
+pub(crate) fn rt60_gain(
+    delay_length: usize,
+    decay_time: Sample,
+    sample_rate: SampleRate,
+) -> Sample {
+    (0.001 as Sample).powf(delay_length as Sample / (decay_time * *sample_rate))
+}
+
+/// All the delay times and polarity flips of a [`LuffVerb`].
+///
+/// These are normally generated randomly from a seed, but can be stored and
+/// passed to [`LuffVerb::from_delay_times`] to reproduce a reverb exactly.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LuffVerbDelayTimes<const CHANNELS: usize = 2, const DIFFUSERS: usize = 4> {
+    /// Delay time in samples for every channel of every diffuser
+    pub diffusers: [[usize; CHANNELS]; DIFFUSERS],
+    /// Whether the polarity of a channel in a diffuser is flipped
+    pub diffuser_polarity: [[bool; CHANNELS]; DIFFUSERS],
+    /// Delay time in samples for every channel of the tail. The tail reads a
+    /// whole block before writing it, so delays shorter than the block size
+    /// plus 2 samples are lengthened to that in [`LuffVerb::init_sized`].
+    pub tail: [usize; CHANNELS],
+}
+
+impl<const CHANNELS: usize, const DIFFUSERS: usize> LuffVerbDelayTimes<CHANNELS, DIFFUSERS> {
+    /// Generate random delay times for a tail of up to `tail_delay` samples.
+    /// The same seed always gives the same delay times.
+    pub fn from_seed(tail_delay: usize, seed: u64) -> Self {
+        let mut rng = fastrand::Rng::with_seed(seed);
+        let diffuser_delay = tail_delay / (DIFFUSERS * 2);
+        let diffusers = std::array::from_fn(|_| {
+            // Spread the channels out over the max delay length
+            std::array::from_fn(|i| {
+                let time_min = diffuser_delay / CHANNELS * i + 1;
+                let time_max = diffuser_delay / CHANNELS * (i + 1);
+                rng.usize(time_min..time_max)
+            })
+        });
+        let diffuser_polarity = std::array::from_fn(|_| {
+            let mut flip_polarity = [false; CHANNELS];
+            flip_polarity[..CHANNELS / 2].fill(true);
+            rng.shuffle(&mut flip_polarity);
+            flip_polarity
+        });
+        let tail = std::array::from_fn(|_| rng.usize(tail_delay / 10..tail_delay));
+        Self {
+            diffusers,
+            diffuser_polarity,
+            tail,
         }
     }
 }
 
-const CHANNELS: usize = 2;
-const DIFFUSERS: usize = 4;
-pub struct LuffVerb {
+/// Feedback delay network reverb with `CHANNELS` internal channels and
+/// `DIFFUSERS` diffusion steps before the tail.
+///
+/// More channels and diffusers give a denser reverb at a higher CPU cost.
+/// `#[impl_gen]` needs a concrete type so `LuffVerb` itself is the gen for the
+/// default size (2x4) and other sizes are available as e.g. [`LuffVerb4x4`]
+/// and [`LuffVerb8x6`].
+///
+/// Unconnected inputs are 0.0. A `decay_time` of 0.0 or less is 2 seconds,
+/// `dry` at 0.0 outputs only the reverb, and the early reflections,
+/// modulation, freeze and ducking are off. `lowpass` and `damping` are
+/// cutoff frequencies in Hz and need to be connected, e.g. to 20000.0 to not
+/// filter at all.
+pub struct LuffVerb<const CHANNELS: usize = 2, const DIFFUSERS: usize = 4> {
+    delay_times: LuffVerbDelayTimes<CHANNELS, DIFFUSERS>,
     diffusers: [Diffuser<CHANNELS>; DIFFUSERS],
     tail: Tail<CHANNELS>,
-    input_lpf: OnePoleLpf,
+    input_lpfs: [Lowpass; CHANNELS],
     buffer0: [Vec<Sample>; CHANNELS],
     buffer1: [Vec<Sample>; CHANNELS],
-    early_reflections_amp: Sample,
+    tail_buffer: [Vec<Sample>; CHANNELS],
+    /// How frozen the tail is (0..=1) for every sample in the block
+    freeze_buffer: Vec<Sample>,
+    /// Current freeze amount, faded towards the freeze gate
+    freeze_amount: Sample,
+    ducker: Ducker,
+    /// Gain of the wet signal for every sample in the block
+    duck_buffer: Vec<Sample>,
 }
-#[impl_gen]
-// impl<const DIFFUSERS: usize, const CHANNELS: usize> LuffVerb<{DIFFUSERS}, {CHANNELS}> {
-impl LuffVerb {
-    pub fn new(tail_delay: usize, feedback: Sample, early_reflections: Sample) -> Self {
-        let diffusers = std::array::from_fn(|_i| Diffuser::new(tail_delay / (DIFFUSERS * 2)));
+
+impl<const CHANNELS: usize, const DIFFUSERS: usize> LuffVerb<CHANNELS, DIFFUSERS> {
+    /// Create a reverb of any size. Use `new` on the gens to get a node.
+    ///
+    /// The delay times are random, seeded from knyst's randomness seed like other gens.
+    pub fn new_sized(tail_delay: usize) -> Self {
+        Self::with_seed(tail_delay, knyst::gen::random::next_randomness_seed())
+    }
+    /// Create a reverb with random delay times from a specific seed
+    pub fn with_seed(tail_delay: usize, seed: u64) -> Self {
+        Self::from_delay_times(LuffVerbDelayTimes::from_seed(tail_delay, seed))
+    }
+    /// Create a reverb with exactly the given delay times
+    pub fn from_delay_times(delay_times: LuffVerbDelayTimes<CHANNELS, DIFFUSERS>) -> Self {
+        let diffusers = std::array::from_fn(|i| {
+            Diffuser::new(delay_times.diffusers[i], delay_times.diffuser_polarity[i])
+        });
         Self {
             diffusers,
-            tail: Tail::new(tail_delay, feedback),
+            tail: Tail::new(delay_times.tail),
+            delay_times,
             buffer0: std::array::from_fn(|_| Vec::new()),
             buffer1: std::array::from_fn(|_| Vec::new()),
-            input_lpf: OnePoleLpf::new(),
-            early_reflections_amp: early_reflections,
+            tail_buffer: std::array::from_fn(|_| Vec::new()),
+            freeze_buffer: Vec::new(),
+            freeze_amount: 0.0,
+            ducker: Ducker::new(),
+            duck_buffer: Vec::new(),
+            input_lpfs: std::array::from_fn(|_| Lowpass::new()),
         }
     }
-    pub fn init(&mut self, block_size: BlockSize) {
+    /// The delay times of this reverb, e.g. for saving a preset
+    pub fn delay_times(&self) -> &LuffVerbDelayTimes<CHANNELS, DIFFUSERS> {
+        &self.delay_times
+    }
+    /// Allocate buffers for the block size and the modulation depth at the sample rate. Not real time safe.
+    ///
+    /// Tail delays shorter than the block size plus 2 samples are lengthened
+    /// to that, see [`LuffVerbDelayTimes::tail`].
+    pub fn init_sized(&mut self, block_size: BlockSize, sample_rate: SampleRate) {
+        let min_tail_delay = *block_size + 2;
+        self.tail = Tail::new(
+            self.delay_times
+                .tail
+                .map(|delay_time| delay_time.max(min_tail_delay)),
+        );
         self.buffer0 = std::array::from_fn(|_| vec![0.0; *block_size]);
         self.buffer1 = std::array::from_fn(|_| vec![0.0; *block_size]);
-        self.tail.init(*block_size);
+        self.tail_buffer = std::array::from_fn(|_| vec![0.0; *block_size]);
+        self.freeze_buffer = vec![0.0; *block_size];
+        self.duck_buffer = vec![1.0; *block_size];
+        self.tail.init(*block_size, sample_rate);
     }
-    pub fn process(
+    /// Process any number of input channels into any number of output channels.
+    ///
+    /// Internal channel `c` is fed from input `c % inputs.len()` and mixed
+    /// into output `c % outputs.len()`. With as many outputs as internal
+    /// channels every decorrelated channel gets its own output instead of
+    /// being summed.
+    ///
+    /// `early_reflections` is the amplitude of the diffused signal in the
+    /// output and `dry` crossfades from the reverb (0.0) to the dry input
+    /// (1.0), both per sample.
+    ///
+    /// While `freeze` is above 0.0 the tail holds its current sound: the
+    /// feedback is unity, the damping is bypassed and new input is muted.
+    ///
+    /// The wet signal is ducked by the level of the input, or of `sidechain`
+    /// while `external_sidechain` is above 0.0. Everything above `threshold`
+    /// dB is reduced by `ratio`, with `attack` and `release` in seconds. A
+    /// ratio of 1.0 or less turns the ducking off.
+    pub fn process_multichannel(
         &mut self,
-        input: &[Sample],
-        output: &mut [Sample],
+        inputs: &[&[Sample]],
+        outputs: &mut [&mut [Sample]],
         lowpass: &[Sample],
         damping: &[Sample],
+        decay_time: &[Sample],
+        mod_depth: &[Sample],
+        mod_rate: &[Sample],
+        early_reflections: &[Sample],
+        dry: &[Sample],
+        freeze: &[Sample],
+        sidechain: &[Sample],
+        external_sidechain: &[Sample],
+        threshold: &[Sample],
+        ratio: &[Sample],
+        attack: &[Sample],
+        release: &[Sample],
         sample_rate: SampleRate,
-    ) -> GenState {
+    ) {
+        // Fade in and out of freeze to avoid clicks
+        let freeze_step = (FREEZE_FADE_TIME * *sample_rate).recip();
+        for (amount, &gate) in self.freeze_buffer.iter_mut().zip(freeze) {
+            let target = if gate > 0.0 { 1.0 } else { 0.0 };
+            self.freeze_amount += (target - self.freeze_amount).clamp(-freeze_step, freeze_step);
+            *amount = self.freeze_amount;
+        }
+        for (i, gain) in self.duck_buffer.iter_mut().enumerate() {
+            let detector = if external_sidechain[i] > 0.0 {
+                sidechain[i]
+            } else {
+                inputs
+                    .iter()
+                    .fold(0.0, |level: Sample, input| level.max(input[i].abs()))
+            };
+            *gain = self.ducker.process(
+                detector,
+                threshold[i],
+                ratio[i],
+                attack[i],
+                release[i],
+                sample_rate,
+            );
+        }
+        // Spread the inputs over the internal channels
+        for (c, (lpf, channel)) in self
+            .input_lpfs
+            .iter_mut()
+            .zip(self.buffer0.iter_mut())
+            .enumerate()
+        {
+            lpf.process(sample_rate, inputs[c % inputs.len()], lowpass, channel);
+            for (sample, freeze) in channel.iter_mut().zip(&self.freeze_buffer) {
+                *sample *= 1.0 - freeze;
+            }
+        }
         // Use buffer0 and buffer1 as input and output buffers every other time to cut down on the number of buffers needed.
-        let mut in_buf = &mut self.buffer0;
-        let mut out_buf = &mut self.buffer1;
-
-        self.input_lpf.process(sample_rate, input, lowpass, output);
-        // Fill all channels of buffer0 with the in,
-        for channel in in_buf.iter_mut() {
-            channel.copy_from_slice(output);
-        }
-        for diffuser in &mut self.diffusers.iter_mut() {
-            diffuser.process_block(in_buf, out_buf);
-            std::mem::swap(&mut in_buf, &mut out_buf);
-        }
-        std::mem::swap(&mut in_buf, &mut out_buf);
-        output.fill(0.0);
-        for (f, out_sample) in output.iter_mut().enumerate() {
-            for channel in out_buf.iter_mut() {
-                *out_sample += channel[f];
+        for (i, diffuser) in self.diffusers.iter_mut().enumerate() {
+            if i % 2 == 0 {
+                diffuser.process_block(&self.buffer0, &mut self.buffer1);
+            } else {
+                diffuser.process_block(&self.buffer1, &mut self.buffer0);
             }
-            *out_sample *= self.early_reflections_amp;
-        }
-        std::mem::swap(&mut in_buf, &mut out_buf);
-        self.tail
-            .process_block(in_buf, out_buf, damping, sample_rate);
-        // Sum output channels
-        let compensation_amp = 1.0 / (CHANNELS as Sample * DIFFUSERS as Sample);
-        for (f, out_sample) in output.iter_mut().enumerate() {
-            for channel in out_buf.iter_mut() {
-                *out_sample += channel[f];
+        }
+        let diffused = if DIFFUSERS.is_multiple_of(2) {
+            &self.buffer0
+        } else {
+            &self.buffer1
+        };
+        self.tail.process_block(
+            diffused,
+            &mut self.tail_buffer,
+            damping,
+            decay_time,
+            mod_depth,
+            mod_rate,
+            &self.freeze_buffer,
+            sample_rate,
+        );
+
+        for output in outputs.iter_mut() {
+            output.fill(0.0);
+        }
+        let num_outputs = outputs.len();
+        for (c, (early_channel, tail_channel)) in diffused.iter().zip(&self.tail_buffer).enumerate()
+        {
+            for (((out_sample, early), tail), early_amp) in outputs[c % num_outputs]
+                .iter_mut()
+                .zip(early_channel)
+                .zip(tail_channel)
+                .zip(early_reflections)
+            {
+                *out_sample += early * early_amp + tail;
+            }
+        }
+        // Compensate for the number of decorrelated internal channels summed into each output
+        let channels_per_output = CHANNELS.div_ceil(num_outputs);
+        let compensation_amp = (channels_per_output as Sample).sqrt().recip() * WET_GAIN;
+        for (o, output) in outputs.iter_mut().enumerate() {
+            let input = inputs[o % inputs.len()];
+            for (((out_sample, input), dry), duck) in
+                output.iter_mut().zip(input).zip(dry).zip(&self.duck_buffer)
+            {
+                *out_sample = *out_sample * compensation_amp * duck * (1.0 - dry) + input * dry;
             }
-            *out_sample *= compensation_amp;
         }
-        GenState::Continue
     }
 }
 
-mod matrix {
-    use knyst::Sample;
-    use std::marker::PhantomData;
+#[impl_gen]
+impl LuffVerb {
+    pub fn new(tail_delay: usize) -> Self {
+        Self::new_sized(tail_delay)
+    }
+    pub fn init(&mut self, block_size: BlockSize, sample_rate: SampleRate) {
+        self.init_sized(block_size, sample_rate);
+    }
+    pub fn process(
+        &mut self,
+        input: &[Sample],
+        output: &mut [Sample],
+        lowpass: &[Sample],
+        damping: &[Sample],
+        decay_time: &[Sample],
+        mod_depth: &[Sample],
+        mod_rate: &[Sample],
+        early_reflections: &[Sample],
+        dry: &[Sample],
+        freeze: &[Sample],
+        sidechain: &[Sample],
+        external_sidechain: &[Sample],
+        threshold: &[Sample],
+        ratio: &[Sample],
+        attack: &[Sample],
+        release: &[Sample],
+        sample_rate: SampleRate,
+    ) -> GenState {
+        self.process_multichannel(
+            &[input],
+            &mut [output],
+            lowpass,
+            damping,
+            decay_time,
+            mod_depth,
+            mod_rate,
+            early_reflections,
+            dry,
+            freeze,
+            sidechain,
+            external_sidechain,
+            threshold,
+            ratio,
+            attack,
+            release,
+            sample_rate,
+        );
+        GenState::Continue
+    }
+}
 
-    pub fn hadamard_recursive(frame: &mut [Sample]) {
-        if frame.len() <= 1 {
-            return;
+/// Mono gen wrapping a [`LuffVerb`] of a specific size.
+///
+/// These are wrapper types rather than type aliases like
+/// `type LuffVerb4x4 = LuffVerb<4, 4>` because `#[impl_gen]` names the
+/// generated handle type and the node function after the type in the impl,
+/// so an `#[impl_gen] impl LuffVerb<4, 4>` would define a second
+/// `luff_verb` function and handle type next to the 2x4 ones. A second
+/// inherent `new` on `LuffVerb` would also make `LuffVerb::new` ambiguous,
+/// since the default parameters are not used to infer the type in
+/// expressions.
+macro_rules! luff_verb_gen {
+    ($(#[$meta:meta])* $name:ident, $channels:literal, $diffusers:literal) => {
+        $(#[$meta])*
+        ///
+        /// See [`LuffVerb`] for the inputs.
+        pub struct $name {
+            verb: LuffVerb<$channels, $diffusers>,
         }
-        let d = frame.len() / 2;
-        hadamard_recursive(&mut frame[..d]);
-        hadamard_recursive(&mut frame[d..]);
-        for i in 0..d {
-            let a = frame[i];
-            let b = frame[i + d];
-            frame[i] = a + b;
-            frame[i + d] = a - b;
+        impl $name {
+            /// Wrap a reverb of the right size, e.g. one made from stored delay times
+            pub fn from_verb(verb: LuffVerb<$channels, $diffusers>) -> Self {
+                Self { verb }
+            }
+            pub fn verb(&self) -> &LuffVerb<$channels, $diffusers> {
+                &self.verb
+            }
         }
-    }
+        #[impl_gen]
+        impl $name {
+            pub fn new(tail_delay: usize) -> Self {
+                Self {
+                    verb: LuffVerb::new_sized(tail_delay),
+                }
+            }
+            pub fn init(&mut self, block_size: BlockSize, sample_rate: SampleRate) {
+                self.verb.init_sized(block_size, sample_rate);
+            }
+            pub fn process(
+                &mut self,
+                input: &[Sample],
+                output: &mut [Sample],
+                lowpass: &[Sample],
+                damping: &[Sample],
+                decay_time: &[Sample],
+                mod_depth: &[Sample],
+                mod_rate: &[Sample],
+                early_reflections: &[Sample],
+                dry: &[Sample],
+                freeze: &[Sample],
+                sidechain: &[Sample],
+                external_sidechain: &[Sample],
+                threshold: &[Sample],
+                ratio: &[Sample],
+                attack: &[Sample],
+                release: &[Sample],
+                sample_rate: SampleRate,
+            ) -> GenState {
+                self.verb.process_multichannel(
+                    &[input],
+                    &mut [output],
+                    lowpass,
+                    damping,
+                    decay_time,
+                    mod_depth,
+                    mod_rate,
+                    early_reflections,
+                    dry,
+                    freeze,
+                    sidechain,
+                    external_sidechain,
+                    threshold,
+                    ratio,
+                    attack,
+                    release,
+                    sample_rate,
+                );
+                GenState::Continue
+            }
+        }
+    };
+}
 
-    pub struct Householder<const CHANNELS: usize> {
-        _channels: PhantomData<[(); CHANNELS]>,
-    }
-    impl<const CHANNELS: usize> Householder<CHANNELS> {
-        const MULTIPLIER: f64 = -2. / CHANNELS as f64;
-        #[inline]
-        pub fn in_place(frame: &mut [Sample; CHANNELS]) {
-            let mut sum: f64 = 0.0;
-            for f in frame.iter_mut() {
-                sum += *f as f64;
+/// Stereo in, stereo out gen wrapping a [`LuffVerb`] of a specific size.
+///
+/// The decorrelated internal channels are kept apart on the outputs instead
+/// of being summed to mono, which gives a wide stereo tail.
+macro_rules! luff_verb_stereo_gen {
+    ($(#[$meta:meta])* $name:ident, $channels:literal, $diffusers:literal) => {
+        $(#[$meta])*
+        ///
+        /// See [`LuffVerb`] for the inputs.
+        pub struct $name {
+            verb: LuffVerb<$channels, $diffusers>,
+        }
+        impl $name {
+            /// Wrap a reverb of the right size, e.g. one made from stored delay times
+            pub fn from_verb(verb: LuffVerb<$channels, $diffusers>) -> Self {
+                Self { verb }
             }
-            sum *= Householder::<CHANNELS>::MULTIPLIER;
-            for f in frame.iter_mut() {
-                *f += sum as Sample;
+            pub fn verb(&self) -> &LuffVerb<$channels, $diffusers> {
+                &self.verb
             }
         }
-    }
+        #[impl_gen]
+        impl $name {
+            pub fn new(tail_delay: usize) -> Self {
+                Self {
+                    verb: LuffVerb::new_sized(tail_delay),
+                }
+            }
+            pub fn init(&mut self, block_size: BlockSize, sample_rate: SampleRate) {
+                self.verb.init_sized(block_size, sample_rate);
+            }
+            pub fn process(
+                &mut self,
+                left: &[Sample],
+                right: &[Sample],
+                lowpass: &[Sample],
+                damping: &[Sample],
+                decay_time: &[Sample],
+                mod_depth: &[Sample],
+                mod_rate: &[Sample],
+                early_reflections: &[Sample],
+                dry: &[Sample],
+                freeze: &[Sample],
+                sidechain: &[Sample],
+                external_sidechain: &[Sample],
+                threshold: &[Sample],
+                ratio: &[Sample],
+                attack: &[Sample],
+                release: &[Sample],
+                left_out: &mut [Sample],
+                right_out: &mut [Sample],
+                sample_rate: SampleRate,
+            ) -> GenState {
+                self.verb.process_multichannel(
+                    &[left, right],
+                    &mut [left_out, right_out],
+                    lowpass,
+                    damping,
+                    decay_time,
+                    mod_depth,
+                    mod_rate,
+                    early_reflections,
+                    dry,
+                    freeze,
+                    sidechain,
+                    external_sidechain,
+                    threshold,
+                    ratio,
+                    attack,
+                    release,
+                    sample_rate,
+                );
+                GenState::Continue
+            }
+        }
+    };
 }
 
+luff_verb_gen!(
+    /// LuffVerb with 4 channels and 4 diffusers
+    LuffVerb4x4,
+    4,
+    4
+);
+luff_verb_gen!(
+    /// LuffVerb with 8 channels and 6 diffusers
+    LuffVerb8x6,
+    8,
+    6
+);
+luff_verb_stereo_gen!(
+    /// Stereo LuffVerb with 2 channels and 4 diffusers
+    LuffVerbStereo,
+    2,
+    4
+);
+luff_verb_stereo_gen!(
+    /// Stereo LuffVerb with 4 channels and 4 diffusers
+    LuffVerbStereo4x4,
+    4,
+    4
+);
+luff_verb_stereo_gen!(
+    /// Stereo LuffVerb with 8 channels and 6 diffusers
+    LuffVerbStereo8x6,
+    8,
+    6
+);
+
 // 1. Separate Tails, one per channel, each processing a block, into a multichannel mix matrix which scrambles the channels
 // 2. Process each
 
@@ -253,6 +732,359 @@ mod matrix {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    const BLOCK_SIZE: usize = 64;
+    const SAMPLE_RATE: SampleRate = SampleRate(48000.);
+
+    fn block(value: Sample) -> Vec<Sample> {
+        vec![value; BLOCK_SIZE]
+    }
+
+    /// All the per sample inputs of a reverb except the audio. The defaults
+    /// are a fully wet reverb without damping, modulation or ducking so tests
+    /// only set the inputs they exercise.
+    struct Controls {
+        lowpass: Vec<Sample>,
+        damping: Vec<Sample>,
+        decay_time: Vec<Sample>,
+        mod_depth: Vec<Sample>,
+        mod_rate: Vec<Sample>,
+        early_reflections: Vec<Sample>,
+        dry: Vec<Sample>,
+        freeze: Vec<Sample>,
+        sidechain: Vec<Sample>,
+        external_sidechain: Vec<Sample>,
+        threshold: Vec<Sample>,
+        ratio: Vec<Sample>,
+        attack: Vec<Sample>,
+        release: Vec<Sample>,
+    }
+
+    impl Default for Controls {
+        fn default() -> Self {
+            Self::sized(BLOCK_SIZE)
+        }
+    }
+
+    impl Controls {
+        /// The defaults for blocks of `block_size` samples
+        fn sized(block_size: usize) -> Self {
+            let block = |value| vec![value; block_size];
+            Self {
+                lowpass: block(20000.),
+                damping: block(20000.),
+                decay_time: block(1.0),
+                mod_depth: block(0.0),
+                mod_rate: block(0.0),
+                early_reflections: block(0.5),
+                dry: block(0.0),
+                freeze: block(0.0),
+                sidechain: block(0.0),
+                external_sidechain: block(0.0),
+                threshold: block(-40.0),
+                // A ratio of 1.0 or less turns the ducking off
+                ratio: block(0.0),
+                attack: block(0.005),
+                release: block(0.1),
+            }
+        }
+        /// Process one block of `verb`
+        fn process<const CHANNELS: usize, const DIFFUSERS: usize>(
+            &self,
+            verb: &mut LuffVerb<CHANNELS, DIFFUSERS>,
+            inputs: &[&[Sample]],
+            outputs: &mut [&mut [Sample]],
+        ) {
+            verb.process_multichannel(
+                inputs,
+                outputs,
+                &self.lowpass,
+                &self.damping,
+                &self.decay_time,
+                &self.mod_depth,
+                &self.mod_rate,
+                &self.early_reflections,
+                &self.dry,
+                &self.freeze,
+                &self.sidechain,
+                &self.external_sidechain,
+                &self.threshold,
+                &self.ratio,
+                &self.attack,
+                &self.release,
+                SAMPLE_RATE,
+            );
+        }
+    }
+
+    fn init<const CHANNELS: usize, const DIFFUSERS: usize>(
+        mut verb: LuffVerb<CHANNELS, DIFFUSERS>,
+    ) -> LuffVerb<CHANNELS, DIFFUSERS> {
+        verb.init_sized(BlockSize(BLOCK_SIZE), SAMPLE_RATE);
+        verb
+    }
+
+    #[test]
+    fn stereo_channels_are_kept_apart() {
+        let mut verb = init(LuffVerbStereo::new(4800).verb);
+        let controls = Controls {
+            mod_depth: block(0.001),
+            mod_rate: block(0.5),
+            ..Default::default()
+        };
+        let mut left = block(0.0);
+        left[0] = 1.0;
+        let mut left_out = block(0.0);
+        let mut right_out = block(0.0);
+        let mut difference = 0.0;
+        for _ in 0..200 {
+            controls.process(
+                &mut verb,
+                &[&left, &block(0.0)],
+                &mut [&mut left_out, &mut right_out],
+            );
+            left.fill(0.0);
+            for (l, r) in left_out.iter().zip(&right_out) {
+                difference += (l - r).abs();
+            }
+        }
+        assert!(difference > 0.0);
+    }
+
+    #[test]
+    fn large_sizes_stay_bounded() {
+        let mut verb = init(LuffVerb8x6::new(9600).verb);
+        let controls = Controls {
+            decay_time: block(3.0),
+            mod_depth: block(0.01),
+            mod_rate: block(2.0),
+            ..Default::default()
+        };
+        let mut input = block(0.0);
+        input[0] = 1.0;
+        let mut output = block(0.0);
+        let mut peak: Sample = 0.0;
+        for _ in 0..500 {
+            controls.process(&mut verb, &[&input], &mut [&mut output]);
+            input.fill(0.0);
+            peak = output.iter().fold(peak, |peak, s| peak.max(s.abs()));
+        }
+        assert!(peak > 0.0);
+        assert!(peak < 1.0, "peak: {peak}");
+    }
+
+    #[test]
+    fn rt60_gain_decays_60db() {
+        let sample_rate = SampleRate(48000.);
+        let delay_length = 1733;
+        let decay_time = 2.5;
+        let gain = rt60_gain(delay_length, decay_time, sample_rate);
+        let round_trips = decay_time * *sample_rate / delay_length as Sample;
+        assert!((gain.powf(round_trips) - 0.001).abs() < 1e-6);
+    }
+
+    fn render(verb: LuffVerb, blocks: usize, mod_depth: Sample) -> Vec<Sample> {
+        let mut verb = init(verb);
+        let controls = Controls {
+            lowpass: block(8000.),
+            damping: block(6000.),
+            decay_time: block(1.5),
+            mod_depth: block(mod_depth),
+            mod_rate: block(0.7),
+            ..Default::default()
+        };
+        let mut input = block(0.0);
+        input[0] = 1.0;
+        let mut output = block(0.0);
+        let mut rendered = Vec::new();
+        for _ in 0..blocks {
+            controls.process(&mut verb, &[&input], &mut [&mut output]);
+            input.fill(0.0);
+            rendered.extend_from_slice(&output);
+        }
+        rendered
+    }
+
+    #[test]
+    fn seeded_renders_are_identical() {
+        let a = render(LuffVerb::with_seed(4800, 17), 100, 0.002);
+        let b = render(LuffVerb::with_seed(4800, 17), 100, 0.002);
+        let c = render(LuffVerb::with_seed(4800, 18), 100, 0.002);
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn delay_times_reproduce_the_reverb() {
+        let original = LuffVerb::with_seed(4800, 3);
+        let copy = LuffVerb::from_delay_times(original.delay_times().clone());
+        assert_eq!(render(original, 100, 0.002), render(copy, 100, 0.002));
+    }
+
+    #[test]
+    fn short_tail_delays_are_lengthened_to_the_block_size() {
+        let block_size = 512;
+        let render = |mut verb: LuffVerb| {
+            verb.init_sized(BlockSize(block_size), SAMPLE_RATE);
+            let controls = Controls::sized(block_size);
+            let mut input = vec![0.0; block_size];
+            input[0] = 1.0;
+            let mut output = vec![0.0; block_size];
+            let mut rendered = Vec::new();
+            for _ in 0..50 {
+                controls.process(&mut verb, &[&input], &mut [&mut output]);
+                input.fill(0.0);
+                rendered.extend_from_slice(&output);
+            }
+            (verb, rendered)
+        };
+        // Tail delays from 60 to 600 samples
+        let short = LuffVerb::with_seed(600, 4);
+        let delay_times = short.delay_times().clone();
+        assert!(delay_times.tail.iter().any(|&delay| delay < block_size + 2));
+        let mut lengthened = delay_times.clone();
+        lengthened.tail = lengthened.tail.map(|delay| delay.max(block_size + 2));
+        let (short, rendered) = render(short);
+        assert_eq!(rendered, render(LuffVerb::from_delay_times(lengthened)).1);
+        assert!(rendered.iter().any(|&s| s != 0.0));
+        assert!(rendered.iter().all(|s| s.abs() < 1.0));
+        // The stored delay times are kept as they were, e.g. for a preset
+        assert_eq!(short.delay_times(), &delay_times);
+    }
+
+    #[test]
+    fn modulation_only_changes_the_tail() {
+        let still = render(LuffVerb::with_seed(4800, 5), 200, 0.0);
+        let modulated = render(LuffVerb::with_seed(4800, 5), 200, 0.003);
+        // The shortest tail delay is 480 samples so the early part is untouched
+        assert_eq!(still[..480], modulated[..480]);
+        assert_ne!(still, modulated);
+        assert!(modulated.iter().all(|s| s.abs() < 1.0));
+    }
+
+    #[test]
+    fn dry_crossfades_per_sample() {
+        let mut verb: LuffVerb = init(LuffVerb::with_seed(4800, 9));
+        let controls = Controls {
+            decay_time: block(2.0),
+            early_reflections: block(1.0),
+            // Fully dry for the first half of the block, fully wet for the second half
+            dry: (0..BLOCK_SIZE)
+                .map(|i| if i < BLOCK_SIZE / 2 { 1.0 } else { 0.0 })
+                .collect(),
+            ..Default::default()
+        };
+        let input: Vec<Sample> = (0..BLOCK_SIZE).map(|i| (i as Sample * 0.1).sin()).collect();
+        let mut output = block(0.0);
+        let mut dry_matches = true;
+        for _ in 0..50 {
+            controls.process(&mut verb, &[&input], &mut [&mut output]);
+            dry_matches &= output[..BLOCK_SIZE / 2] == input[..BLOCK_SIZE / 2];
+        }
+        assert!(dry_matches);
+        assert_ne!(output[BLOCK_SIZE / 2..], input[BLOCK_SIZE / 2..]);
+    }
+
+    #[test]
+    fn unconnected_inputs_give_a_wet_reverb() {
+        let render = |decay_time| {
+            let mut verb: LuffVerb = init(LuffVerb::with_seed(4800, 6));
+            // Unconnected inputs are 0.0, only the filters are set
+            let controls = Controls {
+                lowpass: block(20000.),
+                damping: block(20000.),
+                decay_time: block(decay_time),
+                mod_depth: block(0.0),
+                mod_rate: block(0.0),
+                early_reflections: block(0.0),
+                dry: block(0.0),
+                freeze: block(0.0),
+                sidechain: block(0.0),
+                external_sidechain: block(0.0),
+                threshold: block(0.0),
+                ratio: block(0.0),
+                attack: block(0.0),
+                release: block(0.0),
+            };
+            let mut input = block(0.0);
+            input[0] = 1.0;
+            let mut output = block(0.0);
+            let mut rendered = Vec::new();
+            for _ in 0..200 {
+                controls.process(&mut verb, &[&input], &mut [&mut output]);
+                input.fill(0.0);
+                rendered.extend_from_slice(&output);
+            }
+            rendered
+        };
+        let unconnected = render(0.0);
+        assert_eq!(unconnected, render(DEFAULT_DECAY_TIME));
+        // Only the reverb, without the dry impulse
+        assert_eq!(unconnected[0], 0.0);
+        assert!(unconnected[BLOCK_SIZE * 100..].iter().any(|&s| s != 0.0));
+    }
+
+    #[test]
+    fn freeze_holds_the_tail() {
+        let mut verb: LuffVerb = init(LuffVerb::with_seed(4800, 2));
+        let mut controls = Controls {
+            damping: block(2000.),
+            decay_time: block(0.5),
+            early_reflections: block(0.0),
+            ..Default::default()
+        };
+        let mut output = block(0.0);
+        let mut render_blocks = |verb: &mut LuffVerb, input: Sample, freeze: Sample, blocks| {
+            let input = block(input);
+            controls.freeze.fill(freeze);
+            let mut energy = 0.0;
+            for _ in 0..blocks {
+                controls.process(verb, &[&input], &mut [&mut output]);
+                energy += output.iter().map(|s| s * s).sum::<Sample>();
+            }
+            energy
+        };
+        // Fill the reverb with noise-like content, then freeze it for several decay times
+        let mut rng = fastrand::Rng::with_seed(0);
+        for _ in 0..100 {
+            render_blocks(&mut verb, rng.f32() - 0.5, 0.0, 1);
+        }
+        let before = render_blocks(&mut verb, 0.0, 1.0, 100);
+        // Loud input is muted while frozen
+        let frozen = render_blocks(&mut verb, 1.0, 1.0, 100);
+        assert!(frozen > before * 0.5 && frozen < before * 2.0);
+        // After release it decays again
+        render_blocks(&mut verb, 0.0, 0.0, 1000);
+        let released = render_blocks(&mut verb, 0.0, 0.0, 100);
+        assert!(released < frozen * 0.001);
+    }
+
+    #[test]
+    fn ducking_follows_the_sidechain() {
+        let input: Vec<Sample> = (0..BLOCK_SIZE).map(|i| (i as Sample * 0.1).sin()).collect();
+        let mut output = block(0.0);
+        let mut render = |ratio: Sample, external_sidechain: Sample| {
+            let mut verb: LuffVerb = init(LuffVerb::with_seed(4800, 3));
+            let controls = Controls {
+                damping: block(6000.),
+                ratio: block(ratio),
+                external_sidechain: block(external_sidechain),
+                ..Default::default()
+            };
+            let mut energy = 0.0;
+            for _ in 0..100 {
+                controls.process(&mut verb, &[&input], &mut [&mut output]);
+                energy += output.iter().map(|s| s * s).sum::<Sample>();
+            }
+            energy
+        };
+        let unducked = render(1.0, 0.0);
+        // The input itself ducks the tail
+        assert!(render(10.0, 0.0) < unducked * 0.1);
+        // A silent external sidechain doesn't
+        assert_eq!(render(10.0, 1.0), unducked);
+    }
 
     // #[test]
     // fn tail_delay() {
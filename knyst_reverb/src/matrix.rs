@@ -0,0 +1,252 @@
+//! Mixing matrices for diffusers and feedback delay networks
+//!
+//! [`hadamard_recursive`] and [`Householder`] are fast fixed size matrices
+//! used by [`crate::LuffVerb`]. [`FeedbackMatrix`] chooses the feedback matrix
+//! of an [`crate::Fdn`] with any number of lines.
+
+use knyst::Sample;
+use std::marker::PhantomData;
+
+/// Unnormalised Hadamard matrix applied in place. The length of `frame` has to be a power of two.
+pub fn hadamard_recursive(frame: &mut [Sample]) {
+    if frame.len() <= 1 {
+        return;
+    }
+    let d = frame.len() / 2;
+    hadamard_recursive(&mut frame[..d]);
+    hadamard_recursive(&mut frame[d..]);
+    for i in 0..d {
+        let a = frame[i];
+        let b = frame[i + d];
+        frame[i] = a + b;
+        frame[i + d] = a - b;
+    }
+}
+
+pub struct Householder<const CHANNELS: usize> {
+    _channels: PhantomData<[(); CHANNELS]>,
+}
+impl<const CHANNELS: usize> Householder<CHANNELS> {
+    const MULTIPLIER: f64 = -2. / CHANNELS as f64;
+    #[inline]
+    pub fn in_place(frame: &mut [Sample; CHANNELS]) {
+        let mut sum: f64 = 0.0;
+        for f in frame.iter_mut() {
+            sum += *f as f64;
+        }
+        sum *= Householder::<CHANNELS>::MULTIPLIER;
+        for f in frame.iter_mut() {
+            *f += sum as Sample;
+        }
+    }
+}
+
+/// The kind of feedback matrix of a feedback delay network.
+///
+/// All of them are orthogonal (lossless) so that the decay only depends on the
+/// absorption of the delay lines, but they give different characters: a
+/// Householder matrix mixes every line a little, a Hadamard matrix mixes all
+/// lines equally and a random orthogonal matrix is irregular.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FeedbackMatrix {
+    /// `I - 2/N`, cheap for any number of lines
+    Householder,
+    /// Normalised Hadamard matrix. The number of lines has to be a power of two.
+    Hadamard,
+    /// A random orthogonal matrix, the same for the same seed
+    RandomOrthogonal { seed: u64 },
+    /// A circulant matrix, every row is the first row rotated one step.
+    ///
+    /// It is only lossless if the DFT of the first row has a magnitude of 1 in
+    /// every bin, see [`FeedbackMatrix::lossless_circulant`].
+    Circulant { first_row: Vec<Sample> },
+}
+
+impl FeedbackMatrix {
+    /// A lossless circulant matrix for `lines` lines with random phases
+    pub fn lossless_circulant(lines: usize, seed: u64) -> Self {
+        let mut rng = fastrand::Rng::with_seed(seed);
+        // Unit magnitude eigenvalues with conjugate symmetric phases give a real first row
+        let phases: Vec<f64> = {
+            let mut phases = vec![0.0; lines];
+            for k in 0..=lines / 2 {
+                let mirrored = (lines - k) % lines;
+                phases[k] = if k == mirrored {
+                    // Must be real, i.e. 1 or -1
+                    if rng.bool() {
+                        0.0
+                    } else {
+                        std::f64::consts::PI
+                    }
+                } else {
+                    rng.f64() * std::f64::consts::TAU
+                };
+                phases[mirrored] = -phases[k];
+            }
+            phases
+        };
+        let first_row = (0..lines)
+            .map(|n| {
+                phases
+                    .iter()
+                    .enumerate()
+                    .map(|(k, phase)| {
+                        (phase + std::f64::consts::TAU * (k * n) as f64 / lines as f64).cos()
+                    })
+                    .sum::<f64>() as Sample
+                    / lines as Sample
+            })
+            .collect();
+        Self::Circulant { first_row }
+    }
+    /// Build the matrix for a number of lines.
+    ///
+    /// # Panics
+    /// If the matrix is `Hadamard` and `lines` isn't a power of two, or the
+    /// matrix is `Circulant` and the first row isn't `lines` long.
+    pub fn build(&self, lines: usize) -> MixingMatrix {
+        let kind = match self {
+            FeedbackMatrix::Householder => MatrixKind::Householder,
+            FeedbackMatrix::Hadamard => {
+                assert!(
+                    lines.is_power_of_two(),
+                    "A Hadamard matrix needs a power of two number of lines, not {lines}"
+                );
+                MatrixKind::Hadamard
+            }
+            FeedbackMatrix::RandomOrthogonal { seed } => {
+                MatrixKind::Dense(random_orthogonal(lines, *seed))
+            }
+            FeedbackMatrix::Circulant { first_row } => {
+                assert_eq!(
+                    first_row.len(),
+                    lines,
+                    "The first row of a circulant matrix must have one value per line"
+                );
+                let mut matrix = Vec::with_capacity(lines * lines);
+                for row in 0..lines {
+                    for column in 0..lines {
+                        matrix.push(first_row[(column + lines - row) % lines]);
+                    }
+                }
+                MatrixKind::Dense(matrix)
+            }
+        };
+        MixingMatrix { kind, lines }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum MatrixKind {
+    Householder,
+    Hadamard,
+    /// Row major
+    Dense(Vec<Sample>),
+}
+
+/// A [`FeedbackMatrix`] built for a specific number of lines
+#[derive(Clone, Debug)]
+pub struct MixingMatrix {
+    kind: MatrixKind,
+    lines: usize,
+}
+
+impl MixingMatrix {
+    pub fn lines(&self) -> usize {
+        self.lines
+    }
+    /// Multiply `input` with the matrix into `output`. Both have one value per line.
+    #[inline]
+    pub fn process(&self, input: &[Sample], output: &mut [Sample]) {
+        match &self.kind {
+            MatrixKind::Householder => {
+                let sum: f64 = input.iter().map(|&s| s as f64).sum();
+                let sum = (sum * -2. / self.lines as f64) as Sample;
+                for (out, &sample) in output.iter_mut().zip(input) {
+                    *out = sample + sum;
+                }
+            }
+            MatrixKind::Hadamard => {
+                let scale = (self.lines as Sample).sqrt().recip();
+                for (out, &sample) in output.iter_mut().zip(input) {
+                    *out = sample * scale;
+                }
+                hadamard_recursive(output);
+            }
+            MatrixKind::Dense(matrix) => {
+                for (out, row) in output.iter_mut().zip(matrix.chunks_exact(self.lines)) {
+                    *out = row.iter().zip(input).map(|(m, s)| m * s).sum();
+                }
+            }
+        }
+    }
+}
+
+/// Row major random orthogonal matrix from Gram-Schmidt orthogonalisation of Gaussian noise
+fn random_orthogonal(lines: usize, seed: u64) -> Vec<Sample> {
+    let mut rng = fastrand::Rng::with_seed(seed);
+    let mut gaussian = || {
+        // Box-Muller
+        let u = 1.0 - rng.f64();
+        let v = rng.f64();
+        (-2.0 * u.ln()).sqrt() * (std::f64::consts::TAU * v).cos()
+    };
+    let mut rows: Vec<Vec<f64>> = Vec::with_capacity(lines);
+    while rows.len() < lines {
+        let mut row: Vec<f64> = (0..lines).map(|_| gaussian()).collect();
+        for previous in &rows {
+            let dot: f64 = row.iter().zip(previous).map(|(a, b)| a * b).sum();
+            for (r, p) in row.iter_mut().zip(previous) {
+                *r -= dot * p;
+            }
+        }
+        let norm = row.iter().map(|r| r * r).sum::<f64>().sqrt();
+        // Try again in the very unlikely case of a linearly dependent row
+        if norm > 1e-6 {
+            rows.push(row.into_iter().map(|r| r / norm).collect());
+        }
+    }
+    rows.into_iter().flatten().map(|r| r as Sample).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check that the columns of the matrix are orthonormal
+    fn assert_orthogonal(matrix: &MixingMatrix) {
+        let lines = matrix.lines();
+        let mut columns = vec![vec![0.0; lines]; lines];
+        for (i, column) in columns.iter_mut().enumerate() {
+            let mut unit = vec![0.0; lines];
+            unit[i] = 1.0;
+            matrix.process(&unit, column);
+        }
+        for (i, a) in columns.iter().enumerate() {
+            for (j, b) in columns.iter().enumerate() {
+                let dot: Sample = a.iter().zip(b).map(|(a, b)| a * b).sum();
+                let expected = if i == j { 1.0 } else { 0.0 };
+                assert!((dot - expected).abs() < 1e-5, "{matrix:?} {i} {j}: {dot}");
+            }
+        }
+    }
+
+    #[test]
+    fn feedback_matrices_are_orthogonal() {
+        for lines in [4, 8, 16] {
+            assert_orthogonal(&FeedbackMatrix::Householder.build(lines));
+            assert_orthogonal(&FeedbackMatrix::Hadamard.build(lines));
+        }
+        for lines in [3, 5, 8, 12] {
+            assert_orthogonal(&FeedbackMatrix::Householder.build(lines));
+            assert_orthogonal(&FeedbackMatrix::RandomOrthogonal { seed: 7 }.build(lines));
+            assert_orthogonal(&FeedbackMatrix::lossless_circulant(lines, 7).build(lines));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn hadamard_needs_a_power_of_two() {
+        FeedbackMatrix::Hadamard.build(6);
+    }
+}
@@ -0,0 +1,359 @@
+//! Dattorro plate reverb
+//!
+//! From Jon Dattorro, "Effect Design Part 1: Reverberator and Other Filters", JAES 1997.
+
+use knyst::{
+    prelude::{impl_gen, GenState},
+    Sample, SampleRate,
+};
+
+/// The sample rate the delay lengths in the paper are given for
+const DATTORRO_SAMPLE_RATE: Sample = 29761.;
+/// Input diffuser lengths
+const INPUT_DIFFUSERS: [usize; 4] = [142, 107, 379, 277];
+/// Max excursion in samples at the original sample rate
+const MAX_EXCURSION: Sample = 16.;
+
+/// Delay line where the current input can be read at any delay up to the length
+///
+/// Reading after writing a sample gives the input delayed by exactly `delay`
+/// samples, reading before writing gives the input delayed by `delay + 1`.
+#[derive(Clone, Debug)]
+struct DelayLine {
+    buffer: Vec<Sample>,
+    /// The next position to write to
+    position: usize,
+}
+
+impl DelayLine {
+    fn new(max_delay: usize) -> Self {
+        Self {
+            buffer: vec![0.0; max_delay + 2],
+            position: 0,
+        }
+    }
+    /// The sample written `delay` samples before the last one, 0 is the last written sample
+    #[inline]
+    fn read(&self, delay: usize) -> Sample {
+        let len = self.buffer.len();
+        self.buffer[(self.position + len - 1 - delay) % len]
+    }
+    /// Read with linear interpolation
+    #[inline]
+    fn read_fractional(&self, delay: Sample) -> Sample {
+        let delay_int = delay.floor();
+        let frac = delay - delay_int;
+        let delay_int = delay_int as usize;
+        self.read(delay_int) * (1.0 - frac) + self.read(delay_int + 1) * frac
+    }
+    #[inline]
+    fn write(&mut self, input: Sample) {
+        self.buffer[self.position] = input;
+        self.position = (self.position + 1) % self.buffer.len();
+    }
+}
+
+/// Schroeder allpass in Dattorro's notation, a negative coefficient gives the inverted form of the tank
+#[derive(Clone, Debug)]
+struct Allpass {
+    delay: DelayLine,
+    length: Sample,
+}
+
+impl Allpass {
+    fn new(length: Sample, max_excursion: Sample) -> Self {
+        Self {
+            delay: DelayLine::new((length + max_excursion).ceil() as usize + 1),
+            length,
+        }
+    }
+    /// Process one sample with the delay length offset by `modulation` samples
+    #[inline]
+    fn process(&mut self, input: Sample, coefficient: Sample, modulation: Sample) -> Sample {
+        // Read before writing, so one sample less gives the full length
+        let delayed = self.delay.read_fractional(self.length + modulation - 1.0);
+        let v = input - coefficient * delayed;
+        self.delay.write(v);
+        delayed + coefficient * v
+    }
+}
+
+/// Dattorro's plate reverb, stereo in (summed to mono) and stereo out.
+///
+/// Delay lengths are scaled from the 29761 Hz of the paper to the sample rate.
+///
+/// *Inputs*
+/// - "left", "right"
+/// - "pre_delay": Pre-delay in seconds, up to the `max_pre_delay` given to `new`
+/// - "bandwidth": Input lowpass, 0.0 to 1.0 (0.9995 in the paper)
+/// - "input_diffusion_1", "input_diffusion_2": Input diffuser coefficients (0.75 and 0.625)
+/// - "decay_diffusion": Coefficient of the modulated tank allpasses (0.7).
+///   The second tank allpass follows the decay as in the paper.
+/// - "decay": Tank feedback gain, 0.0 to below 1.0 (0.5)
+/// - "damping": Tank lowpass, 0.0 to 1.0 (0.0005)
+/// - "excursion": Modulation depth of the tank allpasses, 1.0 is 16 samples at 29761 Hz
+/// - "excursion_rate": Modulation frequency in Hz (about 1.0)
+/// - "mix": Dry (0.0) to wet (1.0)
+pub struct DattorroPlate {
+    max_pre_delay: Sample,
+    pre_delay: DelayLine,
+    bandwidth_state: Sample,
+    input_diffusers: Vec<Allpass>,
+    /// Modulated allpass, delay, allpass, delay for the left and right half of the tank
+    tank_allpasses_1: [Allpass; 2],
+    tank_delays_1: [DelayLine; 2],
+    tank_allpasses_2: [Allpass; 2],
+    tank_delays_2: [DelayLine; 2],
+    damping_states: [Sample; 2],
+    /// Delay lengths in samples at the current sample rate: first and second delay
+    tank_delay_lengths: [[usize; 2]; 2],
+    /// Output taps scaled to the sample rate, left output then right output
+    output_taps: [[usize; 7]; 2],
+    /// Excursion LFO phase, 0..1
+    lfo_phase: Sample,
+    /// 16 samples at the original sample rate scaled to the current
+    max_excursion: Sample,
+}
+
+impl DattorroPlate {
+    fn scaled(length: Sample, sample_rate: Sample) -> Sample {
+        length * sample_rate / DATTORRO_SAMPLE_RATE
+    }
+}
+
+#[impl_gen]
+impl DattorroPlate {
+    /// `max_pre_delay` is the longest pre-delay in seconds
+    pub fn new(max_pre_delay: Sample) -> Self {
+        let mut plate = Self {
+            max_pre_delay,
+            pre_delay: DelayLine::new(1),
+            bandwidth_state: 0.0,
+            input_diffusers: Vec::new(),
+            tank_allpasses_1: [Allpass::new(1., 0.), Allpass::new(1., 0.)],
+            tank_delays_1: [DelayLine::new(1), DelayLine::new(1)],
+            tank_allpasses_2: [Allpass::new(1., 0.), Allpass::new(1., 0.)],
+            tank_delays_2: [DelayLine::new(1), DelayLine::new(1)],
+            damping_states: [0.0; 2],
+            tank_delay_lengths: [[1; 2]; 2],
+            output_taps: [[1; 7]; 2],
+            lfo_phase: 0.0,
+            max_excursion: MAX_EXCURSION,
+        };
+        plate.init(SampleRate(DATTORRO_SAMPLE_RATE));
+        plate
+    }
+    /// Allocate delay lines for the sample rate. Not real time safe.
+    pub fn init(&mut self, sample_rate: SampleRate) {
+        let sr = *sample_rate;
+        let scaled = |length: usize| Self::scaled(length as Sample, sr);
+        let scaled_int = |length: usize| scaled(length).round().max(1.0) as usize;
+        self.max_excursion = Self::scaled(MAX_EXCURSION, sr);
+        self.pre_delay = DelayLine::new((self.max_pre_delay * sr).ceil() as usize + 1);
+        self.input_diffusers = INPUT_DIFFUSERS
+            .iter()
+            .map(|&length| Allpass::new(scaled(length), 0.0))
+            .collect();
+        self.tank_allpasses_1 = [
+            Allpass::new(scaled(672), self.max_excursion),
+            Allpass::new(scaled(908), self.max_excursion),
+        ];
+        self.tank_delay_lengths = [
+            [scaled_int(4453), scaled_int(3720)],
+            [scaled_int(4217), scaled_int(3163)],
+        ];
+        self.tank_delays_1 = [
+            DelayLine::new(self.tank_delay_lengths[0][0]),
+            DelayLine::new(self.tank_delay_lengths[1][0]),
+        ];
+        self.tank_allpasses_2 = [
+            Allpass::new(scaled(1800), 0.0),
+            Allpass::new(scaled(2656), 0.0),
+        ];
+        self.tank_delays_2 = [
+            DelayLine::new(self.tank_delay_lengths[0][1]),
+            DelayLine::new(self.tank_delay_lengths[1][1]),
+        ];
+        self.output_taps = [
+            [266, 2974, 1913, 1996, 1990, 187, 1066].map(scaled_int),
+            [353, 3627, 1228, 2673, 2111, 335, 121].map(scaled_int),
+        ];
+        self.bandwidth_state = 0.0;
+        self.damping_states = [0.0; 2];
+    }
+    pub fn process(
+        &mut self,
+        left: &[Sample],
+        right: &[Sample],
+        pre_delay: &[Sample],
+        bandwidth: &[Sample],
+        input_diffusion_1: &[Sample],
+        input_diffusion_2: &[Sample],
+        decay_diffusion: &[Sample],
+        decay: &[Sample],
+        damping: &[Sample],
+        excursion: &[Sample],
+        excursion_rate: &[Sample],
+        mix: &[Sample],
+        left_out: &mut [Sample],
+        right_out: &mut [Sample],
+        sample_rate: SampleRate,
+    ) -> GenState {
+        let sr = *sample_rate;
+        let max_pre_delay = (self.pre_delay.buffer.len() - 2) as Sample;
+        for i in 0..left_out.len() {
+            let input = (left[i] + right[i]) * 0.5;
+            // Pre-delay
+            self.pre_delay.write(input);
+            let sig = self
+                .pre_delay
+                .read_fractional((pre_delay[i] * sr).clamp(0.0, max_pre_delay));
+            // Bandwidth
+            let bandwidth = bandwidth[i].clamp(0.0, 1.0);
+            self.bandwidth_state = bandwidth * sig + (1.0 - bandwidth) * self.bandwidth_state;
+            let mut sig = self.bandwidth_state;
+            // Input diffusion
+            for (d, diffuser) in self.input_diffusers.iter_mut().enumerate() {
+                let coefficient = if d < 2 {
+                    input_diffusion_1[i]
+                } else {
+                    input_diffusion_2[i]
+                };
+                sig = diffuser.process(sig, coefficient, 0.0);
+            }
+            // Tank
+            let decay = decay[i].clamp(0.0, 0.9999);
+            let decay_diffusion_2 = (decay + 0.15).clamp(0.25, 0.5);
+            let damping = damping[i].clamp(0.0, 1.0);
+            let lfo = (self.lfo_phase * std::f64::consts::TAU as Sample).sin();
+            let excursion = excursion[i].clamp(0.0, 1.0) * self.max_excursion;
+            self.lfo_phase = (self.lfo_phase + excursion_rate[i] / sr).fract();
+            // Read the ends of both halves before writing so that they cross-feed each other
+            let ends = [
+                self.tank_delays_2[0].read(self.tank_delay_lengths[0][1] - 1),
+                self.tank_delays_2[1].read(self.tank_delay_lengths[1][1] - 1),
+            ];
+            for half in 0..2 {
+                let tank_input = sig + ends[1 - half] * decay;
+                // The halves are modulated in opposite directions
+                let modulation = if half == 0 { lfo } else { -lfo } * excursion;
+                let s = self.tank_allpasses_1[half].process(
+                    tank_input,
+                    -decay_diffusion[i],
+                    modulation,
+                );
+                self.tank_delays_1[half].write(s);
+                let s = self.tank_delays_1[half].read(self.tank_delay_lengths[half][0]);
+                self.damping_states[half] =
+                    (1.0 - damping) * s + damping * self.damping_states[half];
+                let s = self.damping_states[half] * decay;
+                let s = self.tank_allpasses_2[half].process(s, decay_diffusion_2, 0.0);
+                self.tank_delays_2[half].write(s);
+            }
+            // Output taps
+            let [l, r] = &self.output_taps;
+            let wet_left = self.tank_delays_1[1].read(l[0]) + self.tank_delays_1[1].read(l[1])
+                - self.tank_allpasses_2[1].delay.read(l[2])
+                + self.tank_delays_2[1].read(l[3])
+                - self.tank_delays_1[0].read(l[4])
+                - self.tank_allpasses_2[0].delay.read(l[5])
+                - self.tank_delays_2[0].read(l[6]);
+            let wet_right = self.tank_delays_1[0].read(r[0]) + self.tank_delays_1[0].read(r[1])
+                - self.tank_allpasses_2[0].delay.read(r[2])
+                + self.tank_delays_2[0].read(r[3])
+                - self.tank_delays_1[1].read(r[4])
+                - self.tank_allpasses_2[1].delay.read(r[5])
+                - self.tank_delays_2[1].read(r[6]);
+            let mix = mix[i];
+            left_out[i] = wet_left * 0.6 * mix + left[i] * (1.0 - mix);
+            right_out[i] = wet_right * 0.6 * mix + right[i] * (1.0 - mix);
+        }
+        GenState::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::ImpulseResponse;
+
+    fn impulse_response(decay: Sample, seconds: Sample) -> ImpulseResponse {
+        let sample_rate = SampleRate(48000.);
+        let mut plate = DattorroPlate::new(0.1);
+        plate.init(sample_rate);
+        let block = |value| vec![value; 64];
+        let (pre_delay, bandwidth) = (block(0.01), block(0.9995));
+        let (input_diffusion_1, input_diffusion_2) = (block(0.75), block(0.625));
+        let (decay_diffusion, decay) = (block(0.7), block(decay));
+        let (damping, excursion, excursion_rate) = (block(0.0005), block(1.0), block(1.0));
+        let mix = block(1.0);
+        ImpulseResponse::render(2, seconds, *sample_rate, |input, outputs| {
+            let [left_out, right_out] = outputs else {
+                unreachable!()
+            };
+            plate.process(
+                input,
+                input,
+                &pre_delay,
+                &bandwidth,
+                &input_diffusion_1,
+                &input_diffusion_2,
+                &decay_diffusion,
+                &decay,
+                &damping,
+                &excursion,
+                &excursion_rate,
+                &mix,
+                left_out,
+                right_out,
+                sample_rate,
+            );
+        })
+    }
+
+    #[test]
+    fn delay_line_delays_an_impulse_by_exactly_the_delay() {
+        for delay in [0, 1, 5, 10] {
+            let mut delay_line = DelayLine::new(10);
+            let output: Vec<Sample> = (0..20)
+                .map(|n| {
+                    delay_line.write(if n == 0 { 1.0 } else { 0.0 });
+                    delay_line.read(delay)
+                })
+                .collect();
+            let expected: Vec<Sample> = (0..20)
+                .map(|n| if n == delay { 1.0 } else { 0.0 })
+                .collect();
+            assert_eq!(output, expected, "{delay}");
+        }
+    }
+
+    #[test]
+    fn allpass_delays_by_its_length() {
+        let coefficient = 0.5;
+        let mut allpass = Allpass::new(8.0, 0.0);
+        let output: Vec<Sample> = (0..12)
+            .map(|n| allpass.process(if n == 0 { 1.0 } else { 0.0 }, coefficient, 0.0))
+            .collect();
+        assert_eq!(output[0], coefficient);
+        assert!(output[1..8].iter().all(|&s| s == 0.0));
+        assert_eq!(output[8], 1.0 - coefficient * coefficient);
+    }
+
+    #[test]
+    fn plate_decays_and_is_wide() {
+        let ir = impulse_response(0.5, 3.0);
+        // Nothing before the pre-delay
+        assert!(ir.channels[0][..480].iter().all(|&s| s == 0.0));
+        let rt60 = ir.rt60_broadband(0).unwrap();
+        assert!(rt60 > 0.3 && rt60 < 3.0, "{rt60}");
+        assert!(ir.stereo_correlation().unwrap().abs() < 0.5);
+    }
+
+    #[test]
+    fn longer_decay_rings_longer() {
+        let short = impulse_response(0.3, 6.0).rt60_broadband(0).unwrap();
+        let long = impulse_response(0.8, 6.0).rt60_broadband(0).unwrap();
+        assert!(long > short * 2.0, "{short} {long}");
+    }
+}
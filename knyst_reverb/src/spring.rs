@@ -0,0 +1,203 @@
+//! Dispersive spring reverb
+//!
+//! After Välimäki, Parker and Abel, "Parametric Spring Reverberation Effect", JAES 2010:
+//! a long cascade of stretched allpass filters in a feedback loop gives the
+//! chirps that are characteristic of a spring tank.
+
+use knyst::{
+    prelude::{delay::StaticSampleDelay, impl_gen, GenState},
+    Sample, SampleRate,
+};
+
+use crate::luffverb::rt60_gain;
+
+/// Number of allpass filters in the chirp cascade of every spring
+const SPRING_ALLPASSES: usize = 80;
+/// The frequency in Hz below which the spring is dispersive, sets the stretch of the allpasses
+const TRANSITION_FREQ: Sample = 4300.;
+/// Round trip time in seconds of the left and right springs
+const SPRING_LENGTHS: [Sample; 2] = [0.0513, 0.0627];
+
+/// First order allpass where every unit delay is replaced by `stretch` samples:
+/// y[n] = a x[n] + x[n-K] - a y[n-K]
+#[derive(Clone, Debug)]
+struct StretchedAllpass {
+    x_history: Vec<Sample>,
+    y_history: Vec<Sample>,
+    position: usize,
+}
+
+impl StretchedAllpass {
+    fn new(stretch: usize) -> Self {
+        Self {
+            x_history: vec![0.0; stretch],
+            y_history: vec![0.0; stretch],
+            position: 0,
+        }
+    }
+    #[inline]
+    fn process(&mut self, input: Sample, coefficient: Sample) -> Sample {
+        let output = coefficient * input + self.x_history[self.position]
+            - coefficient * self.y_history[self.position];
+        self.x_history[self.position] = input;
+        self.y_history[self.position] = output;
+        self.position = (self.position + 1) % self.x_history.len();
+        output
+    }
+}
+
+/// One spring: the chirp cascade, a damping lowpass and the round trip delay
+struct Spring {
+    allpasses: Vec<StretchedAllpass>,
+    delay: StaticSampleDelay,
+    delay_length: usize,
+    stretch: usize,
+    lowpass_state: Sample,
+}
+
+impl Spring {
+    fn new(length: Sample, sample_rate: Sample) -> Self {
+        let stretch = (sample_rate / (2.0 * TRANSITION_FREQ)).round().max(1.0) as usize;
+        let delay_length = (length * sample_rate).round().max(1.0) as usize;
+        Self {
+            allpasses: (0..SPRING_ALLPASSES)
+                .map(|_| StretchedAllpass::new(stretch))
+                .collect(),
+            delay: StaticSampleDelay::new(delay_length),
+            delay_length,
+            stretch,
+            lowpass_state: 0.0,
+        }
+    }
+    /// Length of the loop in samples at low frequencies, including the group delay of the cascade
+    fn loop_length(&self, dispersion: Sample) -> usize {
+        let allpass_delay = self.stretch as Sample * (1.0 - dispersion) / (1.0 + dispersion);
+        self.delay_length + (allpass_delay * SPRING_ALLPASSES as Sample).round() as usize
+    }
+    #[inline]
+    fn process(
+        &mut self,
+        input: Sample,
+        feedback: Sample,
+        dispersion: Sample,
+        lowpass_coefficient: Sample,
+    ) -> Sample {
+        let mut sig = input + self.delay.read() * feedback;
+        for allpass in &mut self.allpasses {
+            sig = allpass.process(sig, dispersion);
+        }
+        self.lowpass_state = sig + (self.lowpass_state - sig) * lowpass_coefficient;
+        self.delay.write_and_advance(self.lowpass_state);
+        self.lowpass_state
+    }
+}
+
+/// Spring reverb made from chirp allpass cascades, one spring per channel.
+///
+/// *Inputs*
+/// - "left", "right"
+/// - "decay_time": Time in seconds for the low frequencies to decay by 60 dB
+/// - "dispersion": Allpass coefficient of the chirp cascade, 0.0 to 0.9. Higher values give longer, more pronounced chirps.
+/// - "damping": Cutoff frequency in Hz of the lowpass in the feedback loop
+/// - "mix": Dry (0.0) to wet (1.0)
+pub struct SpringReverb {
+    springs: Vec<Spring>,
+}
+
+impl Default for SpringReverb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[impl_gen]
+impl SpringReverb {
+    pub fn new() -> Self {
+        Self {
+            springs: Vec::new(),
+        }
+    }
+    /// Allocate the springs for the sample rate. Not real time safe.
+    pub fn init(&mut self, sample_rate: SampleRate) {
+        self.springs = SPRING_LENGTHS
+            .iter()
+            .map(|&length| Spring::new(length, *sample_rate))
+            .collect();
+    }
+    pub fn process(
+        &mut self,
+        left: &[Sample],
+        right: &[Sample],
+        decay_time: &[Sample],
+        dispersion: &[Sample],
+        damping: &[Sample],
+        mix: &[Sample],
+        left_out: &mut [Sample],
+        right_out: &mut [Sample],
+        sample_rate: SampleRate,
+    ) -> GenState {
+        let [left_spring, right_spring] = &mut self.springs[..] else {
+            return GenState::Continue;
+        };
+        for i in 0..left_out.len() {
+            let dispersion = dispersion[i].clamp(0.0, 0.9);
+            let decay_time = decay_time[i].max(Sample::EPSILON);
+            let lowpass_coefficient =
+                (-std::f64::consts::TAU as Sample * damping[i].max(0.0) / *sample_rate).exp();
+            let mix = mix[i];
+            for (spring, input, output) in [
+                (&mut *left_spring, left[i], &mut left_out[i]),
+                (&mut *right_spring, right[i], &mut right_out[i]),
+            ] {
+                let feedback = rt60_gain(spring.loop_length(dispersion), decay_time, sample_rate);
+                let wet = spring.process(input, feedback, dispersion, lowpass_coefficient);
+                *output = wet * mix + input * (1.0 - mix);
+            }
+        }
+        GenState::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::ImpulseResponse;
+
+    #[test]
+    fn stretched_allpass_keeps_the_energy() {
+        let mut allpass = StretchedAllpass::new(6);
+        let energy: Sample = (0..20000)
+            .map(|i| allpass.process(if i == 0 { 1.0 } else { 0.0 }, 0.6).powi(2))
+            .sum();
+        assert!((energy - 1.0).abs() < 1e-4, "{energy}");
+    }
+
+    #[test]
+    fn spring_decays_in_the_decay_time() {
+        let sample_rate = SampleRate(48000.);
+        let mut spring = SpringReverb::new();
+        spring.init(sample_rate);
+        let block = |value| vec![value; 64];
+        let (decay_time, dispersion, damping, mix) =
+            (block(1.5), block(0.6), block(20000.), block(1.0));
+        let ir = ImpulseResponse::render(2, 4.0, *sample_rate, |input, outputs| {
+            let [left_out, right_out] = outputs else {
+                unreachable!()
+            };
+            spring.process(
+                input,
+                input,
+                &decay_time,
+                &dispersion,
+                &damping,
+                &mix,
+                left_out,
+                right_out,
+                sample_rate,
+            );
+        });
+        let rt60 = ir.rt60_broadband(0).unwrap();
+        assert!(rt60 > 1.0 && rt60 < 2.0, "{rt60}");
+        assert_ne!(ir.channels[0], ir.channels[1]);
+    }
+}
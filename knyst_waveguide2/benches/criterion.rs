@@ -76,7 +76,7 @@ pub fn bowed_vs_simplified(c: &mut Criterion) {
     });
     */
 
-    let mut bwg = BowedWaveguideOversampled::new();
+    let mut bwg = BowedWaveguideOversampled::new(2);
     bwg.init(SampleRate(sample_rate), BlockSize(BLOCK));
     let mut output = [0.0; BLOCK];
     let mut reset_trig = [0.0; BLOCK];
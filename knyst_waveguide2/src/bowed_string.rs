@@ -1,77 +1,46 @@
 use knyst::{gen::filter::one_pole::OnePole, prelude::*, trig::is_trigger};
 
-pub struct BowedWaveguideOversampled {
-    wg: BowedWaveguide,
-    oversampled_exciter: Vec<Sample>,
-    output_buffer: Vec<Sample>,
-    downsampler: StandardDownsampler2X,
-    /// For exciter upsampling interpolation
-    upsampler_sample: Sample,
-}
+use crate::oversampling::{oversampled_gen, WaveguideModel};
+use crate::polyphony::{poly_string_gen, StringVoice};
+use crate::silence::SilenceDetector;
 
-#[impl_gen]
-impl BowedWaveguideOversampled {
-    pub fn new() -> Self {
-        Self {
-            wg: BowedWaveguide::new(),
-            downsampler: StandardDownsampler2X::new(),
-            output_buffer: Vec::new(),
-            oversampled_exciter: Vec::new(),
-            upsampler_sample: 0.,
-        }
-    }
-
-    pub fn init(&mut self, sample_rate: SampleRate, block_size: BlockSize) {
-        self.wg.init(SampleRate(*sample_rate * 2.));
-        self.output_buffer = vec![0.0; *block_size * 2];
-        self.oversampled_exciter = vec![0.0; *block_size * 2];
-    }
-    pub fn process(
-        &mut self,
-        exciter: &[Sample],
-        freq: &[Sample],
-        position: &[Sample],
-        feedback: &[Sample],
-        stiffness: &[Sample],
-        damping: &[Sample],
-        lf_damping: &[Sample],
-        delay_compensation: &[Sample],
-        bow_force: &[Sample],
-        bow_velocity: &[Sample],
-        reset_trig: &[Sample],
-        output: &mut [Sample],
-        sample_rate: SampleRate,
-    ) -> GenState {
-        let over_sample_rate = SampleRate(*sample_rate * 2.);
-        // Oversample exciter signal
-        assert!(exciter.len() * 2 == self.oversampled_exciter.len());
+oversampled_gen!(
+    /// [`BowedWaveguide`] running at a multiple of the sample rate, which
+    /// keeps the bow non-linearity from aliasing. The argument to `new` is
+    /// the oversampling factor, a power of two.
+    BowedWaveguideOversampled,
+    BowedWaveguide,
+    [
+        freq,
+        position,
+        feedback,
+        stiffness,
+        damping,
+        lf_damping,
+        delay_compensation,
+        bow_force,
+        bow_velocity,
+    ]
+);
 
-        for (inp, out) in exciter.iter().zip(self.oversampled_exciter.chunks_mut(2)) {
-            out[0] = self.upsampler_sample * 0.5 + *inp * 0.5;
-            out[1] = *inp;
-            self.upsampler_sample = *inp;
-        }
-
-        // NB: This only works because we read the settings at block rate
-        self.wg.process(
-            exciter,
-            freq,
-            position,
-            feedback,
-            stiffness,
-            damping,
-            lf_damping,
-            delay_compensation,
-            bow_force,
-            bow_velocity,
-            reset_trig,
-            &mut self.output_buffer,
-            over_sample_rate,
-        );
-        self.downsampler.process_block(&self.output_buffer, output);
-        GenState::Continue
-    }
-}
+poly_string_gen!(
+    /// A number of [`BowedWaveguide`] voices played by note events, see
+    /// [`PolyString`](crate::polyphony::PolyString). The bow is only applied
+    /// while a note is held, and every note on also plucks the string with a
+    /// half sine of "pluck_freq" scaled by the velocity.
+    PolyBowedWaveguide,
+    BowedWaveguide,
+    [
+        position,
+        feedback,
+        stiffness,
+        damping,
+        lf_damping,
+        delay_compensation,
+        bow_force,
+        bow_velocity,
+    ]
+);
 
 /// Settings for the BowedWaveguide
 #[allow(unused)]
@@ -87,7 +56,7 @@ pub struct BowedWaveguideSettings {
     bow_velocity: Sample,
 }
 
-use crate::{internal_filter::hiir::StandardDownsampler2X, AllpassFeedbackDelay};
+use crate::AllpassFeedbackDelay;
 // This waveguide implementation mirrors the one outlined in Palle Dahlstedt's
 //  "Physical Interactions with Digital Strings - A hybrid approach to a digital keyboard instrument"
 // It allows you to stop the string to some variable degree.
@@ -109,14 +78,21 @@ pub struct BowedWaveguide {
     last_position: Sample,
     last_damping: Sample,
     last_lf_damping: Sample,
+    last_delay_compensation: Sample,
     lp_filter: [OnePole<f64>; 4],
     hp_filter: [OnePole<f64>; 1],
     lp_filter_delay_compensation: f64,
     exciter_peak_follower: f64,
     bow: Bow,
+    silence: SilenceDetector,
 }
 
 impl BowedWaveguide {
+    /// Take the done action of `silence_detector` once the string has decayed
+    pub fn silence_detector(mut self, silence_detector: SilenceDetector) -> Self {
+        self.silence = silence_detector;
+        self
+    }
     #[inline]
     pub fn reset(&mut self) {
         // dbg!("Reset", self.last_delay_outputs);
@@ -200,6 +176,47 @@ impl BowedWaveguide {
         self.delays[3].set_delay_in_frames(delay0_time + self.lp_filter_delay_compensation - 0.37);
         // self.dc_blocker.set_freq_lowpass(30.0, sample_rate);
     }
+    /// Update the filters and delay lengths for one sample if any of the settings changed.
+    ///
+    /// The delays interpolate towards new lengths so that settings can be
+    /// modulated at audio rate without clicks.
+    #[inline]
+    pub fn update_settings(
+        &mut self,
+        freq: Sample,
+        position: Sample,
+        damping: Sample,
+        lf_damping: Sample,
+        delay_compensation: Sample,
+        sample_rate: f64,
+    ) {
+        let damping_changed = if damping != self.last_damping || self.last_lf_damping != lf_damping
+        {
+            self.set_damping(damping as f64, lf_damping as f64, sample_rate);
+            self.last_damping = damping;
+            self.last_lf_damping = lf_damping;
+            true
+        } else {
+            false
+        };
+        if damping_changed
+            || freq != self.last_freq
+            || position != self.last_position
+            || delay_compensation != self.last_delay_compensation
+        {
+            let freq = freq.max(20.);
+            self.set_freq_pos(
+                freq as f64,
+                damping as f64,
+                position as f64,
+                sample_rate,
+                delay_compensation as f64,
+            );
+            self.last_freq = freq;
+            self.last_position = position;
+            self.last_delay_compensation = delay_compensation;
+        }
+    }
     #[inline]
     pub fn process_sample(
         &mut self,
@@ -319,11 +336,13 @@ impl BowedWaveguide {
             last_position: 0.0,
             last_damping: 0.0,
             last_lf_damping: 0.0,
+            last_delay_compensation: 0.0,
             lp_filter: [OnePole::new(); 4],
             hp_filter: [OnePole::new()],
             lp_filter_delay_compensation: 0.0,
             exciter_peak_follower: 0.,
             bow: Bow::new(),
+            silence: SilenceDetector::default(),
         }
     }
     pub fn init(&mut self, sample_rate: SampleRate) {
@@ -340,12 +359,15 @@ impl BowedWaveguide {
             last_position: 0.0,
             last_damping: 0.0,
             last_lf_damping: 0.0,
+            last_delay_compensation: 0.0,
             lp_filter: [OnePole::new(); 4],
             hp_filter: [OnePole::new()],
             lp_filter_delay_compensation: 0.0,
             exciter_peak_follower: 0.,
             bow: Bow::new(),
+            silence: std::mem::take(&mut self.silence),
         };
+        self.silence.init(sample_rate);
     }
     pub fn process(
         &mut self,
@@ -363,71 +385,94 @@ impl BowedWaveguide {
         output: &mut [Sample],
         sample_rate: SampleRate,
     ) -> GenState {
-        let reset_trig = reset_trig[0];
-        let damping = damping[0];
-        let lf_damping = lf_damping[0];
-        let freq = freq[0];
-        let position = position[0];
         let sample_rate = sample_rate.to_f64();
-        let delay_comp = delay_compensation[0];
-        let stiffness = stiffness[0];
-        let bow_force = bow_force[0];
-        let bow_velocity = bow_velocity[0];
-        let damping_changed = if damping != self.last_damping || self.last_lf_damping != lf_damping
-        {
-            self.set_damping(damping as f64, lf_damping as f64, sample_rate as f64);
-            self.last_damping = damping;
-            self.last_lf_damping = lf_damping;
-            true
-        } else {
-            false
-        };
-        if damping_changed || freq != self.last_freq || position != self.last_position {
-            let freq = freq.max(20.);
-            self.set_freq_pos(
-                freq as f64,
-                damping as f64,
-                position as f64,
-                sample_rate as f64,
-                delay_comp as f64,
+        for (i, output) in output.iter_mut().enumerate() {
+            // The bow excites the string as well
+            let excitation = exciter[i].abs().max(bow_velocity[i]);
+            let sleeping = !self.silence.should_process(excitation);
+            let reset = is_trigger(reset_trig[i]);
+            if sleeping && !reset {
+                *output = 0.0;
+                continue;
+            }
+            self.update_settings(
+                freq[i],
+                position[i],
+                damping[i],
+                lf_damping[i],
+                delay_compensation[i],
+                sample_rate,
             );
-            self.last_freq = freq;
-            self.last_position = position;
-        }
-        for i in 0..4 {
-            self.delays[i].feedback = stiffness as f64;
-        }
-        // Should come after setting frequency because of how the delay buffer is cleared
-        if is_trigger(reset_trig) {
-            self.reset();
-        }
-        // let exciter_buf = exciter;
-        for ((&exciter, &feedback), output) in exciter.iter().zip(feedback).zip(output.iter_mut()) {
-            // let stop_amount = smootherstep(0.0, 1.0, stop_amount as f64);
+            for delay in &mut self.delays {
+                delay.feedback = stiffness[i] as f64;
+            }
+            // Should come after setting frequency because of how the delay buffer is cleared
+            if reset {
+                self.reset();
+            }
+            // A sleeping string is reset as well, to start from silence when it wakes up
+            if sleeping {
+                *output = 0.0;
+                continue;
+            }
             *output = self.process_sample(
-                exciter as f64,
-                feedback as f64,
-                bow_force as f64,
-                bow_velocity as f64,
+                exciter[i] as f64,
+                feedback[i] as f64,
+                bow_force[i] as f64,
+                bow_velocity[i] as f64,
             );
-            // if output.is_nan() {
-            //     dbg!(
-            //         exciter,
-            //         exciter_buf,
-            //         freq,
-            //         position,
-            //         damping,
-            //         lf_damping,
-            //         sample_rate,
-            //         delay_comp,
-            //         reset_trig,
-            //         stiffness,
-            //     );
-            //     panic!("NaN in waveguide.");
-            // }
+            self.silence.track(&self.last_delay_outputs, *output);
         }
         // dbg!(&output_buf);
-        GenState::Continue
+        self.silence.finish_block()
+    }
+}
+
+impl StringVoice for BowedWaveguide {
+    const FREQ: usize = 0;
+    const DAMPING: usize = 4;
+    const GATED: &'static [usize] = &[8];
+    fn silence_detector(self, silence_detector: SilenceDetector) -> Self {
+        BowedWaveguide::silence_detector(self, silence_detector)
+    }
+    fn silence(&self) -> &SilenceDetector {
+        &self.silence
+    }
+}
+
+impl WaveguideModel for BowedWaveguide {
+    const NUM_SETTINGS: usize = 9;
+    fn init(&mut self, sample_rate: SampleRate) {
+        BowedWaveguide::init(self, sample_rate);
+    }
+    fn process_block(
+        &mut self,
+        exciter: &[Sample],
+        settings: &[&[Sample]],
+        reset_trig: &[Sample],
+        output: &mut [Sample],
+        sample_rate: SampleRate,
+    ) -> GenState {
+        let &[freq, position, feedback, stiffness, damping, lf_damping, delay_compensation, bow_force, bow_velocity] =
+            settings
+        else {
+            unreachable!()
+        };
+        self.process(
+            exciter,
+            freq,
+            position,
+            feedback,
+            stiffness,
+            damping,
+            lf_damping,
+            delay_compensation,
+            bow_force,
+            bow_velocity,
+            reset_trig,
+            output,
+            sample_rate,
+        )
     }
 }
 
@@ -473,3 +518,181 @@ impl Bow {
         // sig
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: Sample = 48000.;
+
+    /// The inputs of the waveguide for `len` frames from `start`: a pluck
+    /// at the start and 6 Hz vibrato
+    fn inputs(start: usize, len: usize) -> [Vec<Sample>; 11] {
+        let frames = start..start + len;
+        let constant = |value: Sample| vec![value; len];
+        [
+            frames
+                .clone()
+                .map(|frame| if frame == 0 { 1.0 } else { 0.0 })
+                .collect(),
+            frames
+                .map(|frame| {
+                    let t = frame as Sample / SAMPLE_RATE;
+                    220. + 20. * (t * 6.0 * std::f32::consts::TAU as Sample).sin()
+                })
+                .collect(),
+            constant(0.3),
+            constant(0.99),
+            constant(0.0),
+            constant(6000.),
+            constant(5.),
+            constant(0.0),
+            constant(0.5),
+            constant(0.0),
+            constant(0.0),
+        ]
+    }
+
+    fn render(block_size: usize, frames: usize) -> Vec<Sample> {
+        let mut wg = BowedWaveguide::new();
+        wg.init(SampleRate(SAMPLE_RATE));
+        let mut output = vec![0.0; frames];
+        for (i, out) in output.chunks_mut(block_size).enumerate() {
+            let [exciter, freq, position, feedback, stiffness, damping, lf_damping, delay_compensation, bow_force, bow_velocity, reset_trig] =
+                inputs(i * block_size, out.len());
+            wg.process(
+                &exciter,
+                &freq,
+                &position,
+                &feedback,
+                &stiffness,
+                &damping,
+                &lf_damping,
+                &delay_compensation,
+                &bow_force,
+                &bow_velocity,
+                &reset_trig,
+                out,
+                SampleRate(SAMPLE_RATE),
+            );
+        }
+        output
+    }
+
+    /// Always initialised for blocks of 64 frames, smaller blocks are partial blocks
+    fn render_oversampled(factor: usize, block_size: usize, frames: usize) -> Vec<Sample> {
+        let mut wg = BowedWaveguideOversampled::new(factor);
+        wg.init(SampleRate(SAMPLE_RATE), BlockSize(64));
+        let mut output = vec![0.0; frames];
+        for (i, out) in output.chunks_mut(block_size).enumerate() {
+            let [exciter, freq, position, feedback, stiffness, damping, lf_damping, delay_compensation, bow_force, bow_velocity, reset_trig] =
+                inputs(i * block_size, out.len());
+            wg.process(
+                &exciter,
+                &freq,
+                &position,
+                &feedback,
+                &stiffness,
+                &damping,
+                &lf_damping,
+                &delay_compensation,
+                &bow_force,
+                &bow_velocity,
+                &reset_trig,
+                out,
+                SampleRate(SAMPLE_RATE),
+            );
+        }
+        output
+    }
+
+    #[test]
+    fn vibrato_does_not_depend_on_the_block_size() {
+        let per_sample = render(1, 4800);
+        let per_block = render(64, 4800);
+        assert!(per_sample.iter().any(|s| s.abs() > 0.01));
+        assert_eq!(per_sample, per_block);
+    }
+
+    #[test]
+    fn oversampled_vibrato_does_not_depend_on_the_block_size() {
+        for factor in [2, 4, 8] {
+            let per_sample = render_oversampled(factor, 1, 4800);
+            let per_block = render_oversampled(factor, 64, 4800);
+            // The whole block is rendered
+            assert!(per_block[4800 - 64..].iter().any(|s| s.abs() > 0.01));
+            assert!(per_block.iter().all(|s| s.is_finite()));
+            assert_eq!(per_sample, per_block);
+            assert_eq!(per_block, render_oversampled(factor, 48, 4800));
+        }
+    }
+
+    #[test]
+    fn settings_change_within_a_block() {
+        let frames = 4800;
+        let [exciter, _, position, feedback, stiffness, damping, lf_damping, delay_compensation, bow_force, bow_velocity, reset_trig] =
+            inputs(0, frames);
+        let render_freq = |freq: &[Sample]| {
+            let mut wg = BowedWaveguide::new();
+            wg.init(SampleRate(SAMPLE_RATE));
+            let mut output = vec![0.0; frames];
+            wg.process(
+                &exciter,
+                freq,
+                &position,
+                &feedback,
+                &stiffness,
+                &damping,
+                &lf_damping,
+                &delay_compensation,
+                &bow_force,
+                &bow_velocity,
+                &reset_trig,
+                &mut output,
+                SampleRate(SAMPLE_RATE),
+            );
+            output
+        };
+        let constant = render_freq(&vec![220.; frames]);
+        let mut glide = vec![220.; frames];
+        glide[frames / 2..].fill(330.);
+        let glide = render_freq(&glide);
+        assert_eq!(constant[..frames / 2], glide[..frames / 2]);
+        assert_ne!(constant[frames / 2..], glide[frames / 2..]);
+    }
+
+    #[test]
+    fn oversampled_gen_takes_the_done_action() {
+        use crate::silence::DoneAction;
+        let mut wg = BowedWaveguideOversampled::new(2)
+            .silence_detector(SilenceDetector::new(DoneAction::FreeSelf).silence_time(0.01));
+        wg.init(SampleRate(SAMPLE_RATE), BlockSize(64));
+        let mut output = vec![0.0; 64];
+        let mut blocks = 0;
+        loop {
+            let [exciter, freq, position, _, stiffness, damping, lf_damping, delay_compensation, bow_force, bow_velocity, reset_trig] =
+                inputs(blocks * 64, 64);
+            let state = wg.process(
+                &exciter,
+                &freq,
+                &position,
+                &vec![0.5; 64],
+                &stiffness,
+                &damping,
+                &lf_damping,
+                &delay_compensation,
+                &bow_force,
+                &bow_velocity,
+                &reset_trig,
+                &mut output,
+                SampleRate(SAMPLE_RATE),
+            );
+            if state == GenState::FreeSelf {
+                break;
+            }
+            blocks += 1;
+            assert!(blocks < 1000, "The string never went silent");
+        }
+        assert!(blocks > 0);
+    }
+}
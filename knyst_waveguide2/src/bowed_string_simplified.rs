@@ -2,6 +2,8 @@ use knyst::{gen::filter::one_pole::OnePole, prelude::*, trig::is_trigger};
 
 const BOW_WAVETABLE_SIZE: usize = 4096;
 
+use crate::oversampling::WaveguideModel;
+use crate::silence::SilenceDetector;
 use crate::AllpassFeedbackDelay;
 // This waveguide implementation mirrors the one outlined in Palle Dahlstedt's
 //  "Physical Interactions with Digital Strings - A hybrid approach to a digital keyboard instrument"
@@ -29,9 +31,15 @@ pub struct BowedWaveguideSimplified {
     lp_filter_delay_compensation: f64,
     exciter_peak_follower: f64,
     bow: Bow,
+    silence: SilenceDetector,
 }
 
 impl BowedWaveguideSimplified {
+    /// Take the done action of `silence_detector` once the string has decayed
+    pub fn silence_detector(mut self, silence_detector: SilenceDetector) -> Self {
+        self.silence = silence_detector;
+        self
+    }
     pub fn reset(&mut self) {
         // dbg!("Reset", self.last_delay_outputs);
         for delay in &mut self.delays {
@@ -236,6 +244,7 @@ impl BowedWaveguideSimplified {
             lp_filter_delay_compensation: 0.0,
             exciter_peak_follower: 0.,
             bow: Bow::new(),
+            silence: SilenceDetector::default(),
         }
     }
     pub fn init(&mut self, sample_rate: SampleRate) {
@@ -255,7 +264,9 @@ impl BowedWaveguideSimplified {
             lp_filter_delay_compensation: 0.0,
             exciter_peak_follower: 0.,
             bow: Bow::new(),
+            silence: std::mem::take(&mut self.silence),
         };
+        self.silence.init(sample_rate);
     }
     pub fn process(
         &mut self,
@@ -310,9 +321,14 @@ impl BowedWaveguideSimplified {
             .zip(bow_velocity)
             .zip(output.iter_mut())
         {
+            // A sleeping string is reset as well, to start from silence when it wakes up
             if is_trigger(reset_trig) {
                 self.reset();
             }
+            if !self.silence.should_process(exciter.abs().max(bow_velocity)) {
+                *output = 0.0;
+                continue;
+            }
             let damping_changed =
                 if damping != self.last_damping || self.last_lf_damping != lf_damping {
                     self.set_damping(damping as f64, lf_damping as f64, sample_rate as f64);
@@ -344,6 +360,7 @@ impl BowedWaveguideSimplified {
                 bow_force as f64,
                 bow_velocity as f64,
             );
+            self.silence.track(&self.last_delay_outputs, *output);
             if output.is_nan() {
                 dbg!(
                     exciter,
@@ -361,7 +378,43 @@ impl BowedWaveguideSimplified {
             }
         }
         // dbg!(&output_buf);
-        GenState::Continue
+        self.silence.finish_block()
+    }
+}
+
+impl WaveguideModel for BowedWaveguideSimplified {
+    const NUM_SETTINGS: usize = 9;
+    fn init(&mut self, sample_rate: SampleRate) {
+        BowedWaveguideSimplified::init(self, sample_rate);
+    }
+    fn process_block(
+        &mut self,
+        exciter: &[Sample],
+        settings: &[&[Sample]],
+        reset_trig: &[Sample],
+        output: &mut [Sample],
+        sample_rate: SampleRate,
+    ) -> GenState {
+        let &[freq, position, feedback, stiffness, damping, lf_damping, delay_compensation, bow_force, bow_velocity] =
+            settings
+        else {
+            unreachable!()
+        };
+        self.process(
+            exciter,
+            freq,
+            position,
+            feedback,
+            stiffness,
+            damping,
+            lf_damping,
+            delay_compensation,
+            bow_force,
+            bow_velocity,
+            reset_trig,
+            output,
+            sample_rate,
+        )
     }
 }
 
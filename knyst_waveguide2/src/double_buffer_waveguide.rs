@@ -1,4 +1,6 @@
 use crate::delay::AllpassFeedbackDelay;
+use crate::oversampling::WaveguideModel;
+use crate::silence::SilenceDetector;
 use crate::*;
 use knyst::prelude::*;
 use knyst::*;
@@ -23,9 +25,15 @@ pub struct Waveguide {
     lp_filter: [OnePole<f64>; 1],
     hp_filter: [OnePole<f64>; 1],
     lp_filter_delay_compensation: f64,
+    silence: SilenceDetector,
 }
 
 impl Waveguide {
+    /// Take the done action of `silence_detector` once the string has decayed
+    pub fn silence_detector(mut self, silence_detector: SilenceDetector) -> Self {
+        self.silence = silence_detector;
+        self
+    }
     pub fn reset(&mut self) {
         // dbg!("Reset", self.last_delay_outputs);
         for delay in &mut self.delays {
@@ -146,6 +154,7 @@ impl Waveguide {
             hp_filter: [OnePole::new()],
             lp_filter_delay_compensation: 0.0,
             current_delays: 0,
+            silence: SilenceDetector::default(),
         }
     }
     fn init(&mut self, sample_rate: SampleRate) {
@@ -169,7 +178,9 @@ impl Waveguide {
             hp_filter: [OnePole::new()],
             lp_filter_delay_compensation: 0.0,
             current_delays: 0,
+            silence: std::mem::take(&mut self.silence),
         };
+        self.silence.init(sample_rate);
     }
     fn process(
         &mut self,
@@ -210,9 +221,14 @@ impl Waveguide {
             .zip(reset_trig)
             .zip(output.iter_mut())
         {
+            // A sleeping string is reset as well, to start from silence when it wakes up
             if is_trigger(reset_trig) {
                 self.reset();
             }
+            if !self.silence.should_process(exciter) {
+                *output = 0.0;
+                continue;
+            }
             let damping_changed =
                 if damping != self.last_damping || self.last_lf_damping != lf_damping {
                     self.set_damping(damping as f64, lf_damping as f64, sample_rate as f64);
@@ -238,6 +254,8 @@ impl Waveguide {
                 self.delays[1][i].feedback = stiffness as f64;
             }
             *output = self.process_sample(exciter as f64, feedback as f64);
+            self.silence
+                .track(self.last_delay_outputs.as_flattened(), *output);
             if output.is_nan() {
                 dbg!(
                     freq,
@@ -253,6 +271,40 @@ impl Waveguide {
             }
         }
         // dbg!(&output_buf);
-        GenState::Continue
+        self.silence.finish_block()
+    }
+}
+
+impl WaveguideModel for Waveguide {
+    const NUM_SETTINGS: usize = 7;
+    fn init(&mut self, sample_rate: SampleRate) {
+        Waveguide::init(self, sample_rate);
+    }
+    fn process_block(
+        &mut self,
+        exciter: &[Sample],
+        settings: &[&[Sample]],
+        reset_trig: &[Sample],
+        output: &mut [Sample],
+        sample_rate: SampleRate,
+    ) -> GenState {
+        let &[freq, position, feedback, stiffness, damping, lf_damping, delay_compensation] =
+            settings
+        else {
+            unreachable!()
+        };
+        self.process(
+            exciter,
+            freq,
+            position,
+            feedback,
+            stiffness,
+            damping,
+            lf_damping,
+            delay_compensation,
+            reset_trig,
+            output,
+            sample_rate,
+        )
     }
 }
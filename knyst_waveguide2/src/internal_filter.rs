@@ -1 +1 @@
-pub(crate) mod hiir;
+pub mod hiir;
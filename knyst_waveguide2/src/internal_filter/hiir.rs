@@ -1,9 +1,13 @@
-/// This is ported and adapted from Laurent de Soras' HIIR library which
-/// includes filters especially useful for oversampling.
-/// http://ldesoras.free.fr/prod.html#src_hiir
-///
-/// Original license: DO WHAT THE FUCK YOU WANT TO PUBLIC LICENSE Version 2
-///
+//! This is ported and adapted from Laurent de Soras' HIIR library which
+//! includes filters especially useful for oversampling.
+//! http://ldesoras.free.fr/prod.html#src_hiir
+//!
+//! All filters are built from the coefficients of a polyphase halfband
+//! filter, see [`coefficient_design`] for choosing the order and transition
+//! bandwidth. Transition bandwidths are relative to the higher sample rate.
+//!
+//! Original license: DO WHAT THE FUCK YOU WANT TO PUBLIC LICENSE Version 2
+//!
 use crate::Sample;
 
 #[derive(Copy, Clone, Default, Debug)]
@@ -12,20 +16,16 @@ struct StageData {
     mem: f64,
 }
 
+/// Halves the sample rate after filtering out what would alias
 #[derive(Clone)]
-struct Downsampler2X {
+pub struct Downsampler2X {
     filter: Vec<StageData>,
     num_coefs: usize,
 }
 impl Downsampler2X {
     pub fn new(coefs: Vec<f64>) -> Self {
-        let mut filter = vec![StageData::default(); coefs.len() + 2];
-        for i in 2..(filter.len()) {
-            filter[i].coef = coefs[i - 2];
-        }
-
         Self {
-            filter,
+            filter: filter_from_coefs(&coefs),
             num_coefs: coefs.len(),
         }
     }
@@ -46,17 +46,129 @@ impl Downsampler2X {
         // Input buffer twice as large because it is upsampled
         assert!(output.len() * 2 == input.len());
         for (inp, out) in input.chunks(2).zip(output.iter_mut()) {
-            let mut inp_array = [inp[0] as f64, inp[1] as f64];
+            // The newest sample goes through the first allpass path, like in HIIR
+            let mut inp_array = [inp[1] as f64, inp[0] as f64];
             *out = self.process_sample(&mut inp_array) as Sample;
         }
     }
-    fn _clear_buffers(&mut self) {
+    /// Reset any state to 0
+    pub fn clear(&mut self) {
         for d in &mut self.filter {
             d.mem = 0.0;
         }
     }
 }
 
+/// Doubles the sample rate and filters out the images
+#[derive(Clone)]
+pub struct Upsampler2X {
+    filter: Vec<StageData>,
+    num_coefs: usize,
+}
+impl Upsampler2X {
+    pub fn new(coefs: Vec<f64>) -> Self {
+        Self {
+            filter: filter_from_coefs(&coefs),
+            num_coefs: coefs.len(),
+        }
+    }
+    pub fn process_sample(&mut self, input: f64) -> [f64; 2] {
+        // The even and odd output samples are the two allpass paths
+        let mut output = [input, input];
+        process_sample_pos(
+            self.num_coefs,
+            self.num_coefs,
+            &mut output,
+            &mut self.filter,
+        );
+        output
+    }
+    pub fn process_block(&mut self, input: &[Sample], output: &mut [Sample]) {
+        // Output buffer twice as large because it is upsampled
+        assert!(input.len() * 2 == output.len());
+        for (inp, out) in input.iter().zip(output.chunks_mut(2)) {
+            let [even, odd] = self.process_sample(*inp as f64);
+            out[0] = even as Sample;
+            out[1] = odd as Sample;
+        }
+    }
+    /// Reset any state to 0
+    pub fn clear(&mut self) {
+        for d in &mut self.filter {
+            d.mem = 0.0;
+        }
+    }
+}
+
+/// A pair of allpass filters with outputs 90 degrees apart, e.g. for
+/// frequency shifting or single sideband modulation.
+///
+/// The phase difference is accurate between the transition bandwidth and half
+/// the sample rate minus the transition bandwidth.
+#[derive(Clone)]
+pub struct PhaseHalfPi {
+    /// The stages run at half the sample rate, so every other sample uses the other state
+    filters: [Vec<StageData>; 2],
+    num_coefs: usize,
+    prev: f64,
+    phase: usize,
+}
+impl PhaseHalfPi {
+    pub fn new(coefs: Vec<f64>) -> Self {
+        Self {
+            filters: [filter_from_coefs(&coefs), filter_from_coefs(&coefs)],
+            num_coefs: coefs.len(),
+            prev: 0.0,
+            phase: 0,
+        }
+    }
+    /// Returns the two outputs, the second lagging the first by 90 degrees
+    pub fn process_sample(&mut self, input: f64) -> [f64; 2] {
+        let mut output = [input, self.prev];
+        process_sample_neg(
+            self.num_coefs,
+            self.num_coefs,
+            &mut output,
+            &mut self.filters[self.phase],
+        );
+        self.prev = input;
+        self.phase = 1 - self.phase;
+        output
+    }
+    pub fn process_block(
+        &mut self,
+        input: &[Sample],
+        output_0: &mut [Sample],
+        output_1: &mut [Sample],
+    ) {
+        assert!(input.len() == output_0.len() && input.len() == output_1.len());
+        for ((inp, out_0), out_1) in input.iter().zip(output_0).zip(output_1) {
+            let [sig_0, sig_1] = self.process_sample(*inp as f64);
+            *out_0 = sig_0 as Sample;
+            *out_1 = sig_1 as Sample;
+        }
+    }
+    /// Reset any state to 0
+    pub fn clear(&mut self) {
+        for filter in &mut self.filters {
+            for d in filter {
+                d.mem = 0.0;
+            }
+        }
+        self.prev = 0.0;
+        self.phase = 0;
+    }
+}
+
+/// The first two stages only hold memory for the input
+fn filter_from_coefs(coefs: &[f64]) -> Vec<StageData> {
+    let mut filter = vec![StageData::default(); coefs.len() + 2];
+    for (stage, &coef) in filter[2..].iter_mut().zip(coefs) {
+        stage.coef = coef;
+    }
+    filter
+}
+
 fn process_sample_pos(
     coefs_remaining: usize,
     num_coefs: usize,
@@ -104,6 +216,55 @@ fn process_sample_pos(
     }
 }
 
+/// Like [`process_sample_pos`] with the sign of the allpass delays flipped,
+/// which shifts the filters by a quarter of the sample rate
+fn process_sample_neg(
+    coefs_remaining: usize,
+    num_coefs: usize,
+    input: &mut [f64; 2],
+    filter: &mut Vec<StageData>,
+) {
+    match coefs_remaining {
+        0 => {
+            let i = num_coefs + 2;
+            filter[i - 2].mem = input[0];
+            filter[i - 1].mem = input[1];
+        }
+        1 => {
+            let i = num_coefs + 2 - 1;
+            let mut tmp0 = input[0];
+            tmp0 += filter[i].mem;
+            tmp0 *= filter[i].coef;
+            tmp0 -= filter[i - 2].mem;
+
+            filter[i - 2].mem = input[0];
+            filter[i - 1].mem = input[1];
+            filter[i].mem = tmp0;
+
+            input[0] = tmp0;
+        }
+        _ => {
+            let i = num_coefs + 2 - coefs_remaining;
+            let mut tmp0 = input[0];
+            tmp0 += filter[i].mem;
+            tmp0 *= filter[i].coef;
+            tmp0 -= filter[i - 2].mem;
+
+            let mut tmp1 = input[1];
+            tmp1 += filter[i + 1].mem;
+            tmp1 *= filter[i + 1].coef;
+            tmp1 -= filter[i - 1].mem;
+
+            filter[i - 2].mem = input[0];
+            filter[i - 1].mem = input[1];
+
+            input[0] = tmp0;
+            input[1] = tmp1;
+            process_sample_neg(coefs_remaining - 2, num_coefs, input, filter);
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct StandardDownsampler2X {
     downsampler: Downsampler2X,
@@ -111,45 +272,9 @@ pub struct StandardDownsampler2X {
 
 impl StandardDownsampler2X {
     pub fn new() -> Self {
-        // coefficients from the hiir oversampling.txt list
-        let num_coefs = 12;
-        // let coefficients = compute_coefs_spec_order_tbw(num_coefs, 0.04);
-        // dbg!(&coefficients);
-        let (coefficients, _delay_in_samples) = match num_coefs {
-            6 => (
-                vec![
-                    0.086928900551398763,
-                    0.29505822040137708,
-                    0.52489392936346835,
-                    0.7137336652558357,
-                    0.85080135560651127,
-                    0.95333447720743869,
-                ],
-                2,
-            ),
-            12 => (
-                vec![
-                    0.017347915108876406,
-                    0.067150480426919179,
-                    0.14330738338179819,
-                    0.23745131944299824,
-                    0.34085550201503761,
-                    0.44601111310335906,
-                    0.54753112652956148,
-                    0.6423859124721446,
-                    0.72968928615804163,
-                    0.81029959388029904,
-                    0.88644514917318362,
-                    0.96150605146543733,
-                ],
-                5,
-            ),
-            _ => {
-                panic!("Incorrect order in Oversampler")
-            }
-        };
-        let downsampler = Downsampler2X::new(coefficients);
-        Self { downsampler }
+        Self {
+            downsampler: Downsampler2X::new(standard_coefs()),
+        }
     }
 
     /// Downsample one channel by 2x from input to output
@@ -158,21 +283,154 @@ impl StandardDownsampler2X {
     }
 }
 
-#[allow(dead_code)]
-mod coefficient_design {
+/// 2x upsampler with the same filter as [`StandardDownsampler2X`]
+#[derive(Clone)]
+pub struct StandardUpsampler2X {
+    upsampler: Upsampler2X,
+}
+
+impl StandardUpsampler2X {
+    pub fn new() -> Self {
+        Self {
+            upsampler: Upsampler2X::new(standard_coefs()),
+        }
+    }
+
+    /// Upsample one channel by 2x from input to output
+    pub fn process_block(&mut self, input: &[Sample], output: &mut [Sample]) {
+        self.upsampler.process_block(input, output)
+    }
+}
+
+/// 12 coefficients with a transition bandwidth of about 0.047, the filter of
+/// [`StandardDownsampler2X`] and [`StandardUpsampler2X`]
+pub fn standard_coefs() -> Vec<f64> {
+    // coefficients from the hiir oversampling.txt list
+    let num_coefs = 12;
+    // let coefficients = compute_coefs_spec_order_tbw(num_coefs, 0.04);
+    // dbg!(&coefficients);
+    let (coefficients, _delay_in_samples) = match num_coefs {
+        6 => (
+            vec![
+                0.086928900551398763,
+                0.29505822040137708,
+                0.52489392936346835,
+                0.7137336652558357,
+                0.85080135560651127,
+                0.95333447720743869,
+            ],
+            2,
+        ),
+        12 => (
+            vec![
+                0.017347915108876406,
+                0.067150480426919179,
+                0.14330738338179819,
+                0.23745131944299824,
+                0.34085550201503761,
+                0.44601111310335906,
+                0.54753112652956148,
+                0.6423859124721446,
+                0.72968928615804163,
+                0.81029959388029904,
+                0.88644514917318362,
+                0.96150605146543733,
+            ],
+            5,
+        ),
+        _ => {
+            panic!("Incorrect order in Oversampler")
+        }
+    };
+    coefficients
+}
+
+/// Design of the coefficients for the filters in this module
+///
+/// `transition` is the transition bandwidth relative to the sample rate the
+/// filter runs at, i.e. the higher rate for up- and downsampling. The
+/// passband ends at `0.25 - transition` and the stopband starts at
+/// `0.25 + transition`.
+pub mod coefficient_design {
+    use num::complex::Complex64;
+
+    /// Coefficients for a filter with `nbr_coefs` coefficients and the
+    /// given transition bandwidth. The stopband attenuation follows from
+    /// those, see [`compute_atten_from_order_tbw`].
     pub fn compute_coefs_spec_order_tbw(nbr_coefs: usize, transition: f64) -> Vec<f64> {
         assert!(nbr_coefs > 0);
         assert!(transition > 0.0);
         assert!(transition < 0.5);
         let (k, q) = compute_transition_param(transition);
-        dbg!(k, q);
-        let mut coefs = Vec::with_capacity(nbr_coefs);
         let order = nbr_coefs * 2 + 1;
-        for i in 0..nbr_coefs {
-            coefs.push(compute_coef(i, k, q, order));
-            println!();
+        (0..nbr_coefs)
+            .map(|i| compute_coef(i, k, q, order))
+            .collect()
+    }
+    /// Coefficients for the lowest order filter with at least `attenuation`
+    /// dB of stopband attenuation and the given transition bandwidth
+    pub fn compute_coefs(attenuation: f64, transition: f64) -> Vec<f64> {
+        let nbr_coefs = compute_nbr_coefs_from_proto(attenuation, transition);
+        compute_coefs_spec_order_tbw(nbr_coefs, transition)
+    }
+    /// Number of coefficients needed for `attenuation` dB of stopband
+    /// attenuation with the given transition bandwidth
+    pub fn compute_nbr_coefs_from_proto(attenuation: f64, transition: f64) -> usize {
+        let (_k, q) = compute_transition_param(transition);
+        let order = compute_order(attenuation, q);
+        (order - 1) / 2
+    }
+    /// Stopband attenuation in dB of a filter with `nbr_coefs` coefficients and
+    /// the given transition bandwidth
+    pub fn compute_atten_from_order_tbw(nbr_coefs: usize, transition: f64) -> f64 {
+        let (_k, q) = compute_transition_param(transition);
+        let order = nbr_coefs * 2 + 1;
+        compute_atten(q, order)
+    }
+    /// Group delay in samples of the halfband filter with `coefs` at `f_fs`,
+    /// both relative to the sample rate the filter runs at.
+    ///
+    /// For a [`Downsampler2X`](super::Downsampler2X) or
+    /// [`Upsampler2X`](super::Upsampler2X) that is the higher sample rate.
+    pub fn compute_group_delay(coefs: &[f64], f_fs: f64) -> f64 {
+        // Differentiate the phase of the frequency response numerically
+        const DELTA: f64 = 1e-6;
+        let w = std::f64::consts::TAU * f_fs;
+        let before = halfband_response(coefs, w - DELTA);
+        let after = halfband_response(coefs, w + DELTA);
+        -(after / before).arg() / (2.0 * DELTA)
+    }
+    /// The frequency response at `w` radians per sample. The even
+    /// coefficients are one allpass path and the odd ones the other path,
+    /// which is delayed by one sample.
+    fn halfband_response(coefs: &[f64], w: f64) -> Complex64 {
+        let z2 = Complex64::from_polar(1.0, -2.0 * w);
+        let mut paths = [Complex64::new(1.0, 0.0); 2];
+        for (i, &a) in coefs.iter().enumerate() {
+            paths[i % 2] *= (a + z2) / (1.0 + a * z2);
+        }
+        (paths[0] + paths[1] * Complex64::from_polar(1.0, -w)) * 0.5
+    }
+    fn compute_order(attenuation: f64, q: f64) -> usize {
+        assert!(attenuation > 0.);
+        assert!(q > 0.);
+        let attn_p2 = 10.0_f64.powf(-attenuation / 10.);
+        let a = attn_p2 / (1. - attn_p2);
+        let mut order = ((a * a / 16.).ln() / q.ln()).ceil() as usize;
+        if order % 2 == 0 {
+            order += 1;
         }
-        coefs
+        if order == 1 {
+            order = 3;
+        }
+        order
+    }
+    fn compute_atten(q: f64, order: usize) -> f64 {
+        assert!(q > 0.);
+        assert!(order % 2 == 1);
+        let a = 4. * (order as f64 * 0.5 * q.ln()).exp();
+        let attn_p2 = a / (1. + a);
+        -10. * attn_p2.log10()
     }
     fn compute_transition_param(transition: f64) -> (f64, f64) {
         assert!(transition > 0.);
@@ -198,8 +456,6 @@ mod coefficient_design {
         let den: f64 = compute_acc_den(q, order, c) + 0.5;
         let ww = num / den;
         let wwsq = ww * ww;
-        dbg!(num, den, ww, wwsq);
-        dbg!((1. - wwsq * k) * (1. - wwsq / k));
         let x = ((1. - wwsq * k) * (1. - wwsq / k)).sqrt() / (1. + wwsq);
         assert!(!x.is_nan());
         let coef = (1. - x) / (1. + x);
@@ -265,3 +521,84 @@ mod coefficient_design {
         z
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::coefficient_design::*;
+    use super::*;
+
+    /// Amplitude and phase of the sine at `freq` in `signal`, `freq` relative to the sample rate
+    fn component(signal: &[Sample], freq: f64) -> (f64, f64) {
+        let (mut re, mut im) = (0.0, 0.0);
+        for (i, &s) in signal.iter().enumerate() {
+            let phase = std::f64::consts::TAU * freq * i as f64;
+            re += s as f64 * phase.cos();
+            im += s as f64 * phase.sin();
+        }
+        (2.0 * re.hypot(im) / signal.len() as f64, re.atan2(im))
+    }
+
+    fn sine(freq: f64, len: usize) -> Vec<Sample> {
+        (0..len)
+            .map(|i| (std::f64::consts::TAU * freq * i as f64).sin() as Sample)
+            .collect()
+    }
+
+    #[test]
+    fn design_reproduces_the_standard_coefs() {
+        let coefs = compute_coefs_spec_order_tbw(12, 0.0472053275108);
+        for (designed, standard) in coefs.iter().zip(standard_coefs()) {
+            assert!((designed - standard).abs() < 1e-9, "{designed} {standard}");
+        }
+    }
+
+    #[test]
+    fn order_follows_the_attenuation() {
+        let attenuation = compute_atten_from_order_tbw(6, 0.05);
+        assert!(attenuation > 60.0, "{attenuation}");
+        assert_eq!(compute_nbr_coefs_from_proto(attenuation - 1.0, 0.05), 6);
+        assert_eq!(compute_coefs(attenuation + 1.0, 0.05).len(), 7);
+        // A wider transition band gives more attenuation for the same order
+        assert!(compute_atten_from_order_tbw(6, 0.1) > attenuation);
+    }
+
+    #[test]
+    fn downsampler_attenuates_as_designed() {
+        let transition = 0.05;
+        let coefs = compute_coefs_spec_order_tbw(6, transition);
+        let attenuation = compute_atten_from_order_tbw(6, transition);
+        // Just inside the stopband, relative to the higher sample rate
+        let freq = 0.25 + transition + 0.01;
+        let mut downsampler = Downsampler2X::new(coefs);
+        let input = sine(freq, 8192);
+        let mut output = vec![0.0; 4096];
+        downsampler.process_block(&input, &mut output);
+        // Where the signal aliases to at the lower rate
+        let (alias, _) = component(&output[1536..], 1.0 - 2.0 * freq);
+        let alias_db = -20.0 * alias.log10();
+        assert!(alias_db > attenuation - 3.0, "{alias_db} {attenuation}");
+    }
+
+    #[test]
+    fn phase_half_pi_outputs_are_90_degrees_apart() {
+        let transition = 0.02;
+        for freq in [0.05, 0.125, 0.25, 0.4] {
+            let mut filter = PhaseHalfPi::new(compute_coefs(90.0, transition));
+            let input = sine(freq, 4096);
+            let mut output_0 = vec![0.0; 4096];
+            let mut output_1 = vec![0.0; 4096];
+            filter.process_block(&input, &mut output_0, &mut output_1);
+            let (amp_0, phase_0) = component(&output_0[1024..], freq);
+            let (amp_1, phase_1) = component(&output_1[1024..], freq);
+            assert!((amp_0 - 1.0).abs() < 1e-3, "{freq}: {amp_0}");
+            assert!((amp_1 - 1.0).abs() < 1e-3, "{freq}: {amp_1}");
+            // Output 1 lags behind
+            let difference = (phase_0 - phase_1).rem_euclid(std::f64::consts::TAU);
+            assert!(
+                (difference - std::f64::consts::FRAC_PI_2).abs() < 1e-3,
+                "{freq}: {}",
+                difference.to_degrees()
+            );
+        }
+    }
+}
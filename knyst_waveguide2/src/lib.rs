@@ -8,7 +8,11 @@ pub mod bowed_string_simplified;
 mod delay;
 pub mod double_buffer_waveguide;
 mod internal_filter;
+pub use internal_filter::hiir;
+pub mod oversampling;
 pub mod parallel_bpf_waveguide;
+pub mod polyphony;
+pub mod silence;
 pub mod split_string;
 use std::f32::consts::{PI, TAU};
 
@@ -19,6 +23,9 @@ use knyst::wavetable::WavetablePhase;
 use knyst::xorrng::XOrShift32Rng;
 use knyst::Sample;
 use knyst::{prelude::*, wavetable::FRACTIONAL_PART};
+use oversampling::WaveguideModel;
+use polyphony::{poly_string_gen, StringVoice};
+use silence::SilenceDetector;
 
 /// Waveguide gen for the internal delay line implementation
 /// *inputs*
@@ -40,9 +47,15 @@ pub struct Waveguide {
     lp_filter: [OnePole<f64>; 1],
     hp_filter: [OnePole<f64>; 1],
     lp_filter_delay_compensation: f64,
+    silence: SilenceDetector,
 }
 
 impl Waveguide {
+    /// Take the done action of `silence_detector` once the string has decayed
+    pub fn silence_detector(mut self, silence_detector: SilenceDetector) -> Self {
+        self.silence = silence_detector;
+        self
+    }
     pub fn reset(&mut self) {
         // dbg!("Reset", self.last_delay_outputs);
         for delay in &mut self.delays {
@@ -149,6 +162,7 @@ impl Waveguide {
             lp_filter: [OnePole::new()],
             hp_filter: [OnePole::new()],
             lp_filter_delay_compensation: 0.0,
+            silence: SilenceDetector::default(),
         }
     }
     fn init(&mut self, sample_rate: SampleRate) {
@@ -166,7 +180,9 @@ impl Waveguide {
             lp_filter: [OnePole::new()],
             hp_filter: [OnePole::new()],
             lp_filter_delay_compensation: 0.0,
+            silence: std::mem::take(&mut self.silence),
         };
+        self.silence.init(sample_rate);
     }
     fn process(
         &mut self,
@@ -207,9 +223,14 @@ impl Waveguide {
             .zip(reset_trig)
             .zip(output.iter_mut())
         {
+            // A sleeping string is reset as well, to start from silence when it wakes up
             if is_trigger(reset_trig) {
                 self.reset();
             }
+            if !self.silence.should_process(exciter) {
+                *output = 0.0;
+                continue;
+            }
             let damping_changed =
                 if damping != self.last_damping || self.last_lf_damping != lf_damping {
                     self.set_damping(damping as f64, lf_damping as f64, sample_rate as f64);
@@ -234,6 +255,7 @@ impl Waveguide {
                 self.delays[i].feedback = stiffness as f64;
             }
             *output = self.process_sample(exciter as f64, feedback as f64);
+            self.silence.track(&self.last_delay_outputs, *output);
             if output.is_nan() {
                 dbg!(
                     freq,
@@ -249,10 +271,72 @@ impl Waveguide {
             }
         }
         // dbg!(&output_buf);
-        GenState::Continue
+        self.silence.finish_block()
     }
 }
 
+impl WaveguideModel for Waveguide {
+    const NUM_SETTINGS: usize = 7;
+    fn init(&mut self, sample_rate: SampleRate) {
+        Waveguide::init(self, sample_rate);
+    }
+    fn process_block(
+        &mut self,
+        exciter: &[Sample],
+        settings: &[&[Sample]],
+        reset_trig: &[Sample],
+        output: &mut [Sample],
+        sample_rate: SampleRate,
+    ) -> GenState {
+        let &[freq, position, feedback, stiffness, damping, lf_damping, delay_compensation] =
+            settings
+        else {
+            unreachable!()
+        };
+        self.process(
+            exciter,
+            freq,
+            position,
+            feedback,
+            stiffness,
+            damping,
+            lf_damping,
+            delay_compensation,
+            reset_trig,
+            output,
+            sample_rate,
+        )
+    }
+}
+
+impl StringVoice for Waveguide {
+    const FREQ: usize = 0;
+    const DAMPING: usize = 4;
+    const GATED: &'static [usize] = &[];
+    fn silence_detector(self, silence_detector: SilenceDetector) -> Self {
+        Waveguide::silence_detector(self, silence_detector)
+    }
+    fn silence(&self) -> &SilenceDetector {
+        &self.silence
+    }
+}
+
+poly_string_gen!(
+    /// A number of [`Waveguide`] voices played by note events, see
+    /// [`PolyString`](polyphony::PolyString). Every note on plucks a voice
+    /// with a half sine of "pluck_freq" scaled by the velocity.
+    PolyWaveguide,
+    Waveguide,
+    [
+        position,
+        feedback,
+        stiffness,
+        damping,
+        lf_damping,
+        delay_compensation,
+    ]
+);
+
 fn delay_times(freq: f64, position: f64) -> (f64, f64) {
     let total_delay = freq.recip();
     let time0 = total_delay * position;
@@ -0,0 +1,533 @@
+//! Oversampling with cascaded 2x HIIR halfband filters
+//!
+//! [`Oversampler`] runs any processing, e.g. a non-linearity, at a multiple of
+//! the sample rate. [`Oversampled`] runs a waveguide model at a multiple of the
+//! sample rate: the exciter is upsampled and the output downsampled, while the
+//! settings are interpolated linearly so that they can still be modulated per
+//! sample.
+
+use knyst::{gen::GenState, BlockSize, Sample, SampleRate};
+
+use crate::hiir::{coefficient_design, standard_coefs, Downsampler2X, Upsampler2X};
+
+/// Most settings a [`WaveguideModel`] can have, the oversampled settings are gathered without allocating
+pub const MAX_SETTINGS: usize = 16;
+
+/// A waveguide model with an exciter input, a number of settings and a reset
+/// trigger, which is how all the waveguides in this crate are laid out.
+pub trait WaveguideModel {
+    /// Number of settings between the exciter and the reset trigger
+    const NUM_SETTINGS: usize;
+    fn init(&mut self, sample_rate: SampleRate);
+    /// Process one block. `settings` has one slice per setting, in the same
+    /// order as the inputs of the gen. Returns the state of the gen, e.g.
+    /// from its [`SilenceDetector`](crate::silence::SilenceDetector).
+    fn process_block(
+        &mut self,
+        exciter: &[Sample],
+        settings: &[&[Sample]],
+        reset_trig: &[Sample],
+        output: &mut [Sample],
+        sample_rate: SampleRate,
+    ) -> GenState;
+}
+
+/// Up- and downsampling of one channel by a power of two, cascading 2x stages
+pub struct Oversampler {
+    upsamplers: Vec<Upsampler2X>,
+    downsamplers: Vec<Downsampler2X>,
+    /// The halfband filter used by every stage
+    coefs: Vec<f64>,
+    buffers: [Vec<Sample>; 2],
+    /// The signal at the oversampled rate for [`Oversampler::process`]
+    oversampled: Vec<Sample>,
+}
+
+impl Oversampler {
+    /// With the filter of [`StandardUpsampler2X`](crate::hiir::StandardUpsampler2X)
+    ///
+    /// # Panics
+    /// If `factor` is not a power of two
+    pub fn new(factor: usize) -> Self {
+        Self::with_coefs(factor, standard_coefs())
+    }
+    /// With a filter of `nbr_coefs` coefficients and the given transition
+    /// bandwidth in every stage. Fewer coefficients are cheaper and have less
+    /// delay, but attenuate less for the same transition bandwidth.
+    ///
+    /// # Panics
+    /// If `factor` is not a power of two
+    pub fn with_design(factor: usize, nbr_coefs: usize, transition: f64) -> Self {
+        Self::with_coefs(
+            factor,
+            coefficient_design::compute_coefs_spec_order_tbw(nbr_coefs, transition),
+        )
+    }
+    /// # Panics
+    /// If `factor` is not a power of two
+    pub fn with_coefs(factor: usize, coefs: Vec<f64>) -> Self {
+        assert!(
+            factor.is_power_of_two(),
+            "Oversampling factor {factor} is not a power of two"
+        );
+        let num_stages = factor.trailing_zeros() as usize;
+        Self {
+            upsamplers: (0..num_stages)
+                .map(|_| Upsampler2X::new(coefs.clone()))
+                .collect(),
+            downsamplers: (0..num_stages)
+                .map(|_| Downsampler2X::new(coefs.clone()))
+                .collect(),
+            coefs,
+            buffers: [Vec::new(), Vec::new()],
+            oversampled: Vec::new(),
+        }
+    }
+    pub fn factor(&self) -> usize {
+        1 << self.upsamplers.len()
+    }
+    /// Group delay in samples at the original rate of upsampling and then
+    /// downsampling, at `freq` relative to the original rate.
+    pub fn group_delay(&self, freq: f64) -> f64 {
+        (0..self.upsamplers.len())
+            .map(|stage| {
+                // Each stage runs at twice the rate of the previous one
+                let rate = (2 << stage) as f64;
+                let filter_delay =
+                    coefficient_design::compute_group_delay(&self.coefs, freq / rate);
+                // The downsampler outputs the newest sample of each pair, one sample
+                // at the higher rate earlier than the upsampler put it
+                (2.0 * filter_delay - 1.0) / rate
+            })
+            .sum()
+    }
+    /// Allocate the buffers for blocks of up to `block_size` frames at the original rate
+    pub fn init(&mut self, block_size: BlockSize) {
+        let oversampled_block_size = *block_size * self.factor();
+        self.buffers = [
+            vec![0.0; oversampled_block_size],
+            vec![0.0; oversampled_block_size],
+        ];
+        self.oversampled = vec![0.0; oversampled_block_size];
+    }
+    /// Upsample `input`, run `process` on it at the oversampled rate in place
+    /// and downsample the result into `output`.
+    pub fn process(
+        &mut self,
+        input: &[Sample],
+        output: &mut [Sample],
+        mut process: impl FnMut(&mut [Sample]),
+    ) {
+        let mut oversampled = std::mem::take(&mut self.oversampled);
+        let len = input.len() * self.factor();
+        self.upsample(input, &mut oversampled[..len]);
+        process(&mut oversampled[..len]);
+        self.downsample(&oversampled[..len], output);
+        self.oversampled = oversampled;
+    }
+    /// Reset any filter state to 0
+    pub fn clear(&mut self) {
+        for upsampler in &mut self.upsamplers {
+            upsampler.clear();
+        }
+        for downsampler in &mut self.downsamplers {
+            downsampler.clear();
+        }
+    }
+    /// `output` needs to be `factor` times as long as `input`
+    pub fn upsample(&mut self, input: &[Sample], output: &mut [Sample]) {
+        assert_eq!(input.len() * self.factor(), output.len());
+        let [current, next] = &mut self.buffers;
+        let mut len = input.len();
+        current[..len].copy_from_slice(input);
+        for upsampler in &mut self.upsamplers {
+            upsampler.process_block(&current[..len], &mut next[..len * 2]);
+            std::mem::swap(current, next);
+            len *= 2;
+        }
+        output.copy_from_slice(&current[..len]);
+    }
+    /// `input` needs to be `factor` times as long as `output`
+    pub fn downsample(&mut self, input: &[Sample], output: &mut [Sample]) {
+        assert_eq!(output.len() * self.factor(), input.len());
+        let [current, next] = &mut self.buffers;
+        let mut len = input.len();
+        current[..len].copy_from_slice(input);
+        for downsampler in &mut self.downsamplers {
+            downsampler.process_block(&current[..len], &mut next[..len / 2]);
+            std::mem::swap(current, next);
+            len /= 2;
+        }
+        output.copy_from_slice(&current[..len]);
+    }
+}
+
+/// Runs a [`WaveguideModel`] at `factor` times the sample rate
+pub struct Oversampled<M> {
+    pub model: M,
+    oversampler: Oversampler,
+    exciter: Vec<Sample>,
+    /// The settings interpolated to the oversampled rate
+    settings: Vec<Vec<Sample>>,
+    /// For settings interpolation
+    last_settings: Vec<Sample>,
+    /// Interpolation starts from the first settings received
+    received_settings: bool,
+    reset_trig: Vec<Sample>,
+    output: Vec<Sample>,
+}
+
+impl<M: WaveguideModel> Oversampled<M> {
+    /// # Panics
+    /// If `factor` is not a power of two or the model has more than [`MAX_SETTINGS`] settings
+    pub fn new(model: M, factor: usize) -> Self {
+        Self::with_oversampler(model, Oversampler::new(factor))
+    }
+    /// # Panics
+    /// If the model has more than [`MAX_SETTINGS`] settings
+    pub fn with_oversampler(model: M, oversampler: Oversampler) -> Self {
+        assert!(M::NUM_SETTINGS <= MAX_SETTINGS);
+        Self {
+            model,
+            oversampler,
+            exciter: Vec::new(),
+            settings: vec![Vec::new(); M::NUM_SETTINGS],
+            last_settings: vec![0.0; M::NUM_SETTINGS],
+            received_settings: false,
+            reset_trig: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+    pub fn factor(&self) -> usize {
+        self.oversampler.factor()
+    }
+    /// The delay added by the oversampling filters, see [`Oversampler::group_delay`]
+    pub fn group_delay(&self, freq: f64) -> f64 {
+        self.oversampler.group_delay(freq)
+    }
+    pub fn init(&mut self, sample_rate: SampleRate, block_size: BlockSize) {
+        let factor = self.factor();
+        self.model.init(SampleRate(*sample_rate * factor as Sample));
+        self.oversampler.init(block_size);
+        let oversampled_block_size = *block_size * factor;
+        self.exciter = vec![0.0; oversampled_block_size];
+        self.settings = vec![vec![0.0; oversampled_block_size]; M::NUM_SETTINGS];
+        self.reset_trig = vec![0.0; oversampled_block_size];
+        self.output = vec![0.0; oversampled_block_size];
+    }
+    /// Process a block of any length up to the block size given to `init`.
+    /// Returns the state of the model with frames at the original rate.
+    pub fn process(
+        &mut self,
+        exciter: &[Sample],
+        settings: &[&[Sample]],
+        reset_trig: &[Sample],
+        output: &mut [Sample],
+        sample_rate: SampleRate,
+    ) -> GenState {
+        assert_eq!(settings.len(), M::NUM_SETTINGS);
+        if output.is_empty() {
+            return GenState::Continue;
+        }
+        let factor = self.factor();
+        let len = output.len() * factor;
+        let exciter_up = &mut self.exciter[..len];
+        self.oversampler.upsample(exciter, exciter_up);
+
+        // The settings are read per sample so they need to be at the oversampled rate as well
+        if !self.received_settings {
+            for (last, setting) in self.last_settings.iter_mut().zip(settings) {
+                *last = setting[0];
+            }
+            self.received_settings = true;
+        }
+        for ((setting, oversampled), last) in settings
+            .iter()
+            .zip(&mut self.settings)
+            .zip(&mut self.last_settings)
+        {
+            interpolate_linear(setting, &mut oversampled[..len], last, factor);
+        }
+        // A trigger should only trigger once
+        for (&trig, out) in reset_trig
+            .iter()
+            .zip(self.reset_trig[..len].chunks_mut(factor))
+        {
+            out.fill(0.0);
+            out[0] = trig;
+        }
+
+        let mut oversampled_settings: [&[Sample]; MAX_SETTINGS] = [&[]; MAX_SETTINGS];
+        for (oversampled, setting) in oversampled_settings.iter_mut().zip(&self.settings) {
+            *oversampled = &setting[..len];
+        }
+        let state = self.model.process_block(
+            &self.exciter[..len],
+            &oversampled_settings[..M::NUM_SETTINGS],
+            &self.reset_trig[..len],
+            &mut self.output[..len],
+            SampleRate(*sample_rate * factor as Sample),
+        );
+        self.oversampler.downsample(&self.output[..len], output);
+        // Keep the frame that contains the last oversampled frame
+        match state {
+            GenState::FreeGraph(frame) => GenState::FreeGraph(frame.div_ceil(factor)),
+            GenState::FreeGraphMendConnections(frame) => {
+                GenState::FreeGraphMendConnections(frame.div_ceil(factor))
+            }
+            state => state,
+        }
+    }
+}
+
+/// Upsample by linear interpolation from the last sample of the previous block
+fn interpolate_linear(
+    input: &[Sample],
+    output: &mut [Sample],
+    last_sample: &mut Sample,
+    factor: usize,
+) {
+    for (&inp, out) in input.iter().zip(output.chunks_mut(factor)) {
+        for (i, out) in out.iter_mut().enumerate() {
+            *out = *last_sample + (inp - *last_sample) * (i + 1) as Sample / factor as Sample;
+        }
+        *last_sample = inp;
+    }
+}
+
+/// Define an oversampled gen for a [`WaveguideModel`] with the same inputs
+/// as the model. The oversampling factor is the argument to `new`.
+///
+/// The model needs a `silence_detector` builder, and `impl_gen`, `GenState`,
+/// `BlockSize`, `Sample` and `SampleRate` need to be in scope where it is used.
+macro_rules! oversampled_gen {
+    ($(#[$meta:meta])* $name:ident, $model:ty, [$($setting:ident),* $(,)?]) => {
+        $(#[$meta])*
+        pub struct $name {
+            oversampled: $crate::oversampling::Oversampled<$model>,
+        }
+        impl $name {
+            /// The waveguide running at the oversampled rate
+            pub fn model(&mut self) -> &mut $model {
+                &mut self.oversampled.model
+            }
+            /// Take the done action of `silence_detector` once the string has decayed
+            pub fn silence_detector(
+                mut self,
+                silence_detector: $crate::silence::SilenceDetector,
+            ) -> Self {
+                self.oversampled.model = self.oversampled.model.silence_detector(silence_detector);
+                self
+            }
+        }
+        #[impl_gen]
+        impl $name {
+            pub fn new(factor: usize) -> Self {
+                Self {
+                    oversampled: $crate::oversampling::Oversampled::new(<$model>::new(), factor),
+                }
+            }
+            pub fn init(&mut self, sample_rate: SampleRate, block_size: BlockSize) {
+                self.oversampled.init(sample_rate, block_size);
+            }
+            pub fn process(
+                &mut self,
+                exciter: &[Sample],
+                $($setting: &[Sample],)*
+                reset_trig: &[Sample],
+                output: &mut [Sample],
+                sample_rate: SampleRate,
+            ) -> GenState {
+                self.oversampled.process(
+                    exciter,
+                    &[$($setting),*],
+                    reset_trig,
+                    output,
+                    sample_rate,
+                )
+            }
+        }
+    };
+}
+pub(crate) use oversampled_gen;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Amplitude of the `freq` component of `signal`, `freq` relative to the sample rate
+    fn amplitude(signal: &[Sample], freq: f64) -> f64 {
+        let (mut re, mut im) = (0.0, 0.0);
+        for (i, &s) in signal.iter().enumerate() {
+            let phase = std::f64::consts::TAU * freq * i as f64;
+            re += s as f64 * phase.cos();
+            im += s as f64 * phase.sin();
+        }
+        2.0 * (re * re + im * im).sqrt() / signal.len() as f64
+    }
+
+    fn sine(freq: f64, len: usize) -> Vec<Sample> {
+        (0..len)
+            .map(|i| (std::f64::consts::TAU * freq * i as f64).sin() as Sample)
+            .collect()
+    }
+
+    #[test]
+    fn round_trip_keeps_the_passband() {
+        for factor in [1, 2, 4, 8] {
+            let mut oversampler = Oversampler::new(factor);
+            oversampler.init(BlockSize(4096));
+            let input = sine(0.05, 4096);
+            let mut upsampled = vec![0.0; 4096 * factor];
+            let mut output = vec![0.0; 4096];
+            oversampler.upsample(&input, &mut upsampled);
+            oversampler.downsample(&upsampled, &mut output);
+            let gain = amplitude(&output[1536..], 0.05);
+            assert!((gain - 1.0).abs() < 0.01, "{factor}: {gain}");
+        }
+    }
+
+    #[test]
+    fn upsampling_removes_the_images() {
+        let mut oversampler = Oversampler::new(2);
+        oversampler.init(BlockSize(4096));
+        // 0.2 of the sample rate is 0.1 of the upsampled rate, the image is at 0.4
+        let input = sine(0.2, 4096);
+        let mut upsampled = vec![0.0; 8192];
+        oversampler.upsample(&input, &mut upsampled);
+        let gain = amplitude(&upsampled[3072..], 0.1);
+        let image = amplitude(&upsampled[3072..], 0.4);
+        assert!((gain - 1.0).abs() < 0.01, "{gain}");
+        assert!(image < 1e-4, "{image}");
+    }
+
+    #[test]
+    fn downsampling_removes_what_would_alias() {
+        let mut oversampler = Oversampler::new(2);
+        oversampler.init(BlockSize(4096));
+        // 0.4 of the upsampled rate would alias to 0.2 of the sample rate
+        let input = sine(0.4, 8192);
+        let mut output = vec![0.0; 4096];
+        oversampler.downsample(&input, &mut output);
+        let alias = amplitude(&output[1536..], 0.2);
+        assert!(alias < 1e-4, "{alias}");
+    }
+
+    #[test]
+    fn group_delay_matches_the_round_trip() {
+        for (factor, nbr_coefs) in [(2, 12), (4, 12), (8, 4)] {
+            let mut oversampler = Oversampler::with_design(factor, nbr_coefs, 0.1);
+            oversampler.init(BlockSize(4096));
+            // A low frequency where phase and group delay are about the same
+            let freq = 0.005;
+            let input = sine(freq, 4096);
+            let mut output = vec![0.0; 4096];
+            oversampler.process(&input, &mut output, |_| {});
+            let delay = oversampler.group_delay(freq);
+            // Compare with the input delayed by the reported group delay
+            for (i, out) in output.iter().enumerate().skip(2048) {
+                let t = i as f64 - delay;
+                let delayed = (std::f64::consts::TAU * freq * t).sin();
+                assert!((*out as f64 - delayed).abs() < 1e-3, "{factor} {delay}");
+            }
+        }
+    }
+
+    #[test]
+    fn process_runs_at_the_oversampled_rate() {
+        let mut oversampler = Oversampler::new(4);
+        oversampler.init(BlockSize(64));
+        let mut len = 0;
+        let mut output = [0.0; 64];
+        oversampler.process(&[0.5; 64], &mut output, |oversampled| {
+            len = oversampled.len();
+            oversampled.iter_mut().for_each(|s| *s = s.tanh());
+        });
+        assert_eq!(len, 256);
+    }
+
+    #[test]
+    fn settings_are_interpolated_and_triggers_kept_once() {
+        struct Recorder {
+            settings: Vec<Sample>,
+            triggers: Vec<Sample>,
+        }
+        impl WaveguideModel for Recorder {
+            const NUM_SETTINGS: usize = 1;
+            fn init(&mut self, _sample_rate: SampleRate) {}
+            fn process_block(
+                &mut self,
+                _exciter: &[Sample],
+                settings: &[&[Sample]],
+                reset_trig: &[Sample],
+                output: &mut [Sample],
+                _sample_rate: SampleRate,
+            ) -> GenState {
+                self.settings.extend_from_slice(settings[0]);
+                self.triggers.extend_from_slice(reset_trig);
+                output.fill(0.0);
+                GenState::Continue
+            }
+        }
+        let recorder = Recorder {
+            settings: Vec::new(),
+            triggers: Vec::new(),
+        };
+        let mut oversampled = Oversampled::new(recorder, 4);
+        oversampled.init(SampleRate(48000.), BlockSize(4));
+        let mut output = [0.0; 2];
+        oversampled.process(
+            &[0.0; 2],
+            &[&[1.0, 2.0]],
+            &[1.0, 0.0],
+            &mut output,
+            SampleRate(48000.),
+        );
+        assert_eq!(
+            oversampled.model.settings,
+            [1.0, 1.0, 1.0, 1.0, 1.25, 1.5, 1.75, 2.0]
+        );
+        assert_eq!(
+            oversampled.model.triggers,
+            [1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn free_graph_frames_are_at_the_original_rate() {
+        /// Frees the graph after a fixed oversampled frame
+        struct Stopping(GenState);
+        impl WaveguideModel for Stopping {
+            const NUM_SETTINGS: usize = 0;
+            fn init(&mut self, _sample_rate: SampleRate) {}
+            fn process_block(
+                &mut self,
+                _exciter: &[Sample],
+                _settings: &[&[Sample]],
+                _reset_trig: &[Sample],
+                output: &mut [Sample],
+                _sample_rate: SampleRate,
+            ) -> GenState {
+                output.fill(0.0);
+                self.0
+            }
+        }
+        for (state, expected) in [
+            (GenState::Continue, GenState::Continue),
+            (GenState::FreeSelf, GenState::FreeSelf),
+            (GenState::FreeGraph(8), GenState::FreeGraph(2)),
+            (GenState::FreeGraph(9), GenState::FreeGraph(3)),
+            (
+                GenState::FreeGraphMendConnections(1),
+                GenState::FreeGraphMendConnections(1),
+            ),
+        ] {
+            let mut oversampled = Oversampled::new(Stopping(state), 4);
+            oversampled.init(SampleRate(48000.), BlockSize(4));
+            let mut output = [0.0; 4];
+            let state =
+                oversampled.process(&[0.0; 4], &[], &[0.0; 4], &mut output, SampleRate(48000.));
+            assert_eq!(state, expected);
+        }
+    }
+}
@@ -1,4 +1,6 @@
 use crate::delay_times;
+use crate::oversampling::WaveguideModel;
+use crate::silence::SilenceDetector;
 use biquad::{Biquad, ToHertz};
 use knyst::prelude::*;
 use knyst::trig::is_trigger;
@@ -30,9 +32,15 @@ pub struct ParallelBpfWaveguide {
     sample_rate: f64,
     bpf_freq: f64,
     lp_filter_delay_compensation: f64,
+    silence: SilenceDetector,
 }
 
 impl ParallelBpfWaveguide {
+    /// Take the done action of `silence_detector` once the string has decayed
+    pub fn silence_detector(mut self, silence_detector: SilenceDetector) -> Self {
+        self.silence = silence_detector;
+        self
+    }
     pub fn reset(&mut self) {
         // dbg!("Reset", self.last_delay_outputs);
         for delay in &mut self.delays {
@@ -157,6 +165,7 @@ impl ParallelBpfWaveguide {
             parallel_filter: biquad::DirectForm1::<f64>::new(coeffs),
             sample_rate: 44100.,
             bpf_freq: 200.,
+            silence: SilenceDetector::default(),
         }
     }
     fn init(&mut self, sample_rate: SampleRate) {
@@ -186,7 +195,9 @@ impl ParallelBpfWaveguide {
             sample_rate: *sample_rate as f64,
             parallel_filter: biquad::DirectForm1::<f64>::new(coeffs),
             bpf_freq: 200.,
+            silence: std::mem::take(&mut self.silence),
         };
+        self.silence.init(sample_rate);
     }
     fn process(
         &mut self,
@@ -232,9 +243,14 @@ impl ParallelBpfWaveguide {
             .zip(reset_trig)
             .zip(output.iter_mut())
         {
+            // A sleeping string is reset as well, to start from silence when it wakes up
             if is_trigger(reset_trig) {
                 self.reset();
             }
+            if !self.silence.should_process(exciter) {
+                *output = 0.0;
+                continue;
+            }
             let damping_changed =
                 if damping != self.last_damping || self.last_lf_damping != lf_damping {
                     self.set_damping(damping as f64, lf_damping as f64, sample_rate as f64);
@@ -257,6 +273,7 @@ impl ParallelBpfWaveguide {
                 self.delays[i].feedback = stiffness as f64;
             }
             *output = self.process_sample(exciter as f64, feedback as f64, bpf_mix as f64);
+            self.silence.track(&self.last_delay_outputs, *output);
             if output.is_nan() {
                 dbg!(
                     freq,
@@ -273,6 +290,39 @@ impl ParallelBpfWaveguide {
             }
         }
         // dbg!(&output_buf);
-        GenState::Continue
+        self.silence.finish_block()
+    }
+}
+
+impl WaveguideModel for ParallelBpfWaveguide {
+    const NUM_SETTINGS: usize = 8;
+    fn init(&mut self, sample_rate: SampleRate) {
+        ParallelBpfWaveguide::init(self, sample_rate);
+    }
+    fn process_block(
+        &mut self,
+        exciter: &[Sample],
+        settings: &[&[Sample]],
+        reset_trig: &[Sample],
+        output: &mut [Sample],
+        sample_rate: SampleRate,
+    ) -> GenState {
+        let &[freq, position, feedback, stiffness, damping, lf_damping, bpf_freq, bpf_mix] = settings else {
+            unreachable!()
+        };
+        self.process(
+            exciter,
+            freq,
+            position,
+            feedback,
+            stiffness,
+            damping,
+            lf_damping,
+            bpf_freq,
+            bpf_mix,
+            reset_trig,
+            output,
+            sample_rate,
+        )
     }
 }
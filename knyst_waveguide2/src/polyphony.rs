@@ -0,0 +1,564 @@
+//! Polyphonic string instruments
+//!
+//! A [`PolyString`] plays a fixed number of voices of a [`StringVoice`] model
+//! from MIDI like note events. Each note on plucks a free voice at the pitch
+//! of the note, stealing a voice if none is free, and each note off releases
+//! the voice by switching it to the release damping. Released voices go idle,
+//! and stop being processed, once the [`SilenceDetector`] of the model finds
+//! them silent.
+//!
+//! `poly_string_gen!` turns a [`PolyString`] into a gen where the events are
+//! the "note_on" and "note_off" triggers, read together with the "pitch" and
+//! "velocity" inputs at the same frame.
+
+use knyst::trig::is_trigger;
+use knyst::{BlockSize, Sample, SampleRate};
+
+use crate::oversampling::{WaveguideModel, MAX_SETTINGS};
+use crate::silence::{DoneAction, SilenceDetector};
+use crate::HalfSineImpulse;
+
+/// A waveguide model that can be played as a voice of a [`PolyString`]
+pub trait StringVoice: WaveguideModel {
+    /// Index of the frequency in the settings, set from the pitch of the note
+    const FREQ: usize;
+    /// Index of the damping in the settings, replaced by the release damping after note off
+    const DAMPING: usize;
+    /// Settings that are 0.0 unless the note is held, e.g. the bow velocity
+    const GATED: &'static [usize];
+    /// Replace the silence detector of the model
+    fn silence_detector(self, silence_detector: SilenceDetector) -> Self;
+    /// The silence detector of the model
+    fn silence(&self) -> &SilenceDetector;
+}
+
+/// A MIDI like note event
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoteEvent {
+    /// `pitch` is a MIDI note number, which may be fractional, and
+    /// `velocity` is 0.0 to 1.0. A velocity of 0.0 is a note off like in MIDI.
+    NoteOn { pitch: Sample, velocity: Sample },
+    /// Release all held voices playing `pitch`
+    NoteOff { pitch: Sample },
+    /// Release all held voices
+    AllNotesOff,
+}
+
+/// Which voice to take for a new note when all voices are busy. Released
+/// voices are always stolen before held ones.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StealStrategy {
+    /// The voice whose note started first
+    #[default]
+    Oldest,
+    /// The voice with the lowest level, see [`SilenceDetector::level`]
+    Quietest,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VoiceState {
+    /// Silent and not processed
+    Idle,
+    /// Between note on and note off
+    Held,
+    /// After note off, ringing out with the release damping
+    Released,
+}
+
+struct Voice<M> {
+    model: M,
+    state: VoiceState,
+    pitch: Sample,
+    freq: Sample,
+    velocity: Sample,
+    /// The note counter at note on, to find the oldest voice
+    started: u64,
+    pluck: HalfSineImpulse,
+    /// Level of the model over the last block it was processed
+    level: Sample,
+    /// Reset the model at the start of the next block, after being (re)allocated
+    reset: bool,
+}
+
+/// Voice allocation and mixing for a number of voices of the same model
+pub struct PolyString<M> {
+    voices: Vec<Voice<M>>,
+    pub steal_strategy: StealStrategy,
+    note_counter: u64,
+    /// Buffers for the settings that are set per voice
+    freq: Vec<Sample>,
+    zeros: Vec<Sample>,
+    exciter: Vec<Sample>,
+    reset_trig: Vec<Sample>,
+    voice_output: Vec<Sample>,
+}
+
+impl<M: StringVoice> PolyString<M> {
+    /// One voice per model. The models get a silence detector with
+    /// [`DoneAction::Sleep`], which decides when a released voice goes idle.
+    ///
+    /// # Panics
+    /// If the model has more than [`MAX_SETTINGS`] settings
+    pub fn new(models: impl IntoIterator<Item = M>, steal_strategy: StealStrategy) -> Self {
+        assert!(M::NUM_SETTINGS <= MAX_SETTINGS);
+        Self {
+            voices: models
+                .into_iter()
+                .map(|model| Voice {
+                    model: model.silence_detector(SilenceDetector::new(DoneAction::Sleep)),
+                    state: VoiceState::Idle,
+                    pitch: 0.0,
+                    freq: 0.0,
+                    velocity: 0.0,
+                    started: 0,
+                    pluck: HalfSineImpulse::new(),
+                    level: 0.0,
+                    reset: false,
+                })
+                .collect(),
+            steal_strategy,
+            note_counter: 0,
+            freq: Vec::new(),
+            zeros: Vec::new(),
+            exciter: Vec::new(),
+            reset_trig: Vec::new(),
+            voice_output: Vec::new(),
+        }
+    }
+    pub fn init(&mut self, sample_rate: SampleRate, block_size: BlockSize) {
+        for voice in &mut self.voices {
+            voice.model.init(sample_rate);
+            voice.state = VoiceState::Idle;
+            voice.level = 0.0;
+        }
+        self.freq = vec![0.0; *block_size];
+        self.zeros = vec![0.0; *block_size];
+        self.exciter = vec![0.0; *block_size];
+        self.reset_trig = vec![0.0; *block_size];
+        self.voice_output = vec![0.0; *block_size];
+    }
+    pub fn num_voices(&self) -> usize {
+        self.voices.len()
+    }
+    pub fn voice_state(&self, voice: usize) -> VoiceState {
+        self.voices[voice].state
+    }
+    /// Number of voices that are held or still ringing
+    pub fn num_active_voices(&self) -> usize {
+        self.voices
+            .iter()
+            .filter(|v| v.state != VoiceState::Idle)
+            .count()
+    }
+    pub fn event(&mut self, event: NoteEvent) {
+        match event {
+            NoteEvent::NoteOn { pitch, velocity } => {
+                self.note_on(pitch, velocity);
+            }
+            NoteEvent::NoteOff { pitch } => self.note_off(pitch),
+            NoteEvent::AllNotesOff => self.all_notes_off(),
+        }
+    }
+    /// Start a note on an idle voice, or steal one, and return the index of the voice.
+    /// Returns `None` for a velocity of 0.0, which releases the pitch instead.
+    pub fn note_on(&mut self, pitch: Sample, velocity: Sample) -> Option<usize> {
+        if velocity <= 0.0 {
+            self.note_off(pitch);
+            return None;
+        }
+        let index = self
+            .voices
+            .iter()
+            .position(|v| v.state == VoiceState::Idle)
+            .unwrap_or_else(|| self.voice_to_steal());
+        self.note_counter += 1;
+        let voice = &mut self.voices[index];
+        voice.state = VoiceState::Held;
+        voice.pitch = pitch;
+        voice.freq = midi_to_freq(pitch);
+        voice.velocity = velocity.min(1.0);
+        voice.started = self.note_counter;
+        voice.pluck.reset();
+        // Until the voice has been processed its level is unknown, assume it is as loud as it was played
+        voice.level = voice.velocity;
+        voice.reset = true;
+        Some(index)
+    }
+    pub fn note_off(&mut self, pitch: Sample) {
+        for voice in &mut self.voices {
+            if voice.state == VoiceState::Held && voice.pitch == pitch {
+                voice.state = VoiceState::Released;
+            }
+        }
+    }
+    pub fn all_notes_off(&mut self) {
+        for voice in &mut self.voices {
+            if voice.state == VoiceState::Held {
+                voice.state = VoiceState::Released;
+            }
+        }
+    }
+    fn voice_to_steal(&self) -> usize {
+        let released = self.voices.iter().any(|v| v.state == VoiceState::Released);
+        let candidates = self
+            .voices
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| !released || v.state == VoiceState::Released);
+        let stolen = match self.steal_strategy {
+            StealStrategy::Oldest => candidates.min_by_key(|(_, v)| v.started),
+            StealStrategy::Quietest => candidates.min_by(|(_, a), (_, b)| {
+                a.level.total_cmp(&b.level).then(a.started.cmp(&b.started))
+            }),
+        };
+        stolen.map(|(i, _)| i).unwrap_or(0)
+    }
+    /// Process a block with the events given as triggers. At a frame where
+    /// "note_off" triggers, held voices playing `pitch` are released, and at
+    /// a frame where "note_on" triggers a note is started with `pitch` and
+    /// `velocity`, in that order.
+    ///
+    /// `settings` are the settings of the model without the frequency.
+    pub fn process_triggers(
+        &mut self,
+        note_on: &[Sample],
+        note_off: &[Sample],
+        pitch: &[Sample],
+        velocity: &[Sample],
+        pluck_freq: &[Sample],
+        settings: &[&[Sample]],
+        release_damping: &[Sample],
+        output: &mut [Sample],
+        sample_rate: SampleRate,
+    ) {
+        let mut start = 0;
+        for i in 0..output.len() {
+            let (off, on) = (is_trigger(note_off[i]), is_trigger(note_on[i]));
+            if !(off || on) {
+                continue;
+            }
+            self.process_range(
+                start..i,
+                pluck_freq,
+                settings,
+                release_damping,
+                output,
+                sample_rate,
+            );
+            start = i;
+            if off {
+                self.note_off(pitch[i]);
+            }
+            if on {
+                self.note_on(pitch[i], velocity[i]);
+            }
+        }
+        self.process_range(
+            start..output.len(),
+            pluck_freq,
+            settings,
+            release_damping,
+            output,
+            sample_rate,
+        );
+    }
+    fn process_range(
+        &mut self,
+        range: std::ops::Range<usize>,
+        pluck_freq: &[Sample],
+        settings: &[&[Sample]],
+        release_damping: &[Sample],
+        output: &mut [Sample],
+        sample_rate: SampleRate,
+    ) {
+        let mut range_settings: [&[Sample]; MAX_SETTINGS] = [&[]; MAX_SETTINGS];
+        for (range_setting, setting) in range_settings.iter_mut().zip(settings) {
+            *range_setting = &setting[range.clone()];
+        }
+        self.process(
+            &pluck_freq[range.clone()],
+            &range_settings[..settings.len()],
+            &release_damping[range.clone()],
+            &mut output[range],
+            sample_rate,
+        );
+    }
+    /// Process all voices that are not idle and write the sum to `output`.
+    /// Events can be applied between calls to split a block.
+    ///
+    /// `settings` are the settings of the model without the frequency.
+    pub fn process(
+        &mut self,
+        pluck_freq: &[Sample],
+        settings: &[&[Sample]],
+        release_damping: &[Sample],
+        output: &mut [Sample],
+        sample_rate: SampleRate,
+    ) {
+        assert_eq!(settings.len() + 1, M::NUM_SETTINGS);
+        output.fill(0.0);
+        if output.is_empty() {
+            return;
+        }
+        let len = output.len();
+        for voice in &mut self.voices {
+            if voice.state == VoiceState::Idle {
+                continue;
+            }
+            let held = voice.state == VoiceState::Held;
+            let freq = &mut self.freq[..len];
+            freq.fill(voice.freq);
+            let mut voice_settings: [&[Sample]; MAX_SETTINGS] = [&[]; MAX_SETTINGS];
+            let mut shared = settings.iter();
+            for (index, voice_setting) in voice_settings[..M::NUM_SETTINGS].iter_mut().enumerate() {
+                let setting = if index == M::FREQ {
+                    &*freq
+                } else {
+                    shared.next().unwrap()
+                };
+                *voice_setting = if held {
+                    setting
+                } else if index == M::DAMPING {
+                    release_damping
+                } else if M::GATED.contains(&index) {
+                    &self.zeros[..len]
+                } else {
+                    setting
+                };
+            }
+            for (exciter, &pluck_freq) in self.exciter[..len].iter_mut().zip(pluck_freq) {
+                *exciter = voice.pluck.next_sample(pluck_freq, *sample_rate) * voice.velocity;
+            }
+            let reset_trig = &mut self.reset_trig[..len];
+            reset_trig.fill(0.0);
+            if voice.reset {
+                reset_trig[0] = 1.0;
+                voice.reset = false;
+            }
+            let voice_output = &mut self.voice_output[..len];
+            voice.model.process_block(
+                &self.exciter[..len],
+                &voice_settings[..M::NUM_SETTINGS],
+                reset_trig,
+                voice_output,
+                sample_rate,
+            );
+            for (out, &voice_out) in output.iter_mut().zip(voice_output.iter()) {
+                *out += voice_out;
+            }
+            voice.level = voice.model.silence().level();
+            if !held && voice.model.silence().is_silent() {
+                voice.state = VoiceState::Idle;
+            }
+        }
+    }
+}
+
+/// Frequency of a MIDI note number, A4 (69) is 440 Hz
+pub fn midi_to_freq(pitch: Sample) -> Sample {
+    440.0 * (2.0 as Sample).powf((pitch - 69.0) / 12.0)
+}
+
+/// Define a polyphonic gen for a [`StringVoice`] with a "note_on" and
+/// "note_off" trigger, "pitch", "velocity" and "pluck_freq" inputs, then
+/// every setting of the model except the frequency, in order, and a
+/// "release_damping" input. The number of voices and the
+/// [`StealStrategy`] are the arguments to `new`.
+///
+/// `impl_gen`, `GenState`, `BlockSize`, `Sample`, `SampleRate` and `Trig` need
+/// to be in scope where it is used.
+macro_rules! poly_string_gen {
+    ($(#[$meta:meta])* $name:ident, $model:ty, [$($setting:ident),* $(,)?]) => {
+        $(#[$meta])*
+        pub struct $name {
+            poly: $crate::polyphony::PolyString<$model>,
+        }
+        impl $name {
+            /// The voices, e.g. to send [`NoteEvent`](crate::polyphony::NoteEvent)s directly
+            pub fn poly(&mut self) -> &mut $crate::polyphony::PolyString<$model> {
+                &mut self.poly
+            }
+        }
+        #[impl_gen]
+        impl $name {
+            pub fn new(voices: usize, steal_strategy: $crate::polyphony::StealStrategy) -> Self {
+                Self {
+                    poly: $crate::polyphony::PolyString::new(
+                        (0..voices).map(|_| <$model>::new()),
+                        steal_strategy,
+                    ),
+                }
+            }
+            pub fn init(&mut self, sample_rate: SampleRate, block_size: BlockSize) {
+                self.poly.init(sample_rate, block_size);
+            }
+            pub fn process(
+                &mut self,
+                note_on: &[Trig],
+                note_off: &[Trig],
+                pitch: &[Sample],
+                velocity: &[Sample],
+                pluck_freq: &[Sample],
+                $($setting: &[Sample],)*
+                release_damping: &[Sample],
+                output: &mut [Sample],
+                sample_rate: SampleRate,
+            ) -> GenState {
+                self.poly.process_triggers(
+                    note_on,
+                    note_off,
+                    pitch,
+                    velocity,
+                    pluck_freq,
+                    &[$($setting),*],
+                    release_damping,
+                    output,
+                    sample_rate,
+                );
+                GenState::Continue
+            }
+        }
+    };
+}
+pub(crate) use poly_string_gen;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PolyWaveguide, Waveguide};
+
+    const SAMPLE_RATE: SampleRate = SampleRate(48000.);
+    const BLOCK_SIZE: usize = 64;
+
+    fn poly(voices: usize, steal_strategy: StealStrategy) -> PolyString<Waveguide> {
+        let mut poly = PolyString::new((0..voices).map(|_| Waveguide::new()), steal_strategy);
+        poly.init(SAMPLE_RATE, BlockSize(BLOCK_SIZE));
+        poly
+    }
+
+    /// One block of a plucked string
+    fn process(poly: &mut PolyString<Waveguide>, release_damping: Sample) -> Vec<Sample> {
+        let block = |value| vec![value; BLOCK_SIZE];
+        // position, feedback, stiffness, damping, lf_damping, delay_compensation
+        let settings = [
+            block(0.3),
+            block(0.99),
+            block(0.0),
+            block(8000.),
+            block(6.),
+            block(0.0),
+        ];
+        let settings = settings.each_ref().map(|s| s.as_slice());
+        let mut output = block(0.0);
+        poly.process(
+            &block(2000.),
+            &settings,
+            &block(release_damping),
+            &mut output,
+            SAMPLE_RATE,
+        );
+        output
+    }
+
+    #[test]
+    fn notes_are_held_and_released() {
+        let mut poly = poly(4, StealStrategy::Oldest);
+        assert_eq!(poly.note_on(60., 0.5), Some(0));
+        assert_eq!(poly.note_on(64., 0.5), Some(1));
+        poly.event(NoteEvent::NoteOff { pitch: 60. });
+        assert_eq!(poly.voice_state(0), VoiceState::Released);
+        assert_eq!(poly.voice_state(1), VoiceState::Held);
+        // A note on without velocity is a note off
+        assert_eq!(poly.note_on(64., 0.0), None);
+        assert_eq!(poly.voice_state(1), VoiceState::Released);
+        assert_eq!(poly.num_active_voices(), 2);
+        assert!((midi_to_freq(57.) - 220.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn oldest_voice_is_stolen() {
+        let mut poly = poly(2, StealStrategy::Oldest);
+        poly.note_on(60., 0.5);
+        poly.note_on(62., 0.5);
+        assert_eq!(poly.note_on(64., 0.5), Some(0));
+        assert_eq!(poly.note_on(65., 0.5), Some(1));
+        // Released voices go first even if they are newer
+        poly.note_off(65.);
+        assert_eq!(poly.note_on(67., 0.5), Some(1));
+    }
+
+    #[test]
+    fn quietest_voice_is_stolen() {
+        for (steal_strategy, expected) in [(StealStrategy::Oldest, 0), (StealStrategy::Quietest, 1)]
+        {
+            let mut poly = poly(2, steal_strategy);
+            poly.note_on(60., 1.0);
+            process(&mut poly, 8000.);
+            poly.note_on(62., 0.05);
+            for _ in 0..10 {
+                process(&mut poly, 8000.);
+            }
+            assert_eq!(poly.note_on(64., 0.5), Some(expected));
+        }
+    }
+
+    #[test]
+    fn released_voices_go_idle_when_silent() {
+        let mut poly = poly(2, StealStrategy::Oldest);
+        poly.note_on(60., 1.0);
+        for _ in 0..100 {
+            process(&mut poly, 8000.);
+        }
+        assert_eq!(poly.voice_state(0), VoiceState::Held);
+        poly.note_off(60.);
+        let mut blocks = 0;
+        while poly.voice_state(0) != VoiceState::Idle {
+            let output = process(&mut poly, 200.);
+            assert!(output.iter().any(|&s| s != 0.0));
+            blocks += 1;
+            assert!(blocks < 3000, "The voice never went idle");
+        }
+        // Idle once the detector of the model has found it silent
+        assert!(poly.voices[0].model.silence().is_silent());
+        assert!(process(&mut poly, 200.).iter().all(|&s| s == 0.0));
+        assert_eq!(poly.num_active_voices(), 0);
+    }
+
+    #[test]
+    fn trigger_events_are_sample_accurate() {
+        let mut gen = PolyWaveguide::new(2, StealStrategy::Oldest);
+        gen.init(SAMPLE_RATE, BlockSize(BLOCK_SIZE));
+        let block = |value| vec![value; BLOCK_SIZE];
+        let trig_at = |frame| {
+            let mut trig = block(0.0);
+            trig[frame] = 1.0;
+            trig
+        };
+        let mut process = |note_on: &[Sample], note_off: &[Sample]| {
+            let mut output = block(0.0);
+            gen.process(
+                note_on,
+                note_off,
+                &block(60.),
+                &block(0.8),
+                &block(2000.),
+                &block(0.3),
+                &block(0.99),
+                &block(0.0),
+                &block(8000.),
+                &block(6.),
+                &block(0.0),
+                &block(200.),
+                &mut output,
+                SAMPLE_RATE,
+            );
+            output
+        };
+        let output = process(&trig_at(10), &block(0.0));
+        assert!(output[..10].iter().all(|&s| s == 0.0));
+        assert!(output[10] != 0.0);
+        process(&block(0.0), &trig_at(5));
+        assert_eq!(gen.poly().voice_state(0), VoiceState::Released);
+        assert_eq!(gen.poly().voice_state(1), VoiceState::Idle);
+    }
+}
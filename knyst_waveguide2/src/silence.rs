@@ -0,0 +1,301 @@
+//! Detecting when a string has decayed to silence
+//!
+//! Every waveguide gen has a [`SilenceDetector`] which tracks the energy of
+//! the string, i.e. the last outputs of its delays and its output. When the
+//! energy has stayed below a threshold for long enough the [`DoneAction`] is
+//! taken: the gen can keep going, skip processing and output zeros until it
+//! is excited again, or free itself or its graph. By default it keeps going,
+//! like before.
+
+use knyst::gen::{GenState, StopAction};
+use knyst::{Sample, SampleRate};
+
+/// What a waveguide gen does once it has been silent for the silence time
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DoneAction {
+    /// Keep processing, the energy is not tracked
+    #[default]
+    Continue,
+    /// Skip processing and output zeros until the exciter is above the threshold again
+    Sleep,
+    /// Output zeros and return [`GenState::FreeSelf`]
+    FreeSelf,
+    /// Output zeros and return [`GenState::FreeGraph`]
+    FreeGraph,
+    /// Output zeros and return [`GenState::FreeGraphMendConnections`]
+    FreeGraphMendConnections,
+}
+
+impl From<StopAction> for DoneAction {
+    fn from(stop_action: StopAction) -> Self {
+        match stop_action {
+            StopAction::Continue => DoneAction::Continue,
+            StopAction::FreeSelf => DoneAction::FreeSelf,
+            StopAction::FreeGraph => DoneAction::FreeGraph,
+            StopAction::FreeGraphMendConnections => DoneAction::FreeGraphMendConnections,
+        }
+    }
+}
+
+/// Energy tracker deciding when a string is silent
+///
+/// Per frame a gen calls [`SilenceDetector::should_process`] first, skipping
+/// the frame and outputting 0.0 if it returns false, then
+/// [`SilenceDetector::track`] with the state of the string after processing,
+/// and it returns [`SilenceDetector::finish_block`] at the end of the block.
+#[derive(Clone, Debug)]
+pub struct SilenceDetector {
+    done_action: DoneAction,
+    /// Amplitude below which the string is silent
+    threshold: f64,
+    /// Seconds the energy needs to stay below the threshold
+    silence_time: f64,
+    silence_frames: usize,
+    silent_frames: usize,
+    silent: bool,
+    /// Number of frames so far in the current block
+    frame: usize,
+    /// The frame after the one where the string became silent in the current block
+    silent_from: Option<usize>,
+    /// Highest energy so far in the current block
+    peak_energy: f64,
+    /// Peak amplitude over the last block
+    level: f64,
+}
+
+impl SilenceDetector {
+    /// With a threshold of -100 dB and a silence time of 0.1 seconds, long
+    /// enough for the lowest strings to get from the exciter to the output.
+    pub fn new(done_action: DoneAction) -> Self {
+        Self {
+            done_action,
+            threshold: 1e-5,
+            silence_time: 0.1,
+            silence_frames: 0,
+            silent_frames: 0,
+            silent: false,
+            frame: 0,
+            silent_from: None,
+            peak_energy: 0.0,
+            level: 0.0,
+        }
+    }
+    /// Set the amplitude below which the string counts as silent
+    pub fn threshold(mut self, amplitude: Sample) -> Self {
+        self.threshold = amplitude as f64;
+        self
+    }
+    /// Set the time in seconds the string needs to stay silent before the done action
+    pub fn silence_time(mut self, seconds: Sample) -> Self {
+        self.silence_time = seconds as f64;
+        self
+    }
+    pub fn done_action(&self) -> DoneAction {
+        self.done_action
+    }
+    pub fn init(&mut self, sample_rate: SampleRate) {
+        self.silence_frames = (self.silence_time * sample_rate.to_f64()).round() as usize;
+        self.silent_frames = 0;
+        self.silent = false;
+        self.frame = 0;
+        self.silent_from = None;
+        self.peak_energy = 0.0;
+        self.level = 0.0;
+    }
+    /// True if the done action has been taken and the gen is not processing
+    pub fn is_silent(&self) -> bool {
+        self.silent
+    }
+    /// Peak amplitude of the string over the last block. Always 0.0 with
+    /// [`DoneAction::Continue`] since the energy is not tracked then.
+    pub fn level(&self) -> Sample {
+        self.level as Sample
+    }
+    /// Call at the start of every frame with the amplitude of the excitation.
+    /// Returns false if the frame should not be processed.
+    #[inline]
+    pub fn should_process(&mut self, excitation: Sample) -> bool {
+        self.frame += 1;
+        if !self.silent {
+            return true;
+        }
+        if self.done_action == DoneAction::Sleep && excitation.abs() as f64 > self.threshold {
+            self.silent = false;
+            self.silent_frames = 0;
+            return true;
+        }
+        false
+    }
+    /// Call after processing a frame with the last outputs of the delays and the output
+    #[inline]
+    pub fn track(&mut self, last_delay_outputs: &[f64], output: Sample) {
+        if self.done_action == DoneAction::Continue {
+            return;
+        }
+        let energy =
+            last_delay_outputs.iter().map(|s| s * s).sum::<f64>() + (output as f64 * output as f64);
+        self.peak_energy = self.peak_energy.max(energy);
+        if energy > self.threshold * self.threshold {
+            self.silent_frames = 0;
+            return;
+        }
+        self.silent_frames += 1;
+        if self.silent_frames > self.silence_frames {
+            self.silent = true;
+            self.silent_from = Some(self.frame);
+        }
+    }
+    /// The state to return from the process function of the gen
+    pub fn finish_block(&mut self) -> GenState {
+        let silent_from = self.silent_from.take().unwrap_or(0);
+        self.frame = 0;
+        self.level = self.peak_energy.sqrt();
+        self.peak_energy = 0.0;
+        if !self.silent {
+            return GenState::Continue;
+        }
+        match self.done_action {
+            DoneAction::Continue | DoneAction::Sleep => GenState::Continue,
+            DoneAction::FreeSelf => GenState::FreeSelf,
+            DoneAction::FreeGraph => GenState::FreeGraph(silent_from),
+            DoneAction::FreeGraphMendConnections => GenState::FreeGraphMendConnections(silent_from),
+        }
+    }
+}
+
+impl Default for SilenceDetector {
+    fn default() -> Self {
+        Self::new(DoneAction::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Waveguide;
+
+    /// Run a silent block through `detector`, returning the state at the end of it
+    fn silent_block(detector: &mut SilenceDetector, len: usize) -> GenState {
+        for _ in 0..len {
+            if detector.should_process(0.0) {
+                detector.track(&[0.0, 0.0], 0.0);
+            }
+        }
+        detector.finish_block()
+    }
+
+    #[test]
+    fn free_actions_report_the_frame() {
+        for (done_action, expected) in [
+            (DoneAction::Continue, GenState::Continue),
+            (DoneAction::Sleep, GenState::Continue),
+            (DoneAction::FreeSelf, GenState::FreeSelf),
+            (DoneAction::FreeGraph, GenState::FreeGraph(11)),
+        ] {
+            let mut detector = SilenceDetector::new(done_action).silence_time(0.01);
+            detector.init(SampleRate(1000.));
+            assert_eq!(silent_block(&mut detector, 16), expected, "{done_action:?}");
+            assert_eq!(detector.is_silent(), done_action != DoneAction::Continue);
+        }
+        let mut detector = SilenceDetector::new(StopAction::FreeGraph.into()).silence_time(0.01);
+        detector.init(SampleRate(1000.));
+        assert_eq!(silent_block(&mut detector, 8), GenState::Continue);
+        // Later blocks report the frame as 0 to stop right away
+        assert_eq!(silent_block(&mut detector, 8), GenState::FreeGraph(3));
+        assert_eq!(silent_block(&mut detector, 8), GenState::FreeGraph(0));
+    }
+
+    #[test]
+    fn sleeping_waveguide_wakes_up_when_excited() {
+        let sample_rate = SampleRate(48000.);
+        let mut waveguide =
+            Waveguide::new().silence_detector(SilenceDetector::new(DoneAction::Sleep));
+        waveguide.init(sample_rate);
+        let block = |value| vec![value; 64];
+        let mut process = |exciter: &[Sample], output: &mut [Sample]| {
+            waveguide.process(
+                exciter,
+                &block(220.),
+                &block(0.3),
+                &block(0.9),
+                &block(0.0),
+                &block(2000.),
+                &block(6.),
+                &block(0.0),
+                &block(0.0),
+                output,
+                sample_rate,
+            );
+            waveguide.silence.is_silent()
+        };
+        let mut pluck = block(0.0);
+        pluck[3] = 1.0;
+        let mut output = block(1.0);
+        assert!(!process(&pluck, &mut output));
+        assert!(output[3] != 0.0);
+        let mut blocks = 0;
+        while !process(&block(0.0), &mut output) {
+            blocks += 1;
+            assert!(blocks < 1000, "The string never went silent");
+        }
+        // Silent for at least the silence time after the pluck
+        assert!(blocks * 64 > 4800);
+        assert!(process(&block(0.0), &mut output));
+        assert!(output.iter().all(|&s| s == 0.0));
+        assert!(!process(&pluck, &mut output));
+        assert!(output[..3].iter().all(|&s| s == 0.0));
+        assert!(output[3] != 0.0);
+    }
+
+    #[test]
+    fn sleeping_waveguide_is_reset() {
+        let sample_rate = SampleRate(48000.);
+        let block = |value| vec![value; 64];
+        let process = |waveguide: &mut Waveguide, exciter: &[Sample], reset: &[Sample]| {
+            let mut output = block(0.0);
+            waveguide.process(
+                exciter,
+                &block(220.),
+                &block(0.3),
+                &block(0.9),
+                &block(0.0),
+                &block(2000.),
+                &block(6.),
+                &block(0.0),
+                reset,
+                &mut output,
+                sample_rate,
+            );
+            output
+        };
+        let new_waveguide = || {
+            let mut waveguide =
+                Waveguide::new().silence_detector(SilenceDetector::new(DoneAction::Sleep));
+            waveguide.init(sample_rate);
+            waveguide
+        };
+        let mut pluck = block(0.0);
+        pluck[3] = 1.0;
+        let mut waveguide = new_waveguide();
+        process(&mut waveguide, &pluck, &block(0.0));
+        let mut blocks = 0;
+        while !waveguide.silence.is_silent() {
+            process(&mut waveguide, &block(0.0), &block(0.0));
+            blocks += 1;
+            assert!(blocks < 1000, "The string never went silent");
+        }
+        // What is left of the pluck is below the threshold, but not gone
+        assert!(waveguide.last_delay_outputs.iter().any(|&s| s != 0.0));
+        let mut reset = block(0.0);
+        reset[10] = 1.0;
+        process(&mut waveguide, &block(0.0), &reset);
+        assert!(waveguide.silence.is_silent());
+        assert_eq!(waveguide.last_delay_outputs, [0.0; 2]);
+        // Woken up, it sounds like a string that was never plucked before
+        let mut fresh = new_waveguide();
+        assert_eq!(
+            process(&mut waveguide, &pluck, &block(0.0)),
+            process(&mut fresh, &pluck, &block(0.0))
+        );
+    }
+}
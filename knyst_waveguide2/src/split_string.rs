@@ -1,5 +1,7 @@
 use knyst::{gen::filter::one_pole::OnePole, prelude::*, trig::is_trigger};
 
+use crate::oversampling::WaveguideModel;
+use crate::silence::SilenceDetector;
 use crate::AllpassFeedbackDelay;
 // This waveguide implementation mirrors the one outlined in Palle Dahlstedt's
 //  "Physical Interactions with Digital Strings - A hybrid approach to a digital keyboard instrument"
@@ -26,9 +28,15 @@ pub struct SplitWaveguide {
     hp_filter: [OnePole<f64>; 1],
     lp_filter_delay_compensation: f64,
     exciter_peak_follower: f64,
+    silence: SilenceDetector,
 }
 
 impl SplitWaveguide {
+    /// Take the done action of `silence_detector` once the string has decayed
+    pub fn silence_detector(mut self, silence_detector: SilenceDetector) -> Self {
+        self.silence = silence_detector;
+        self
+    }
     pub fn reset(&mut self) {
         // dbg!("Reset", self.last_delay_outputs);
         for delay in &mut self.delays {
@@ -219,6 +227,7 @@ impl SplitWaveguide {
             hp_filter: [OnePole::new()],
             lp_filter_delay_compensation: 0.0,
             exciter_peak_follower: 0.,
+            silence: SilenceDetector::default(),
         }
     }
     fn init(&mut self, sample_rate: SampleRate) {
@@ -239,7 +248,9 @@ impl SplitWaveguide {
             hp_filter: [OnePole::new()],
             lp_filter_delay_compensation: 0.0,
             exciter_peak_follower: 0.,
+            silence: std::mem::take(&mut self.silence),
         };
+        self.silence.init(sample_rate);
     }
     fn process(
         &mut self,
@@ -286,9 +297,14 @@ impl SplitWaveguide {
             .zip(stop_amount)
             .zip(output.iter_mut())
         {
+            // A sleeping string is reset as well, to start from silence when it wakes up
             if is_trigger(reset_trig) {
                 self.reset();
             }
+            if !self.silence.should_process(exciter) {
+                *output = 0.0;
+                continue;
+            }
             let damping_changed =
                 if damping != self.last_damping || self.last_lf_damping != lf_damping {
                     self.set_damping(
@@ -320,6 +336,7 @@ impl SplitWaveguide {
             }
             // let stop_amount = smootherstep(0.0, 1.0, stop_amount as f64);
             *output = self.process_sample(exciter as f64, feedback as f64, stop_amount as f64);
+            self.silence.track(&self.last_delay_outputs, *output);
             if output.is_nan() {
                 dbg!(
                     exciter,
@@ -337,7 +354,42 @@ impl SplitWaveguide {
             }
         }
         // dbg!(&output_buf);
-        GenState::Continue
+        self.silence.finish_block()
+    }
+}
+
+impl WaveguideModel for SplitWaveguide {
+    const NUM_SETTINGS: usize = 8;
+    fn init(&mut self, sample_rate: SampleRate) {
+        SplitWaveguide::init(self, sample_rate);
+    }
+    fn process_block(
+        &mut self,
+        exciter: &[Sample],
+        settings: &[&[Sample]],
+        reset_trig: &[Sample],
+        output: &mut [Sample],
+        sample_rate: SampleRate,
+    ) -> GenState {
+        let &[freq, position, feedback, stiffness, damping, lf_damping, delay_compensation, stop_amount] =
+            settings
+        else {
+            unreachable!()
+        };
+        self.process(
+            exciter,
+            freq,
+            position,
+            feedback,
+            stiffness,
+            damping,
+            lf_damping,
+            delay_compensation,
+            stop_amount,
+            reset_trig,
+            output,
+            sample_rate,
+        )
     }
 }
 